@@ -21,6 +21,7 @@ pub fn nihav_register_all_decoders(rd: &mut RegisteredDecoders) {
 
 /// Registers all known packetisers.
 pub fn nihav_register_all_packetisers(rp: &mut RegisteredPacketisers) {
+    duck_register_all_packetisers(rp);
 }
 
 /// Registers all known demuxers.
@@ -40,6 +41,7 @@ pub fn nihav_register_all_encoders(re: &mut RegisteredEncoders) {
 
 /// Registers all known demuxers.
 pub fn nihav_register_all_muxers(rm: &mut RegisteredMuxers) {
+    duck_register_all_muxers(rm);
     generic_register_all_muxers(rm);
 }
 
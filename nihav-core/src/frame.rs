@@ -1,6 +1,7 @@
 //! Packets and decoded frames functionality.
 use std::cmp::max;
 //use std::collections::HashMap;
+use std::any::Any;
 use std::fmt;
 pub use std::sync::Arc;
 pub use crate::formats::*;
@@ -1190,6 +1191,12 @@ pub struct NAFrame {
     pub frame_type:     FrameType,
     /// Keyframe flag.
     pub key:            bool,
+    /// Damaged frame flag (set when the decoder had to conceal errors).
+    pub damaged:        bool,
+    /// Format changed flag (set when the frame format differs from the one of the previous frame).
+    pub format_changed: bool,
+    /// Frame side data (e.g. decoder analysis information).
+    pub side_data:      Vec<NASideData>,
 //        options:        HashMap<String, NAValue>,
 }
 
@@ -1213,7 +1220,7 @@ impl NAFrame {
                info:           NACodecInfoRef,
                /*options:        HashMap<String, NAValue>,*/
                buffer:         NABufferType) -> Self {
-        NAFrame { ts, id: 0, buffer, info, frame_type: ftype, key: keyframe/*, options*/ }
+        NAFrame { ts, id: 0, buffer, info, frame_type: ftype, key: keyframe, damaged: false, format_changed: false, side_data: Vec::new()/*, options*/ }
     }
     /// Returns frame format information.
     pub fn get_info(&self) -> NACodecInfoRef { self.info.clone() }
@@ -1225,6 +1232,16 @@ impl NAFrame {
     pub fn set_frame_type(&mut self, ftype: FrameType) { self.frame_type = ftype; }
     /// Sets keyframe flag.
     pub fn set_keyframe(&mut self, key: bool) { self.key = key; }
+    /// Reports whether the frame was decoded from damaged data.
+    pub fn is_damaged(&self) -> bool { self.damaged }
+    /// Sets damaged frame flag.
+    pub fn set_damaged(&mut self, damaged: bool) { self.damaged = damaged; }
+    /// Reports whether the frame format differs from the one of the previous frame.
+    pub fn is_format_changed(&self) -> bool { self.format_changed }
+    /// Sets format changed flag.
+    pub fn set_format_changed(&mut self, changed: bool) { self.format_changed = changed; }
+    /// Adds side data for a frame.
+    pub fn add_side_data(&mut self, side_data: NASideData) { self.side_data.push(side_data); }
     /// Returns frame timestamp.
     pub fn get_time_information(&self) -> NATimeInfo { self.ts }
     /// Returns frame presentation time.
@@ -1263,6 +1280,8 @@ impl fmt::Display for NAFrame {
         if let Some(dts) = self.ts.dts { ostr = format!("{} dts {}", ostr, dts); }
         if let Some(dur) = self.ts.duration { ostr = format!("{} duration {}", ostr, dur); }
         if self.key { ostr = format!("{} kf", ostr); }
+        if self.damaged { ostr = format!("{} damaged", ostr); }
+        if self.format_changed { ostr = format!("{} format changed", ostr); }
         write!(f, "[{}]", ostr)
     }
 }
@@ -1368,7 +1387,7 @@ impl fmt::Display for NAStream {
     }
 }
 
-/// Side data that may accompany demuxed data.
+/// Side data that may accompany demuxed data or decoded frames.
 #[derive(Clone)]
 pub enum NASideData {
     /// Palette information.
@@ -1378,6 +1397,10 @@ pub enum NASideData {
     Palette(bool, Arc<[u8; 1024]>),
     /// Generic user data.
     UserData(Arc<Vec<u8>>),
+    /// Codec-specific data.
+    ///
+    /// The actual type is defined by the codec and can be obtained with `downcast_ref()`.
+    Custom(Arc<dyn Any + Send + Sync>),
 }
 
 /// Packet with compressed data.
@@ -1,7 +1,7 @@
 use crate::frame::*;
 use super::{ScaleInfo, ScaleResult};
 
-pub trait Kernel {
+pub trait Kernel: Send {
     fn init(&mut self, in_fmt: &ScaleInfo, dest_fmt: &ScaleInfo, options: &[(String, String)]) -> ScaleResult<NABufferType>;
     fn process(&mut self, pic_in: &NABufferType, pic_out: &mut NABufferType);
 }
@@ -146,6 +146,7 @@ pub struct NAScale {
     fmt_out:        ScaleInfo,
     just_convert:   bool,
     pipeline:       Option<Stage>,
+    options:        Vec<(String, String)>,
 }
 
 fn check_format(in_fmt: NAVideoInfo, ref_fmt: &ScaleInfo, just_convert: bool) -> ScaleResult<()> {
@@ -445,7 +446,7 @@ impl NAScale {
         } else {
             pipeline = None;
         }
-        Ok(Self { fmt_in, fmt_out, just_convert, pipeline })
+        Ok(Self { fmt_in, fmt_out, just_convert, pipeline, options: Vec::new() })
     }
     /// Constructs a new `NAScale` instance taking into account provided options.
     pub fn new_with_options(fmt_in: ScaleInfo, fmt_out: ScaleInfo, options: &[(String, String)]) -> ScaleResult<Self> {
@@ -456,7 +457,19 @@ impl NAScale {
         } else {
             pipeline = None;
         }
-        Ok(Self { fmt_in, fmt_out, just_convert, pipeline })
+        Ok(Self { fmt_in, fmt_out, just_convert, pipeline, options: options.to_vec() })
+    }
+    /// Reconfigures the converter if the input picture format has changed (e.g. after a decoder reported format change).
+    ///
+    /// The output format stays the same. Returns `true` if the converter has been reconfigured.
+    pub fn update_input(&mut self, pic_in: &NABufferType) -> ScaleResult<bool> {
+        if pic_in.get_video_info().is_none() { return Err(ScaleError::NoFrame); }
+        let fmt_in = get_scale_fmt_from_pic(pic_in);
+        if fmt_in == self.fmt_in {
+            return Ok(false);
+        }
+        *self = Self::new_with_options(fmt_in, self.fmt_out, &self.options)?;
+        Ok(true)
     }
     /// Checks whether requested conversion operation is needed at all.
     pub fn needs_processing(&self) -> bool { self.pipeline.is_some() }
@@ -560,6 +573,37 @@ mod test {
         assert_eq!(odata[0], 42);
     }
     #[test]
+    fn test_update_input() {
+        let mut in_pic = alloc_video_buffer(NAVideoInfo::new(2, 2, false, RGB565_FORMAT), 3).unwrap();
+        fill_pic(&mut in_pic, 42);
+        let mut out_pic = alloc_video_buffer(NAVideoInfo::new(3, 3, false, RGB565_FORMAT), 3).unwrap();
+        fill_pic(&mut out_pic, 0);
+        let ifmt = get_scale_fmt_from_pic(&in_pic);
+        let ofmt = get_scale_fmt_from_pic(&out_pic);
+        let mut scaler = NAScale::new(ifmt, ofmt).unwrap();
+        assert_eq!(scaler.update_input(&in_pic), Ok(false));
+        let mut in_pic = alloc_video_buffer(NAVideoInfo::new(5, 4, false, RGB565_FORMAT), 3).unwrap();
+        fill_pic(&mut in_pic, 42);
+        assert!(scaler.convert(&in_pic, &mut out_pic).is_err());
+        assert_eq!(scaler.update_input(&in_pic), Ok(true));
+        assert!(scaler.get_out_fmt() == ofmt);
+        scaler.convert(&in_pic, &mut out_pic).unwrap();
+        let obuf = out_pic.get_vbuf16().unwrap();
+        let odata = obuf.get_data();
+        assert_eq!(odata[0], 42);
+        assert_eq!(scaler.update_input(&NABufferType::None), Err(ScaleError::NoFrame));
+
+        let options = vec![("debug".to_string(), "false".to_string())];
+        let mut scaler = NAScale::new_with_options(ifmt, ofmt, &options).unwrap();
+        let mut cmyk_fmt = YUV420_FORMAT;
+        cmyk_fmt.model = ColorModel::CMYK;
+        let bad_pic = alloc_video_buffer(NAVideoInfo::new(4, 4, false, cmyk_fmt), 3).unwrap();
+        assert!(scaler.update_input(&bad_pic).is_err());
+        assert_eq!(scaler.options, options);
+        assert_eq!(scaler.update_input(&in_pic), Ok(true));
+        assert_eq!(scaler.options, options);
+    }
+    #[test]
     fn test_scale_and_convert() {
         let mut in_pic = alloc_video_buffer(NAVideoInfo::new(7, 3, false, RGB565_FORMAT), 3).unwrap();
         fill_pic(&mut in_pic, 42);
@@ -81,7 +81,7 @@ fn decode_vp6() {
 }
 
 pub fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("decode_vp6", |b| b.iter(|| decode_vp6));
+    c.bench_function("decode_vp6", |b| b.iter(decode_vp6));
 }
 
 criterion_group! {
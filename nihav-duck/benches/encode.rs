@@ -224,7 +224,7 @@ fn encode_vp6() {
 }
 
 pub fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("encode_vp6", |b| b.iter(|| encode_vp6));
+    c.bench_function("encode_vp6", |b| b.iter(encode_vp6));
 }
 
 criterion_group! {
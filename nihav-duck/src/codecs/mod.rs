@@ -28,6 +28,20 @@ mod vp6data;
 mod vp6dec;
 #[cfg(any(feature = "decoder_vp6", feature = "encoder_vp6"))]
 mod vp6dsp;
+#[cfg(all(
+    target_arch = "x86_64",
+    any(feature = "decoder_vp6", feature = "encoder_vp6")
+))]
+mod vpsimd;
+
+#[cfg(feature = "decoder_vp6")]
+pub use self::vp56::{MBParams, VP56FrameInfo};
+#[cfg(feature = "decoder_vp6")]
+pub use self::vp6dec::{
+    parse_frame_header, VP6FrameHeader, VP6HeaderParser, VP6KeyframeParams, VP6StreamHeader,
+};
+#[cfg(feature = "decoder_vp6")]
+pub use self::vpcommon::{VPMBType, VP_REF_GOLDEN, VP_REF_INTER};
 
 const DUCK_CODECS: &[DecoderInfo] = &[
     #[cfg(feature = "decoder_vp6")]
@@ -69,6 +83,11 @@ const DUCK_ENCODERS: &[EncoderInfo] = &[
         name: "vp6f",
         get_encoder: vp6enc::get_encoder_flv,
     },
+    #[cfg(feature = "encoder_vp6")]
+    EncoderInfo {
+        name: "vp6a",
+        get_encoder: vp6enc::get_encoder_alpha,
+    },
 ];
 
 /// Registers all available encoders provided by this crate.
@@ -77,3 +96,31 @@ pub fn duck_register_all_encoders(re: &mut RegisteredEncoders) {
         re.add_encoder(*encoder);
     }
 }
+
+#[cfg(feature = "packetiser_vp6")]
+mod vp6pkt;
+
+const DUCK_PACKETISERS: &[PacketiserInfo] = &[
+    #[cfg(feature = "packetiser_vp6")]
+    PacketiserInfo {
+        name: "vp6f",
+        get_packetiser: vp6pkt::get_packetiser_vp6f,
+    },
+    #[cfg(feature = "packetiser_vp6")]
+    PacketiserInfo {
+        name: "vp6",
+        get_packetiser: vp6pkt::get_packetiser_vp6,
+    },
+    #[cfg(feature = "packetiser_vp6")]
+    PacketiserInfo {
+        name: "vp6a",
+        get_packetiser: vp6pkt::get_packetiser_vp6_alpha,
+    },
+];
+
+/// Registers all available packetisers provided by this crate.
+pub fn duck_register_all_packetisers(rp: &mut RegisteredPacketisers) {
+    for packetiser in DUCK_PACKETISERS.iter() {
+        rp.add_packetiser(*packetiser);
+    }
+}
@@ -4,6 +4,7 @@ use super::vpcommon::*;
 use nihav_codec_support::codecs::{MV, ZERO_MV};
 use nihav_core::codecs::*;
 use nihav_core::io::bitreader::*;
+use std::sync::mpsc;
 
 pub const TOKEN_LARGE: u8 = 5;
 pub const TOKEN_EOB: u8 = 42;
@@ -243,7 +244,7 @@ impl VP56Models {
     }
 }
 
-pub trait VP56Parser {
+pub trait VP56Parser: Sync {
     fn parse_header(&mut self, bc: &mut BoolCoder) -> DecoderResult<VP56Header>;
     fn reset_models(&self, models: &mut VP56Models);
     fn decode_mv(&self, bc: &mut BoolCoder, model: &VP56MVModel) -> i16;
@@ -282,6 +283,7 @@ pub trait VP56Parser {
         plane: usize,
         x: usize,
         y: usize,
+        dst_y: usize,
         mv: MV,
         loop_tab: &[i8; 256],
     );
@@ -299,6 +301,263 @@ struct MBInfo {
     mv: MV,
 }
 
+/// Parameters of a decoded macroblock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MBParams {
+    /// Macroblock type (the reference frame is reported by its `get_ref_id()`).
+    pub mb_type: VPMBType,
+    /// Motion vectors for each luma block.
+    pub mvs: [MV; 4],
+    /// Block has non-zero residual.
+    pub coded: [bool; 6],
+    /// Block has non-zero AC coefficients.
+    pub has_ac: [bool; 6],
+    /// Macroblock uses interlaced coding.
+    pub ilace: bool,
+}
+
+/// Macroblock information for a decoded frame.
+#[derive(Clone, Debug, Default)]
+pub struct VP56FrameInfo {
+    /// Frame width in macroblocks.
+    pub mb_w: usize,
+    /// Frame height in macroblocks.
+    pub mb_h: usize,
+    /// Frame quantiser.
+    pub quant: u8,
+    /// Macroblock parameters in raster order.
+    pub mb_params: Vec<MBParams>,
+}
+
+/// Parsed macroblock data needed for reconstruction.
+#[derive(Clone, Copy)]
+struct MBRecon {
+    mb_type: VPMBType,
+    mv: MV,
+    four_mv: [MV; 4],
+    ilace: bool,
+    has_ac: [bool; 6],
+    coeffs: [[i16; 64]; 6],
+}
+
+impl MBRecon {
+    fn get_params(&self) -> MBParams {
+        let mut params = MBParams {
+            mb_type: self.mb_type,
+            mvs: if self.mb_type == VPMBType::InterFourMV {
+                self.four_mv
+            } else {
+                [self.mv; 4]
+            },
+            coded: [false; 6],
+            has_ac: self.has_ac,
+            ilace: self.ilace,
+        };
+        for (coded, (blk, &has_ac)) in params
+            .coded
+            .iter_mut()
+            .zip(self.coeffs.iter().zip(self.has_ac.iter()))
+        {
+            *coded = self.mb_type.is_intra() || has_ac || blk[0] != 0;
+        }
+        params
+    }
+}
+
+impl Default for MBRecon {
+    fn default() -> Self {
+        Self {
+            mb_type: VPMBType::Intra,
+            mv: ZERO_MV,
+            four_mv: [ZERO_MV; 4],
+            ilace: false,
+            has_ac: [false; 6],
+            coeffs: [[0; 64]; 6],
+        }
+    }
+}
+
+/// Reference frames and parameters for reconstructing parsed macroblocks.
+struct ReconCtx<'a> {
+    br: &'a dyn VP56Parser,
+    last: Option<NAVideoBufferRef<u8>>,
+    golden: Option<NAVideoBufferRef<u8>>,
+    loop_tab: [i8; 256],
+    alpha: bool,
+}
+
+impl ReconCtx<'_> {
+    /// Reconstructs macroblock into row `dst_mb_y` of the provided frame.
+    fn recon_mb(
+        &self,
+        frm: &mut NASimpleVideoFrame<u8>,
+        mc_buf: &NAVideoBufferRef<u8>,
+        mb_x: usize,
+        mb_y: usize,
+        dst_mb_y: usize,
+        mb: &mut MBRecon,
+    ) {
+        let mb_type = mb.mb_type;
+        if !mb_type.is_intra() && (mb_type != VPMBType::InterFourMV) {
+            self.do_mc(frm, mc_buf, mb_x, mb_y, dst_mb_y, mb_type, mb.mv);
+        } else if mb_type == VPMBType::InterFourMV {
+            self.do_fourmv(frm, mc_buf, mb_x, mb_y, dst_mb_y, &mb.four_mv);
+        }
+
+        let plane = if !self.alpha { 0 } else { 3 };
+        for blk_no in 0..4 {
+            let bx = mb_x * 2 + (blk_no & 1);
+            let by = dst_mb_y * 2 + (blk_no >> 1);
+            let has_ac = mb.has_ac[blk_no];
+            let coeffs = &mut mb.coeffs[blk_no];
+            if mb_type.is_intra() {
+                if !mb.ilace {
+                    if has_ac {
+                        vp_put_block(coeffs, bx, by, plane, frm);
+                    } else {
+                        vp_put_block_dc(coeffs, bx, by, plane, frm);
+                    }
+                } else {
+                    vp_put_block_ilace(coeffs, bx, by, plane, frm);
+                }
+            } else {
+                if !mb.ilace {
+                    if has_ac {
+                        vp_add_block(coeffs, bx, by, plane, frm);
+                    } else {
+                        vp_add_block_dc(coeffs, bx, by, plane, frm);
+                    }
+                } else {
+                    vp_add_block_ilace(coeffs, bx, by, plane, frm);
+                }
+            }
+        }
+        if !self.alpha {
+            for blk_no in 4..6 {
+                let plane = blk_no - 3;
+                let has_ac = mb.has_ac[blk_no];
+                let coeffs = &mut mb.coeffs[blk_no];
+                if mb_type.is_intra() {
+                    if has_ac {
+                        vp_put_block(coeffs, mb_x, dst_mb_y, plane, frm);
+                    } else {
+                        vp_put_block_dc(coeffs, mb_x, dst_mb_y, plane, frm);
+                    }
+                } else {
+                    if has_ac {
+                        vp_add_block(coeffs, mb_x, dst_mb_y, plane, frm);
+                    } else {
+                        vp_add_block_dc(coeffs, mb_x, dst_mb_y, plane, frm);
+                    }
+                }
+            }
+        }
+    }
+    fn do_mc(
+        &self,
+        frm: &mut NASimpleVideoFrame<u8>,
+        mc_buf: &NAVideoBufferRef<u8>,
+        mb_x: usize,
+        mb_y: usize,
+        dst_mb_y: usize,
+        mb_type: VPMBType,
+        mv: MV,
+    ) {
+        let x = mb_x * 16;
+        let y = mb_y * 16;
+        let dst_y = dst_mb_y * 16;
+        let plane = if !self.alpha { 0 } else { 3 };
+        let src = if mb_type.get_ref_id() == VP_REF_INTER {
+            self.last.clone().unwrap()
+        } else {
+            self.golden.clone().unwrap()
+        };
+
+        for blk_no in 0..4 {
+            self.br.mc_block(
+                frm,
+                mc_buf.clone(),
+                src.clone(),
+                plane,
+                x + (blk_no & 1) * 8,
+                y + (blk_no & 2) * 4,
+                dst_y + (blk_no & 2) * 4,
+                mv,
+                &self.loop_tab,
+            );
+        }
+        if !self.alpha {
+            let x = mb_x * 8;
+            let y = mb_y * 8;
+            let dst_y = dst_mb_y * 8;
+            self.br.mc_block(
+                frm,
+                mc_buf.clone(),
+                src.clone(),
+                1,
+                x,
+                y,
+                dst_y,
+                mv,
+                &self.loop_tab,
+            );
+            self.br
+                .mc_block(frm, mc_buf.clone(), src, 2, x, y, dst_y, mv, &self.loop_tab);
+        }
+    }
+    fn do_fourmv(
+        &self,
+        frm: &mut NASimpleVideoFrame<u8>,
+        mc_buf: &NAVideoBufferRef<u8>,
+        mb_x: usize,
+        mb_y: usize,
+        dst_mb_y: usize,
+        mvs: &[MV; 4],
+    ) {
+        let x = mb_x * 16;
+        let y = mb_y * 16;
+        let dst_y = dst_mb_y * 16;
+        let plane = if !self.alpha { 0 } else { 3 };
+        let src = self.last.clone().unwrap();
+        for blk_no in 0..4 {
+            self.br.mc_block(
+                frm,
+                mc_buf.clone(),
+                src.clone(),
+                plane,
+                x + (blk_no & 1) * 8,
+                y + (blk_no & 2) * 4,
+                dst_y + (blk_no & 2) * 4,
+                mvs[blk_no],
+                &self.loop_tab,
+            );
+        }
+        if !self.alpha {
+            let x = mb_x * 8;
+            let y = mb_y * 8;
+            let dst_y = dst_mb_y * 8;
+            let sum = mvs[0] + mvs[1] + mvs[2] + mvs[3];
+            let mv = MV {
+                x: sum.x / 4,
+                y: sum.y / 4,
+            };
+            self.br.mc_block(
+                frm,
+                mc_buf.clone(),
+                src.clone(),
+                1,
+                x,
+                y,
+                dst_y,
+                mv,
+                &self.loop_tab,
+            );
+            self.br
+                .mc_block(frm, mc_buf.clone(), src, 2, x, y, dst_y, mv, &self.loop_tab);
+        }
+    }
+}
+
 pub struct FrameState {
     pub mb_x: usize,
     pub mb_y: usize,
@@ -409,6 +668,20 @@ pub struct VP56Decoder {
     top_ctx: [Vec<u8>; 4],
 
     mc_buf: NAVideoBufferRef<u8>,
+    threads: usize,
+    mc_bufs: Vec<NAVideoBufferRef<u8>>,
+    recon_bufs: Vec<Vec<u8>>,
+
+    conceal: bool,
+    grey_ref: bool,
+    damaged: bool,
+    broken: bool,
+
+    quant: u8,
+    disp_w: usize,
+    disp_h: usize,
+    scale: u8,
+    mb_params: Vec<MBParams>,
 }
 
 fn rescale_mb_mode_prob(prob: u32, total: u32) -> u8 {
@@ -503,8 +776,76 @@ impl VP56Decoder {
             top_ctx: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
 
             mc_buf,
+            threads: 1,
+            mc_bufs: Vec::new(),
+            recon_bufs: Vec::new(),
+
+            conceal: false,
+            grey_ref: false,
+            damaged: false,
+            broken: false,
+
+            quant: 0,
+            disp_w: 0,
+            disp_h: 0,
+            scale: 0,
+            mb_params: Vec::new(),
         }
     }
+    /// Sets the number of threads used for macroblock reconstruction.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+    /// Enables concealment of corrupted or truncated frames instead of reporting errors.
+    pub fn set_conceal(&mut self, conceal: bool) {
+        self.conceal = conceal;
+    }
+    pub fn get_conceal(&self) -> bool {
+        self.conceal
+    }
+    /// Allows decoding to start from an inter frame using a grey reference frame.
+    pub fn set_grey_ref(&mut self, grey_ref: bool) {
+        self.grey_ref = grey_ref;
+    }
+    pub fn get_grey_ref(&self) -> bool {
+        self.grey_ref
+    }
+    /// Reports whether the last decoded frame had errors concealed.
+    pub fn is_damaged(&self) -> bool {
+        self.damaged
+    }
+    /// Returns frame size in macroblocks.
+    pub fn get_mb_dimensions(&self) -> (usize, usize) {
+        (self.mb_w, self.mb_h)
+    }
+    /// Returns the quantiser of the last decoded frame.
+    pub fn get_quant(&self) -> u8 {
+        self.quant
+    }
+    /// Returns parameters of the macroblocks in the last decoded frame.
+    pub fn get_mb_params(&self) -> &[MBParams] {
+        &self.mb_params
+    }
+    /// Returns macroblock information for the last decoded frame.
+    pub fn get_frame_info(&self) -> VP56FrameInfo {
+        VP56FrameInfo {
+            mb_w: self.mb_w,
+            mb_h: self.mb_h,
+            quant: self.quant,
+            mb_params: self.mb_params.clone(),
+        }
+    }
+    /// Returns the part of the picture that should be displayed.
+    pub fn get_display_size(&self) -> (usize, usize) {
+        (self.width.min(self.disp_w), self.height.min(self.disp_h))
+    }
+    /// Returns the scaling mode requested by the last keyframe.
+    pub fn get_scale_mode(&self) -> u8 {
+        self.scale
+    }
     fn set_dimensions(&mut self, width: usize, height: usize) {
         self.width = width;
         self.height = height;
@@ -512,12 +853,15 @@ impl VP56Decoder {
         self.mb_h = (self.height + 15) >> 4;
         self.mb_info
             .resize(self.mb_w * self.mb_h, MBInfo::default());
+        self.mb_params
+            .resize(self.mb_w * self.mb_h, MBParams::default());
         self.top_ctx = [
             vec![0; self.mb_w * 2],
             vec![0; self.mb_w],
             vec![0; self.mb_w],
             vec![0; self.mb_w * 2],
         ];
+        self.dc_pred.resize(self.mb_w);
     }
     pub fn init(&mut self, supp: &mut NADecoderSupport, vinfo: NAVideoInfo) -> DecoderResult<()> {
         supp.pool_u8
@@ -532,7 +876,9 @@ impl VP56Decoder {
             4,
         )?;
         self.set_dimensions(vinfo.get_width(), vinfo.get_height());
-        self.dc_pred.resize(self.mb_w);
+        self.disp_w = self.width;
+        self.disp_h = self.height;
+        self.scale = 0;
         Ok(())
     }
     pub fn flush(&mut self) {
@@ -544,26 +890,29 @@ impl VP56Decoder {
         src: &[u8],
         br: &mut dyn VP56Parser,
     ) -> DecoderResult<(NABufferType, FrameType)> {
-        let aoffset;
-        let mut bc;
-        if self.has_alpha {
-            validate!(src.len() >= 7);
-            aoffset = ((src[0] as usize) << 16) | ((src[1] as usize) << 8) | (src[2] as usize);
-            validate!((aoffset > 0) && (aoffset < src.len() - 3));
-            bc = BoolCoder::new(&src[3..])?;
-        } else {
-            validate!(src.len() >= 4);
-            aoffset = src.len();
-            bc = BoolCoder::new(src)?;
-        }
-        let hdr = br.parse_header(&mut bc)?;
-        validate!((hdr.offset as usize) < aoffset); //XXX: take alpha 3 byte offset into account?
+        self.damaged = false;
+        let (aoffset, mut bc, hdr) = match self.parse_frame_start(src, br) {
+            Ok(ret) => ret,
+            Err(err) => {
+                // nothing can be decoded so output the previous frame instead
+                if let (true, Some(last)) = (self.conceal, self.shuf.get_last()) {
+                    self.damaged = true;
+                    return Ok((NABufferType::Video(last), FrameType::P));
+                }
+                return Err(err);
+            }
+        };
 
         if hdr.mb_w != 0
             && (usize::from(hdr.mb_w) != self.mb_w || usize::from(hdr.mb_h) != self.mb_h)
         {
             self.set_dimensions((hdr.mb_w as usize) * 16, (hdr.mb_h as usize) * 16);
         }
+        if hdr.mb_w != 0 {
+            self.disp_w = usize::from(hdr.disp_w) * 16;
+            self.disp_h = usize::from(hdr.disp_h) * 16;
+            self.scale = hdr.scale;
+        }
         let fmt = if !self.has_alpha {
             YUV420_FORMAT
         } else {
@@ -585,29 +934,42 @@ impl VP56Decoder {
             }
             buf = ret.unwrap();
         }
-        let mut dframe = NASimpleVideoFrame::from_video_buf(&mut buf).unwrap();
-
-        if hdr.is_intra {
-            self.shuf.clear();
-        } else {
-            if !self.shuf.has_refs() {
+        if !hdr.is_intra && !self.shuf.has_refs() {
+            if !self.grey_ref {
                 return Err(DecoderError::MissingReference);
             }
+            self.add_grey_refs(supp, br)?;
+            self.damaged = true;
         }
 
-        let psrc = &src[if self.has_alpha { 3 } else { 0 }..aoffset];
+        let mut dframe = NASimpleVideoFrame::from_video_buf(&mut buf).unwrap();
+
+        let psrc = if self.has_alpha {
+            &src[3..aoffset + 3]
+        } else {
+            src
+        };
         self.decode_planes(br, &mut dframe, &mut bc, &hdr, psrc, false)?;
 
         if self.has_alpha {
             let asrc = &src[aoffset + 3..];
-            let mut bc = BoolCoder::new(asrc)?;
-            let ahdr = br.parse_header(&mut bc)?;
-            validate!(ahdr.mb_w == hdr.mb_w && ahdr.mb_h == hdr.mb_h);
-            std::mem::swap(&mut self.models, &mut self.amodels);
-            let ret = self.decode_planes(br, &mut dframe, &mut bc, &ahdr, asrc, true);
-            std::mem::swap(&mut self.models, &mut self.amodels);
-            if let Err(err) = ret {
-                return Err(err);
+            let ahdr = match Self::parse_alpha_header(asrc, br, &hdr) {
+                Ok((mut bc, ahdr)) => {
+                    std::mem::swap(&mut self.models, &mut self.amodels);
+                    let ret = self.decode_planes(br, &mut dframe, &mut bc, &ahdr, asrc, true);
+                    std::mem::swap(&mut self.models, &mut self.amodels);
+                    ret?;
+                    ahdr
+                }
+                Err(_) if self.conceal => {
+                    self.conceal_alpha(&mut dframe);
+                    hdr
+                }
+                Err(err) => return Err(err),
+            };
+            // an intra frame replaces all references
+            if hdr.is_intra {
+                self.shuf.clear();
             }
             match (hdr.is_golden, ahdr.is_golden) {
                 (true, true) => {
@@ -639,6 +1001,9 @@ impl VP56Decoder {
             };
         }
 
+        if hdr.is_intra && !self.has_alpha {
+            self.shuf.clear();
+        }
         if hdr.is_golden && !self.has_alpha {
             self.shuf.add_golden_frame(buf.clone());
         }
@@ -653,27 +1018,100 @@ impl VP56Decoder {
             },
         ))
     }
-    fn decode_planes(
+    fn parse_frame_start<'a>(
+        &self,
+        src: &'a [u8],
+        br: &mut dyn VP56Parser,
+    ) -> DecoderResult<(usize, BoolCoder<'a>, VP56Header)> {
+        let aoffset;
+        let mut bc;
+        if self.has_alpha {
+            validate!(src.len() >= 7);
+            aoffset = ((src[0] as usize) << 16) | ((src[1] as usize) << 8) | (src[2] as usize);
+            validate!((aoffset > 0) && (aoffset < src.len() - 3));
+            bc = BoolCoder::new(&src[3..])?;
+        } else {
+            validate!(src.len() >= 4);
+            aoffset = src.len();
+            bc = BoolCoder::new(src)?;
+        }
+        let hdr = br.parse_header(&mut bc)?;
+        validate!((hdr.offset as usize) < aoffset);
+        Ok((aoffset, bc, hdr))
+    }
+    fn parse_alpha_header<'a>(
+        asrc: &'a [u8],
+        br: &mut dyn VP56Parser,
+        hdr: &VP56Header,
+    ) -> DecoderResult<(BoolCoder<'a>, VP56Header)> {
+        let mut bc = BoolCoder::new(asrc)?;
+        let ahdr = br.parse_header(&mut bc)?;
+        validate!(ahdr.mb_w == hdr.mb_w && ahdr.mb_h == hdr.mb_h);
+        validate!((ahdr.offset as usize) < asrc.len());
+        Ok((bc, ahdr))
+    }
+    /// Creates grey reference frames so decoding may start from an inter frame.
+    fn add_grey_refs(
+        &mut self,
+        supp: &mut NADecoderSupport,
+        br: &dyn VP56Parser,
+    ) -> DecoderResult<()> {
+        if self.mb_w == 0 || self.mb_h == 0 {
+            return Err(DecoderError::MissingReference);
+        }
+        let mut grey = supp.pool_u8.get_free().ok_or(DecoderError::AllocError)?;
+        let alpha_off = if self.has_alpha {
+            grey.get_offset(3)
+        } else {
+            grey.get_data().len()
+        };
+        let data = grey.get_data_mut().unwrap();
+        for el in data[..alpha_off].iter_mut() {
+            *el = 0x80;
+        }
+        for el in data[alpha_off..].iter_mut() {
+            *el = 0xFF;
+        }
+        // there was no keyframe to initialise the models
+        for _ in 0..2 {
+            br.reset_models(&mut self.models);
+            self.reset_mbtype_models();
+            std::mem::swap(&mut self.models, &mut self.amodels);
+        }
+        self.shuf.add_golden_frame(grey.clone());
+        self.shuf.add_frame(grey);
+        Ok(())
+    }
+    /// Replaces the alpha plane with the one from the previous frame.
+    fn conceal_alpha(&mut self, dframe: &mut NASimpleVideoFrame<u8>) {
+        self.damaged = true;
+        let off = dframe.offset[3];
+        let size = dframe.stride[3] * self.mb_h * 16;
+        if let Some(last) = self.shuf.get_last() {
+            dframe.data[off..][..size].copy_from_slice(&last.get_data()[off..][..size]);
+        } else {
+            for el in dframe.data[off..][..size].iter_mut() {
+                *el = 0xFF;
+            }
+        }
+    }
+    fn decode_frame_models<'a>(
         &mut self,
         br: &mut dyn VP56Parser,
-        dframe: &mut NASimpleVideoFrame<u8>,
         bc: &mut BoolCoder,
+        cr: &mut CoeffReader<'a>,
         hdr: &VP56Header,
-        src: &[u8],
-        alpha: bool,
+        src: &'a [u8],
     ) -> DecoderResult<()> {
-        let mut cr;
         if hdr.multistream {
             let off = hdr.offset as usize;
             if !hdr.use_huffman {
                 let bc2 = BoolCoder::new(&src[off..])?;
-                cr = CoeffReader::Bool(bc2);
+                *cr = CoeffReader::Bool(bc2);
             } else {
                 let br = BitReader::new(&src[off..], BitReaderMode::BE);
-                cr = CoeffReader::Huff(br);
+                *cr = CoeffReader::Huff(br);
             }
-        } else {
-            cr = CoeffReader::None;
         }
 
         if hdr.is_intra {
@@ -702,11 +1140,33 @@ impl VP56Decoder {
                     .build_codes_zero_run(&self.models.vp6models.zero_run_probs[i]);
             }
         }
+        Ok(())
+    }
+    fn decode_planes(
+        &mut self,
+        br: &mut dyn VP56Parser,
+        dframe: &mut NASimpleVideoFrame<u8>,
+        bc: &mut BoolCoder,
+        hdr: &VP56Header,
+        src: &[u8],
+        alpha: bool,
+    ) -> DecoderResult<()> {
+        self.broken = false;
+        let mut cr = CoeffReader::None;
+        if let Err(err) = self.decode_frame_models(br, bc, &mut cr, hdr, src) {
+            if !self.conceal {
+                return Err(err);
+            }
+            self.broken = true;
+        }
 
         if hdr.interlaced {
             self.ilace_prob = bc.read_bits(8) as u8;
         }
 
+        if !alpha {
+            self.quant = hdr.quant;
+        }
         self.fstate = FrameState::new();
         self.fstate.dc_quant = VP56_DC_QUANTS[hdr.quant as usize] * 4;
         self.fstate.ac_quant = VP56_AC_QUANTS[hdr.quant as usize] * 4;
@@ -727,19 +1187,213 @@ impl VP56Decoder {
         self.dc_pred.reset();
 
         self.ilace_mb = false;
-        for mb_y in 0..self.mb_h {
-            self.fstate.mb_y = mb_y;
-            self.fstate.coeff_cat = [[0; 64]; 4];
-            self.fstate.last_idx = [24; 4];
-            for mb_x in 0..self.mb_w {
-                self.fstate.mb_x = mb_x;
-                self.decode_mb(dframe, bc, &mut cr, br, hdr, alpha)?;
-                self.dc_pred.next_mb();
+        let br: &dyn VP56Parser = br;
+        let rctx = ReconCtx {
+            br,
+            last: self.shuf.get_last(),
+            golden: self.shuf.get_golden(),
+            loop_tab: self.loop_tab,
+            alpha,
+        };
+        let num_threads = self.threads.min(self.mb_h);
+        if num_threads <= 1 {
+            let mc_buf = self.mc_buf.clone();
+            let mut mb = MBRecon::default();
+            for mb_y in 0..self.mb_h {
+                self.start_row(mb_y);
+                for mb_x in 0..self.mb_w {
+                    self.fstate.mb_x = mb_x;
+                    self.parse_mb(bc, &mut cr, &rctx, hdr, &mut mb)?;
+                    rctx.recon_mb(dframe, &mc_buf, mb_x, mb_y, mb_y, &mut mb);
+                    self.dc_pred.next_mb();
+                }
+                self.dc_pred.update_row();
             }
-            self.dc_pred.update_row();
+        } else {
+            self.decode_planes_mt(dframe, bc, &mut cr, &rctx, hdr, num_threads)?;
+        }
+        self.damaged |= self.broken;
+        Ok(())
+    }
+    /// Parses the next macroblock or conceals it once the data is known to be broken.
+    fn parse_mb(
+        &mut self,
+        bc: &mut BoolCoder,
+        cr: &mut CoeffReader,
+        rctx: &ReconCtx,
+        hdr: &VP56Header,
+        mb: &mut MBRecon,
+    ) -> DecoderResult<()> {
+        if !self.broken {
+            let ret = self.decode_mb(bc, cr, rctx.br, hdr, rctx.alpha, mb);
+            let overrun =
+                bc.is_exhausted() || matches!(cr, CoeffReader::Bool(ref bcc) if bcc.is_exhausted());
+            match ret {
+                Ok(()) if !self.conceal || !overrun => {}
+                Err(err) if !self.conceal => return Err(err),
+                _ => self.broken = true,
+            };
+        }
+        if self.broken {
+            self.conceal_mb(mb, rctx.last.is_some());
+        }
+        if !rctx.alpha {
+            let mb_pos = self.fstate.mb_x + self.fstate.mb_y * self.mb_w;
+            self.mb_params[mb_pos] = mb.get_params();
         }
         Ok(())
     }
+    /// Fills the current macroblock by extrapolating motion from the neighbours.
+    ///
+    /// The macroblock above belongs to the current frame while the co-located one
+    /// still holds data from the previous frame.
+    fn conceal_mb(&mut self, mb: &mut MBRecon, has_last: bool) {
+        let mb_pos = self.fstate.mb_x + self.fstate.mb_y * self.mb_w;
+        *mb = MBRecon::default();
+        if has_last {
+            let mut cand = vec![mb_pos];
+            if self.fstate.mb_y > 0 {
+                cand.insert(0, mb_pos - self.mb_w);
+            }
+            mb.mb_type = VPMBType::InterNoMV;
+            for pos in cand.into_iter() {
+                let info = self.mb_info[pos];
+                if info.mb_type.get_ref_id() == VP_REF_INTER && info.mv != ZERO_MV {
+                    mb.mb_type = VPMBType::InterMV;
+                    mb.mv = info.mv;
+                    break;
+                }
+            }
+        }
+        self.mb_info[mb_pos] = MBInfo {
+            mb_type: mb.mb_type,
+            mv: mb.mv,
+        };
+    }
+    fn start_row(&mut self, mb_y: usize) {
+        self.fstate.mb_y = mb_y;
+        self.fstate.coeff_cat = [[0; 64]; 4];
+        self.fstate.last_idx = [24; 4];
+    }
+    /// Parses macroblock rows and hands them to worker threads for reconstruction.
+    ///
+    /// Reconstruction reads only reference frames so rows are independent and
+    /// worker `n` renders every `n`-th row into its own buffer.
+    fn decode_planes_mt(
+        &mut self,
+        dframe: &mut NASimpleVideoFrame<u8>,
+        bc: &mut BoolCoder,
+        cr: &mut CoeffReader,
+        rctx: &ReconCtx,
+        hdr: &VP56Header,
+        num_threads: usize,
+    ) -> DecoderResult<()> {
+        while self.mc_bufs.len() < num_threads {
+            let vt = alloc_video_buffer(self.mc_buf.get_info(), 4)?;
+            self.mc_bufs.push(vt.get_vbuf().unwrap());
+        }
+        let mc_bufs = std::mem::take(&mut self.mc_bufs);
+
+        // threads reconstruct single macroblock rows which are copied into the frame afterwards
+        let planes: &[(usize, usize)] = if !rctx.alpha {
+            &[(0, 16), (1, 8), (2, 8)]
+        } else {
+            &[(3, 16)]
+        };
+        let mut row_offset = [0; 4];
+        let mut row_size = 0;
+        for &(plane, rows) in planes.iter() {
+            row_offset[plane] = row_size;
+            row_size += rows * dframe.stride[plane];
+        }
+        let copy_row = |dframe: &mut NASimpleVideoFrame<u8>, mb_y: usize, src: &[u8]| {
+            for &(plane, rows) in planes.iter() {
+                let stride = dframe.stride[plane];
+                let off = dframe.offset[plane] + mb_y * rows * stride;
+                let size = rows * stride;
+                dframe.data[off..][..size].copy_from_slice(&src[row_offset[plane]..][..size]);
+            }
+        };
+        let (width, height, flip) = (dframe.width, dframe.height, dframe.flip);
+        let (stride, components) = (dframe.stride, dframe.components);
+        let mut free_bufs = std::mem::take(&mut self.recon_bufs);
+
+        let ret = std::thread::scope(|s| {
+            let (done_tx, done_rx) = mpsc::channel::<(usize, Vec<u8>)>();
+            let mut senders = Vec::with_capacity(num_threads);
+            let mut workers = Vec::with_capacity(num_threads);
+            for mc_buf in mc_bufs.iter().take(num_threads) {
+                let (tx, rx) = mpsc::channel::<(usize, Vec<MBRecon>, Vec<u8>)>();
+                senders.push(tx);
+                let done_tx = done_tx.clone();
+                workers.push(s.spawn(move || {
+                    for (mb_y, mut row, mut buf) in rx.iter() {
+                        let mut frm = NASimpleVideoFrame {
+                            width,
+                            height,
+                            flip,
+                            stride,
+                            offset: row_offset,
+                            components,
+                            data: buf.as_mut_slice(),
+                        };
+                        for (mb_x, mb) in row.iter_mut().enumerate() {
+                            rctx.recon_mb(&mut frm, mc_buf, mb_x, mb_y, 0, mb);
+                        }
+                        if done_tx.send((mb_y, buf)).is_err() {
+                            break;
+                        }
+                    }
+                }));
+            }
+            drop(done_tx);
+
+            let ret = (|| {
+                let mut rows_left = self.mb_h;
+                for mb_y in 0..self.mb_h {
+                    self.start_row(mb_y);
+                    let mut row = vec![MBRecon::default(); self.mb_w];
+                    for (mb_x, mb) in row.iter_mut().enumerate() {
+                        self.fstate.mb_x = mb_x;
+                        self.parse_mb(bc, cr, rctx, hdr, mb)?;
+                        self.dc_pred.next_mb();
+                    }
+                    self.dc_pred.update_row();
+                    for (done_y, buf) in done_rx.try_iter() {
+                        copy_row(dframe, done_y, &buf);
+                        free_bufs.push(buf);
+                        rows_left -= 1;
+                    }
+                    let mut buf = free_bufs.pop().unwrap_or_default();
+                    buf.resize(row_size, 0);
+                    senders[mb_y % num_threads]
+                        .send((mb_y, row, buf))
+                        .map_err(|_| DecoderError::Bug)?;
+                }
+                drop(senders);
+                while rows_left > 0 {
+                    let (done_y, buf) = done_rx.recv().map_err(|_| DecoderError::Bug)?;
+                    copy_row(dframe, done_y, &buf);
+                    free_bufs.push(buf);
+                    rows_left -= 1;
+                }
+                Ok(())
+            })();
+            // a worker may fail only on bad data, report it instead of panicking
+            let mut worker_failed = false;
+            for worker in workers {
+                worker_failed |= worker.join().is_err();
+            }
+            if worker_failed {
+                return Err(DecoderError::InvalidData);
+            }
+            ret
+        });
+
+        self.recon_bufs = free_bufs;
+        self.mc_bufs = mc_bufs;
+        ret
+    }
     fn reset_mbtype_models(&mut self) {
         const DEFAULT_XMITTED_PROBS: [[u8; 20]; 3] = [
             [
@@ -882,7 +1536,7 @@ impl VP56Decoder {
 
         (num_mv, nearest_mv, near_mv, pred_mv)
     }
-    fn decode_mv(&self, bc: &mut BoolCoder, br: &mut dyn VP56Parser) -> MV {
+    fn decode_mv(&self, bc: &mut BoolCoder, br: &dyn VP56Parser) -> MV {
         let x = br.decode_mv(bc, &self.models.mv_models[0]);
         let y = br.decode_mv(bc, &self.models.mv_models[1]);
         MV { x, y }
@@ -917,12 +1571,12 @@ impl VP56Decoder {
     #[allow(clippy::cognitive_complexity)]
     fn decode_mb(
         &mut self,
-        frm: &mut NASimpleVideoFrame<u8>,
         bc: &mut BoolCoder,
         cr: &mut CoeffReader,
-        br: &mut dyn VP56Parser,
+        br: &dyn VP56Parser,
         hdr: &VP56Header,
         alpha: bool,
+        mb: &mut MBRecon,
     ) -> DecoderResult<()> {
         const FOURMV_SUB_TYPE: [VPMBType; 4] = [
             VPMBType::InterNoMV,
@@ -1019,12 +1673,6 @@ impl VP56Decoder {
                 _ => unreachable!(),
             };
         }
-        if !mb_type.is_intra() && (mb_type != VPMBType::InterFourMV) {
-            self.do_mc(br, frm, mb_type, self.mb_info[mb_pos].mv, alpha);
-        } else if mb_type == VPMBType::InterFourMV {
-            self.do_fourmv(br, frm, &four_mv, alpha);
-        }
-
         for blk_no in 0..4 {
             self.fstate.plane = if !alpha { 0 } else { 3 };
             self.fstate.ctx_idx = blk_no >> 1;
@@ -1060,31 +1708,7 @@ impl VP56Decoder {
             };
             self.top_ctx[self.fstate.plane][mb_x * 2 + (blk_no & 1)] = self.fstate.top_ctx;
             self.predict_dc(mb_type, mb_pos, blk_no, alpha);
-
-            let bx = mb_x * 2 + (blk_no & 1);
-            let by = mb_y * 2 + (blk_no >> 1);
-            let has_ac = self.fstate.last_idx[self.fstate.ctx_idx] > 0;
-            if mb_type.is_intra() {
-                if !self.ilace_mb {
-                    if has_ac {
-                        vp_put_block(&mut self.coeffs[blk_no], bx, by, self.fstate.plane, frm);
-                    } else {
-                        vp_put_block_dc(&mut self.coeffs[blk_no], bx, by, self.fstate.plane, frm);
-                    }
-                } else {
-                    vp_put_block_ilace(&mut self.coeffs[blk_no], bx, by, self.fstate.plane, frm);
-                }
-            } else {
-                if !self.ilace_mb {
-                    if has_ac {
-                        vp_add_block(&mut self.coeffs[blk_no], bx, by, self.fstate.plane, frm);
-                    } else {
-                        vp_add_block_dc(&mut self.coeffs[blk_no], bx, by, self.fstate.plane, frm);
-                    }
-                } else {
-                    vp_add_block_ilace(&mut self.coeffs[blk_no], bx, by, self.fstate.plane, frm);
-                }
-            }
+            mb.has_ac[blk_no] = self.fstate.last_idx[self.fstate.ctx_idx] > 0;
         }
         for blk_no in 4..6 {
             self.fstate.plane = blk_no - 3;
@@ -1121,154 +1745,15 @@ impl VP56Decoder {
             };
             self.top_ctx[self.fstate.plane][mb_x] = self.fstate.top_ctx;
             self.predict_dc(mb_type, mb_pos, blk_no, alpha);
-            if !alpha {
-                let has_ac = self.fstate.last_idx[self.fstate.ctx_idx] > 0;
-                if mb_type.is_intra() {
-                    if has_ac {
-                        vp_put_block(&mut self.coeffs[blk_no], mb_x, mb_y, self.fstate.plane, frm);
-                    } else {
-                        vp_put_block_dc(
-                            &mut self.coeffs[blk_no],
-                            mb_x,
-                            mb_y,
-                            self.fstate.plane,
-                            frm,
-                        );
-                    }
-                } else {
-                    if has_ac {
-                        vp_add_block(&mut self.coeffs[blk_no], mb_x, mb_y, self.fstate.plane, frm);
-                    } else {
-                        vp_add_block_dc(
-                            &mut self.coeffs[blk_no],
-                            mb_x,
-                            mb_y,
-                            self.fstate.plane,
-                            frm,
-                        );
-                    }
-                }
-            }
+            mb.has_ac[blk_no] = self.fstate.last_idx[self.fstate.ctx_idx] > 0;
         }
+        mb.mb_type = mb_type;
+        mb.mv = self.mb_info[mb_pos].mv;
+        mb.four_mv = four_mv;
+        mb.ilace = self.ilace_mb;
+        mb.coeffs = self.coeffs;
         Ok(())
     }
-    fn do_mc(
-        &mut self,
-        br: &dyn VP56Parser,
-        frm: &mut NASimpleVideoFrame<u8>,
-        mb_type: VPMBType,
-        mv: MV,
-        alpha: bool,
-    ) {
-        let x = self.fstate.mb_x * 16;
-        let y = self.fstate.mb_y * 16;
-        let plane = if !alpha { 0 } else { 3 };
-        let src = if mb_type.get_ref_id() == VP_REF_INTER {
-            self.shuf.get_last().unwrap()
-        } else {
-            self.shuf.get_golden().unwrap()
-        };
-
-        br.mc_block(
-            frm,
-            self.mc_buf.clone(),
-            src.clone(),
-            plane,
-            x + 0,
-            y + 0,
-            mv,
-            &self.loop_tab,
-        );
-        br.mc_block(
-            frm,
-            self.mc_buf.clone(),
-            src.clone(),
-            plane,
-            x + 8,
-            y + 0,
-            mv,
-            &self.loop_tab,
-        );
-        br.mc_block(
-            frm,
-            self.mc_buf.clone(),
-            src.clone(),
-            plane,
-            x + 0,
-            y + 8,
-            mv,
-            &self.loop_tab,
-        );
-        br.mc_block(
-            frm,
-            self.mc_buf.clone(),
-            src.clone(),
-            plane,
-            x + 8,
-            y + 8,
-            mv,
-            &self.loop_tab,
-        );
-        if !alpha {
-            let x = self.fstate.mb_x * 8;
-            let y = self.fstate.mb_y * 8;
-            br.mc_block(
-                frm,
-                self.mc_buf.clone(),
-                src.clone(),
-                1,
-                x,
-                y,
-                mv,
-                &self.loop_tab,
-            );
-            br.mc_block(frm, self.mc_buf.clone(), src, 2, x, y, mv, &self.loop_tab);
-        }
-    }
-    fn do_fourmv(
-        &mut self,
-        br: &dyn VP56Parser,
-        frm: &mut NASimpleVideoFrame<u8>,
-        mvs: &[MV; 4],
-        alpha: bool,
-    ) {
-        let x = self.fstate.mb_x * 16;
-        let y = self.fstate.mb_y * 16;
-        let plane = if !alpha { 0 } else { 3 };
-        let src = self.shuf.get_last().unwrap();
-        for blk_no in 0..4 {
-            br.mc_block(
-                frm,
-                self.mc_buf.clone(),
-                src.clone(),
-                plane,
-                x + (blk_no & 1) * 8,
-                y + (blk_no & 2) * 4,
-                mvs[blk_no],
-                &self.loop_tab,
-            );
-        }
-        if !alpha {
-            let x = self.fstate.mb_x * 8;
-            let y = self.fstate.mb_y * 8;
-            let sum = mvs[0] + mvs[1] + mvs[2] + mvs[3];
-            let mv = MV {
-                x: sum.x / 4,
-                y: sum.y / 4,
-            };
-            br.mc_block(
-                frm,
-                self.mc_buf.clone(),
-                src.clone(),
-                1,
-                x,
-                y,
-                mv,
-                &self.loop_tab,
-            );
-            br.mc_block(frm, self.mc_buf.clone(), src, 2, x, y, mv, &self.loop_tab);
-        }
-    }
     fn predict_dc(&mut self, mb_type: VPMBType, _mb_pos: usize, blk_no: usize, _alpha: bool) {
         let is_luma = blk_no < 4;
         let (plane, dcs) = match blk_no {
@@ -1353,7 +1838,7 @@ const VP56_AC_QUANTS: [i16; 64] = [
     16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1,
 ];
 
-const VP56_FILTER_LIMITS: [u8; 64] = [
+pub const VP56_FILTER_LIMITS: [u8; 64] = [
     14, 14, 13, 13, 12, 12, 10, 10, 10, 10, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
     8, 8, 8, 8, 8, 8, 8, 8, 7, 7, 7, 7, 7, 7, 6, 6, 6, 6, 6, 6, 5, 5, 5, 5, 4, 4, 4, 4, 4, 4, 4, 3,
     3, 3, 3, 2,
@@ -0,0 +1,179 @@
+//! Lightweight VP6 frame header parsing.
+use super::super::vp56::*;
+use super::super::vpcommon::*;
+use super::VP6BR;
+use nihav_core::codecs::*;
+use nihav_core::io::byteio::read_u16be;
+
+/// Stream parameters coded in VP6 keyframes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VP6KeyframeParams {
+    /// Bitstream version (6-8).
+    pub version: u8,
+    /// Profile (0 - simple, 3 - advanced).
+    pub profile: u8,
+    /// Frames may contain interlaced macroblocks.
+    pub interlaced: bool,
+    /// Picture height in macroblocks.
+    pub mb_h: u8,
+    /// Picture width in macroblocks.
+    pub mb_w: u8,
+    /// Display height in macroblocks.
+    pub disp_h: u8,
+    /// Display width in macroblocks.
+    pub disp_w: u8,
+    /// Scaling mode.
+    pub scale: u8,
+}
+
+impl VP6KeyframeParams {
+    fn new(kf_hdr: &VP56Header) -> Self {
+        Self {
+            version: kf_hdr.version,
+            profile: kf_hdr.profile,
+            interlaced: kf_hdr.interlaced,
+            mb_h: kf_hdr.mb_h,
+            mb_w: kf_hdr.mb_w,
+            disp_h: kf_hdr.disp_h,
+            disp_w: kf_hdr.disp_w,
+            scale: kf_hdr.scale,
+        }
+    }
+}
+
+/// Header of a single VP6 sub-stream (colour or alpha).
+///
+/// Some fields of inter frames depend on the stream profile so they are known
+/// only after a keyframe has been parsed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VP6StreamHeader {
+    /// Frame is a keyframe.
+    pub is_intra: bool,
+    /// Frame replaces the golden frame (if known).
+    pub is_golden: Option<bool>,
+    /// Frame quantiser.
+    pub quant: u8,
+    /// Coefficients are coded with Huffman codes instead of the bool coder (if known).
+    pub use_huffman: Option<bool>,
+    /// Offset of the sub-stream in the packet.
+    pub data_offset: usize,
+    /// Offset of the coefficient partition in the packet (if it is known to be coded separately).
+    pub coef_offset: Option<usize>,
+    /// Parameters from the current or the last parsed keyframe.
+    pub kf_params: Option<VP6KeyframeParams>,
+}
+
+impl VP6StreamHeader {
+    fn new(hdr: &VP56Header, kf_hdr: &VP56Header, data_offset: usize) -> Self {
+        Self {
+            is_intra: hdr.is_intra,
+            is_golden: Some(hdr.is_golden),
+            quant: hdr.quant,
+            use_huffman: Some(hdr.use_huffman),
+            data_offset,
+            coef_offset: if hdr.multistream {
+                Some(data_offset + (hdr.offset as usize))
+            } else {
+                None
+            },
+            kf_params: Some(VP6KeyframeParams::new(kf_hdr)),
+        }
+    }
+}
+
+/// VP6 frame header.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VP6FrameHeader {
+    /// Colour sub-stream header.
+    pub main: VP6StreamHeader,
+    /// Alpha sub-stream header (for VP6 with alpha).
+    pub alpha: Option<VP6StreamHeader>,
+}
+
+/// VP6 frame header parser.
+///
+/// Inter frames do not code version, profile and dimensions so they are taken from
+/// the last parsed keyframe. Before the first keyframe only the fields that do not
+/// depend on them are reported for inter frames.
+#[derive(Default)]
+pub struct VP6HeaderParser {
+    br: [VP6BR; 2],
+    has_alpha: bool,
+    kf_hdr: [Option<VP56Header>; 2],
+}
+
+impl VP6HeaderParser {
+    /// Creates a new parser for VP6 streams with or without alpha.
+    pub fn new(has_alpha: bool) -> Self {
+        Self {
+            has_alpha,
+            ..Default::default()
+        }
+    }
+    fn parse_stream_header(
+        &mut self,
+        src: &[u8],
+        data_offset: usize,
+        idx: usize,
+    ) -> DecoderResult<VP6StreamHeader> {
+        validate!(src.len() >= 4);
+        let is_intra = (src[0] & 0x80) == 0;
+        if let (false, None) = (is_intra, self.kf_hdr[idx]) {
+            // coefficient partition offset is always present in multistream frames
+            let mut hdr = VP6StreamHeader {
+                quant: (src[0] >> 1) & 0x3F,
+                data_offset,
+                ..Default::default()
+            };
+            if (src[0] & 1) != 0 {
+                let offset = read_u16be(&src[1..])? as usize;
+                validate!(offset > 2 && offset < src.len());
+                let mut bc = BoolCoder::new(&src[3..])?;
+                hdr.is_golden = Some(bc.read_bool());
+                hdr.coef_offset = Some(data_offset + offset);
+            }
+            return Ok(hdr);
+        }
+        let mut bc = BoolCoder::new(src)?;
+        let hdr = self.br[idx].parse_header(&mut bc)?;
+        validate!((hdr.offset as usize) < src.len());
+        if hdr.is_intra {
+            self.kf_hdr[idx] = Some(hdr);
+        }
+        let kf_hdr = self.kf_hdr[idx].unwrap_or(hdr);
+        Ok(VP6StreamHeader::new(&hdr, &kf_hdr, data_offset))
+    }
+    /// Parses frame header without decoding the frame.
+    pub fn parse_frame_header(&mut self, src: &[u8]) -> DecoderResult<VP6FrameHeader> {
+        let end = if self.has_alpha {
+            validate!(src.len() >= 7);
+            let aoffset = ((src[0] as usize) << 16) | ((src[1] as usize) << 8) | (src[2] as usize);
+            validate!((aoffset > 0) && (aoffset < src.len() - 3));
+            aoffset + 3
+        } else {
+            src.len()
+        };
+        let start = if self.has_alpha { 3 } else { 0 };
+        let main = self.parse_stream_header(&src[start..end], start, 0)?;
+        let alpha = if self.has_alpha {
+            let ahdr = self.parse_stream_header(&src[end..], end, 1)?;
+            validate!(ahdr.is_intra == main.is_intra);
+            if main.is_intra {
+                let (params, aparams) = (main.kf_params.unwrap(), ahdr.kf_params.unwrap());
+                validate!(aparams.mb_w == params.mb_w && aparams.mb_h == params.mb_h);
+            }
+            Some(ahdr)
+        } else {
+            None
+        };
+        Ok(VP6FrameHeader { main, alpha })
+    }
+}
+
+/// Parses the header of a VP6 frame.
+///
+/// Inter frames are parsed without the keyframe context, use `VP6HeaderParser`
+/// for whole streams.
+pub fn parse_frame_header(src: &[u8], has_alpha: bool) -> DecoderResult<VP6FrameHeader> {
+    VP6HeaderParser::new(has_alpha).parse_frame_header(src)
+}
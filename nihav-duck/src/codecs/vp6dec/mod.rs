@@ -5,6 +5,12 @@ use super::vpcommon::*;
 use nihav_codec_support::codecs::{MV, ZIGZAG};
 use nihav_core::codecs::*;
 use nihav_core::io::bitreader::*;
+use nihav_core::scale::*;
+
+mod header;
+pub use header::*;
+mod pp;
+use pp::*;
 
 #[derive(Default)]
 struct VP6BR {
@@ -367,6 +373,7 @@ impl VP56Parser for VP6BR {
         plane: usize,
         x: usize,
         y: usize,
+        dst_y: usize,
         mv: MV,
         loop_tab: &[i8; 256],
     ) {
@@ -418,7 +425,7 @@ impl VP56Parser for VP6BR {
             }
         }
         let dstride = dst.stride[plane];
-        let dbuf = &mut dst.data[dst.offset[plane] + x + y * dstride..];
+        let dbuf = &mut dst.data[dst.offset[plane] + x + dst_y * dstride..];
         if copy_mode {
             let src = &tmp_blk[2 * 16 + 2..];
             for (dline, sline) in dbuf
@@ -463,6 +470,8 @@ fn reset_scan(model: &mut VP6Models, interlaced: bool) {
         model.scan[i] = i;
     }
     model.zigzag.copy_from_slice(&ZIGZAG);
+    // interlaced default order is not a plain zigzag
+    update_scan(model);
 }
 
 fn decode_token_bc(bc: &mut BoolCoder, probs: &[u8], prob34: u8, is_dc: bool, has_nnz: bool) -> u8 {
@@ -579,6 +588,13 @@ struct VP6Decoder {
     br: VP6BR,
     has_alpha: bool,
     flipped: bool,
+    pp: PostProcessor,
+    pp_pool: OutputPool,
+    mb_info: bool,
+    crop_pool: OutputPool,
+    upscale: bool,
+    scaler: Option<NAScale>,
+    scale_pool: OutputPool,
 }
 
 impl VP6Decoder {
@@ -589,6 +605,128 @@ impl VP6Decoder {
             br: VP6BR::new(),
             has_alpha,
             flipped,
+            pp: PostProcessor::new(),
+            pp_pool: OutputPool::new(),
+            mb_info: false,
+            crop_pool: OutputPool::new(),
+            upscale: false,
+            scaler: None,
+            scale_pool: OutputPool::new(),
+        }
+    }
+    /// Filters a copy of the decoded frame so the reference frames stay intact.
+    fn postprocess(&mut self, bufinfo: NABufferType) -> DecoderResult<NABufferType> {
+        let src = bufinfo.get_vbuf().unwrap();
+        let mut dst = self.pp_pool.get_buffer(src.get_info())?;
+        dst.get_data_mut().unwrap().copy_from_slice(src.get_data());
+        let (mb_w, mb_h) = self.dec.get_mb_dimensions();
+        self.pp.process(
+            &mut dst,
+            mb_w,
+            mb_h,
+            self.dec.get_quant(),
+            self.dec.get_mb_params(),
+        );
+        Ok(NABufferType::Video(dst))
+    }
+    /// Crops the picture to the display size and upscales it if requested.
+    fn output_picture(
+        &mut self,
+        bufinfo: NABufferType,
+        (disp_w, disp_h): (usize, usize),
+        scale: u8,
+    ) -> DecoderResult<NABufferType> {
+        let src = bufinfo.get_vbuf().unwrap();
+        let mut vinfo = src.get_info();
+        let bufinfo = if (vinfo.get_width(), vinfo.get_height()) != (disp_w, disp_h) {
+            vinfo.set_width(disp_w);
+            vinfo.set_height(disp_h);
+            let mut dst = self.crop_pool.get_buffer(vinfo)?;
+            copy_picture(&src, &mut dst);
+            NABufferType::Video(dst)
+        } else {
+            bufinfo
+        };
+        if !self.upscale || scale == 0 {
+            return Ok(bufinfo);
+        }
+        let (num, den) = VP6_SCALE_RATIOS[usize::from(scale & 3)];
+        vinfo.set_width(disp_w * num / den);
+        vinfo.set_height(disp_h * num / den);
+        let ifmt = get_scale_fmt_from_pic(&bufinfo);
+        let ofmt = ScaleInfo {
+            fmt: vinfo.get_format(),
+            width: vinfo.get_width(),
+            height: vinfo.get_height(),
+        };
+        let reinit = match self.scaler {
+            Some(ref scaler) => scaler.get_in_fmt() != ifmt || scaler.get_out_fmt() != ofmt,
+            None => true,
+        };
+        if reinit {
+            self.scaler = Some(NAScale::new(ifmt, ofmt).map_err(|_| DecoderError::NotImplemented)?);
+        }
+        let mut dst = NABufferType::Video(self.scale_pool.get_buffer(vinfo)?);
+        if let Some(ref mut scaler) = self.scaler {
+            scaler
+                .convert(&bufinfo, &mut dst)
+                .map_err(|_| DecoderError::Bug)?;
+        }
+        Ok(dst)
+    }
+}
+
+/// Upscaling ratios for the scaling modes signalled in keyframe header.
+const VP6_SCALE_RATIOS: [(usize, usize); 4] = [(1, 1), (5, 4), (5, 3), (2, 1)];
+
+/// Pool for output frames that grows when the caller keeps all of them.
+struct OutputPool {
+    pool: NAVideoBufferPool<u8>,
+    vinfo: Option<NAVideoInfo>,
+    add_bufs: usize,
+}
+
+impl OutputPool {
+    fn new() -> Self {
+        Self {
+            pool: NAVideoBufferPool::new(2),
+            vinfo: None,
+            add_bufs: 0,
+        }
+    }
+    /// Returns a free buffer, reallocating the pool only if the format has changed.
+    fn get_buffer(&mut self, vinfo: NAVideoInfo) -> DecoderResult<NAVideoBufferRef<u8>> {
+        if self.vinfo != Some(vinfo) {
+            self.pool.reset();
+            self.add_bufs = 0;
+            self.pool.set_dec_bufs(0);
+            self.vinfo = Some(vinfo);
+        } else if let Some(buf) = self.pool.get_free() {
+            return Ok(buf);
+        } else {
+            self.add_bufs += 1;
+            self.pool.set_dec_bufs(self.add_bufs);
+        }
+        self.pool.prealloc_video(vinfo, 4)?;
+        self.pool.get_free().ok_or(DecoderError::AllocError)
+    }
+}
+
+fn copy_picture(src: &NAVideoBuffer<u8>, dst: &mut NAVideoBuffer<u8>) {
+    for plane in 0..dst.get_num_components() {
+        let (w, h) = dst.get_dimensions(plane);
+        let sstride = src.get_stride(plane);
+        let dstride = dst.get_stride(plane);
+        let soff = src.get_offset(plane);
+        let doff = dst.get_offset(plane);
+        let sdata = src.get_data();
+        let ddata = dst.get_data_mut().unwrap();
+        for (dline, sline) in ddata[doff..]
+            .chunks_mut(dstride)
+            .zip(sdata[soff..].chunks(sstride))
+            .take(h)
+        {
+            dline[..w].copy_from_slice(&sline[..w]);
         }
     }
 }
@@ -623,10 +761,35 @@ impl NADecoder for VP6Decoder {
         let src = pkt.get_buffer();
 
         let (bufinfo, ftype) = self.dec.decode_frame(supp, src.as_slice(), &mut self.br)?;
+        let bufinfo = if self.pp.get_level() > 0 {
+            self.postprocess(bufinfo)?
+        } else {
+            bufinfo
+        };
+        let bufinfo = self.output_picture(
+            bufinfo,
+            self.dec.get_display_size(),
+            self.dec.get_scale_mode(),
+        )?;
+        let out_vinfo = bufinfo.get_video_info().unwrap();
+        let fmt_changed = self.info.get_properties().get_video_info() != Some(out_vinfo);
+        if fmt_changed {
+            self.info = NACodecInfo::new_ref(
+                self.info.get_name(),
+                NACodecTypeInfo::Video(out_vinfo),
+                self.info.get_extradata(),
+            )
+            .into_ref();
+        }
 
         let mut frm = NAFrame::new_from_pkt(pkt, self.info.clone(), bufinfo);
         frm.set_keyframe(ftype == FrameType::I);
         frm.set_frame_type(ftype);
+        frm.set_damaged(self.dec.is_damaged());
+        frm.set_format_changed(fmt_changed);
+        if self.mb_info {
+            frm.add_side_data(NASideData::Custom(Arc::new(self.dec.get_frame_info())));
+        }
         Ok(frm.into_ref())
     }
     fn flush(&mut self) {
@@ -634,13 +797,101 @@ impl NADecoder for VP6Decoder {
     }
 }
 
+const THREADS_OPTION: &str = "threads";
+const CONCEAL_OPTION: &str = "conceal";
+const GREY_REF_OPTION: &str = "grey_ref";
+const POSTPROC_OPTION: &str = "postproc";
+const MB_INFO_OPTION: &str = "mb_info";
+const UPSCALE_OPTION: &str = "upscale";
+
+const DECODER_OPTS: &[NAOptionDefinition] = &[
+    NAOptionDefinition {
+        name: THREADS_OPTION,
+        description: "number of threads for macroblock reconstruction",
+        opt_type: NAOptionDefinitionType::Int(Some(1), Some(64)),
+    },
+    NAOptionDefinition {
+        name: CONCEAL_OPTION,
+        description: "conceal errors in damaged frames instead of failing",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: GREY_REF_OPTION,
+        description: "start decoding from an inter frame using grey reference",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: POSTPROC_OPTION,
+        description: "postprocessing level (0 - off, 1 - deblock, 2 - strong deblock, 3 - dering)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(PP_MAX_LEVEL as i64)),
+    },
+    NAOptionDefinition {
+        name: MB_INFO_OPTION,
+        description: "attach macroblock information (VP56FrameInfo) to decoded frames",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: UPSCALE_OPTION,
+        description: "upscale pictures according to the scaling mode signalled in the stream",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+];
+
 impl NAOptionHandler for VP6Decoder {
     fn get_supported_options(&self) -> &[NAOptionDefinition] {
-        &[]
+        DECODER_OPTS
     }
-    fn set_options(&mut self, _options: &[NAOption]) {}
-    fn query_option_value(&self, _name: &str) -> Option<NAValue> {
-        None
+    fn set_options(&mut self, options: &[NAOption]) {
+        for option in options.iter() {
+            for opt_def in DECODER_OPTS.iter() {
+                if opt_def.check(option).is_ok() {
+                    match option.name {
+                        THREADS_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.dec.set_threads(intval as usize);
+                            }
+                        }
+                        CONCEAL_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.dec.set_conceal(bval);
+                            }
+                        }
+                        GREY_REF_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.dec.set_grey_ref(bval);
+                            }
+                        }
+                        POSTPROC_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.pp.set_level(intval as u8);
+                            }
+                        }
+                        MB_INFO_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.mb_info = bval;
+                            }
+                        }
+                        UPSCALE_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.upscale = bval;
+                            }
+                        }
+                        _ => {}
+                    };
+                }
+            }
+        }
+    }
+    fn query_option_value(&self, name: &str) -> Option<NAValue> {
+        match name {
+            THREADS_OPTION => Some(NAValue::Int(self.dec.get_threads() as i64)),
+            CONCEAL_OPTION => Some(NAValue::Bool(self.dec.get_conceal())),
+            GREY_REF_OPTION => Some(NAValue::Bool(self.dec.get_grey_ref())),
+            POSTPROC_OPTION => Some(NAValue::Int(i64::from(self.pp.get_level()))),
+            MB_INFO_OPTION => Some(NAValue::Bool(self.mb_info)),
+            UPSCALE_OPTION => Some(NAValue::Bool(self.upscale)),
+            _ => None,
+        }
     }
 }
 
@@ -661,8 +912,8 @@ mod test {
     use crate::duck_register_all_decoders;
     use nihav_codec_support::test::dec_video::*;
     use nihav_commonfmt::generic_register_all_demuxers;
-    use nihav_core::codecs::RegisteredDecoders;
-    use nihav_core::demuxers::RegisteredDemuxers;
+    use nihav_core::codecs::*;
+    use nihav_core::demuxers::*;
 
     #[test]
     fn test_vp6() {
@@ -681,6 +932,470 @@ mod test {
             ExpectedTestResult::MD5([0x042c3e96, 0x8a9b26a2, 0x4dcbaf66, 0x1b788d03]),
         );
     }
+    fn get_decoder_vp6_threaded() -> Box<dyn NADecoder + Send> {
+        let mut dec = super::get_decoder_vp6();
+        dec.set_options(&[NAOption {
+            name: super::THREADS_OPTION,
+            value: NAValue::Int(3),
+        }]);
+        dec
+    }
+    #[test]
+    fn test_vp6_threads() {
+        let mut dmx_reg = RegisteredDemuxers::new();
+        generic_register_all_demuxers(&mut dmx_reg);
+        let mut dec_reg = RegisteredDecoders::new();
+        dec_reg.add_decoder(DecoderInfo {
+            name: "vp6",
+            get_decoder: get_decoder_vp6_threaded,
+        });
+
+        // the output must match test_vp6
+        test_decoding(
+            "avi",
+            "vp6",
+            "assets/selection_720x576_300kBit_vp60i.avi",
+            Some(16),
+            &dmx_reg,
+            &dec_reg,
+            ExpectedTestResult::MD5([0x042c3e96, 0x8a9b26a2, 0x4dcbaf66, 0x1b788d03]),
+        );
+    }
+    fn read_packets(name: &str, count: usize) -> Vec<NAPacket> {
+        let mut dmx_reg = RegisteredDemuxers::new();
+        generic_register_all_demuxers(&mut dmx_reg);
+        let mut file = std::fs::File::open(name).unwrap();
+        let mut fr = FileReader::new_read(&mut file);
+        let mut br = ByteReader::new(&mut fr);
+        let mut dmx = create_demuxer(dmx_reg.find_demuxer("avi").unwrap(), &mut br).unwrap();
+        let mut pkts = Vec::with_capacity(count);
+        while pkts.len() < count {
+            let pkt = dmx.get_frame().unwrap();
+            if pkt.get_stream().get_media_type() == StreamType::Video {
+                pkts.push(pkt);
+            }
+        }
+        pkts
+    }
+    fn truncate_packet(pkt: &NAPacket, len: usize) -> NAPacket {
+        let mut data = pkt.get_buffer().to_vec();
+        data.truncate(len);
+        NAPacket::new(
+            pkt.get_stream(),
+            pkt.get_time_information(),
+            pkt.keyframe,
+            data,
+        )
+    }
+    #[test]
+    fn test_vp6_conceal() {
+        let pkts = read_packets("assets/selection_720x576_300kBit_vp60i.avi", 16);
+        let mut dec = super::get_decoder_vp6();
+        let mut dsupp = Box::new(NADecoderSupport::new());
+        dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+            .unwrap();
+        dec.set_options(&[
+            NAOption {
+                name: super::CONCEAL_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::THREADS_OPTION,
+                value: NAValue::Int(2),
+            },
+        ]);
+        // a lost frame leaves the probability models out of sync so the following
+        // inter frames may be damaged as well
+        for (i, pkt) in pkts.iter().enumerate() {
+            let damaged = (i % 5) == 3;
+            let frm = if damaged {
+                let len = if i < 10 {
+                    pkt.get_buffer().len() / 2
+                } else {
+                    3
+                };
+                dec.decode(&mut dsupp, &truncate_packet(pkt, len)).unwrap()
+            } else {
+                dec.decode(&mut dsupp, pkt).unwrap()
+            };
+            if damaged {
+                assert!(frm.is_damaged());
+            } else if i < 3 || pkt.keyframe {
+                assert!(!frm.is_damaged());
+            }
+        }
+
+        // corrupted data must be reported or concealed in both decoding modes
+        for &threads in [1, 3].iter() {
+            let mut dec = super::get_decoder_vp6();
+            dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+                .unwrap();
+            dec.set_options(&[
+                NAOption {
+                    name: super::CONCEAL_OPTION,
+                    value: NAValue::Bool(true),
+                },
+                NAOption {
+                    name: super::THREADS_OPTION,
+                    value: NAValue::Int(threads),
+                },
+            ]);
+            for (i, pkt) in pkts.iter().enumerate() {
+                let mut data = pkt.get_buffer().to_vec();
+                let len = data.len();
+                for k in 0..len / 64 {
+                    data[8 + (i * 37 + k * 61) % (len - 8)] ^= (k * 29 + i) as u8 | 1;
+                }
+                let pkt = NAPacket::new(
+                    pkt.get_stream(),
+                    pkt.get_time_information(),
+                    pkt.keyframe,
+                    data,
+                );
+                let _ = dec.decode(&mut dsupp, &pkt);
+            }
+        }
+
+        // without concealment the damaged frame is rejected
+        let mut dec = super::get_decoder_vp6();
+        dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+            .unwrap();
+        dec.decode(&mut dsupp, &pkts[0]).unwrap();
+        assert!(dec
+            .decode(&mut dsupp, &truncate_packet(&pkts[1], 3))
+            .is_err());
+    }
+    #[test]
+    fn test_vp6_grey_ref() {
+        let pkts = read_packets("assets/selection_720x576_300kBit_vp60i.avi", 8);
+        let mut dec = super::get_decoder_vp6();
+        let mut dsupp = Box::new(NADecoderSupport::new());
+        dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+            .unwrap();
+        assert!(dec.decode(&mut dsupp, &pkts[1]).is_err());
+        // the probability models are not known either so errors have to be concealed
+        dec.set_options(&[
+            NAOption {
+                name: super::GREY_REF_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::CONCEAL_OPTION,
+                value: NAValue::Bool(true),
+            },
+        ]);
+        assert!(dec.decode(&mut dsupp, &pkts[1]).unwrap().is_damaged());
+        for pkt in pkts[2..].iter() {
+            dec.decode(&mut dsupp, pkt).unwrap();
+        }
+    }
+    #[test]
+    fn test_vp6_scan_reset() {
+        use crate::codecs::vp56::VP6Models;
+        use nihav_codec_support::codecs::ZIGZAG;
+
+        let mut model = VP6Models {
+            scan_order: [0; 64],
+            scan: [0; 64],
+            zigzag: [0; 64],
+            zero_run_probs: [[0; 14]; 2],
+        };
+        for &interlaced in [false, true].iter() {
+            super::reset_scan(&mut model, interlaced);
+            // coefficients are read band by band
+            for pair in model.scan[1..].windows(2) {
+                assert!(model.scan_order[pair[0]] <= model.scan_order[pair[1]]);
+            }
+            for i in 0..64 {
+                assert_eq!(model.zigzag[i], ZIGZAG[model.scan[i]]);
+            }
+        }
+        assert!(model.zigzag != ZIGZAG);
+    }
+    #[test]
+    fn test_vp6_postproc() {
+        let pkts = read_packets("assets/selection_720x576_300kBit_vp60i.avi", 12);
+        let mut dsupp = Box::new(NADecoderSupport::new());
+        let mut dsupp_pp = Box::new(NADecoderSupport::new());
+        let mut dec = super::get_decoder_vp6();
+        let mut dec_pp = super::get_decoder_vp6();
+        dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+            .unwrap();
+        dec_pp
+            .init(&mut dsupp_pp, pkts[0].get_stream().get_info())
+            .unwrap();
+        dec_pp.set_options(&[NAOption {
+            name: super::POSTPROC_OPTION,
+            value: NAValue::Int(3),
+        }]);
+        let mut filtered = false;
+        let mut held = Vec::new();
+        for pkt in pkts[..8].iter() {
+            let frm = dec.decode(&mut dsupp, pkt).unwrap();
+            let frm_pp = dec_pp.decode(&mut dsupp_pp, pkt).unwrap();
+            let data = frm.get_buffer().get_vbuf().unwrap();
+            let data_pp = frm_pp.get_buffer().get_vbuf().unwrap();
+            filtered |= data.get_data() != data_pp.get_data();
+            held.push((data_pp.get_data().to_vec(), data_pp));
+        }
+        assert!(filtered);
+        // output frames kept by the caller must not be reused
+        for (copy, buf) in held.iter() {
+            assert!(copy == buf.get_data());
+        }
+        // postprocessing must not affect the reference frames
+        dec_pp.set_options(&[NAOption {
+            name: super::POSTPROC_OPTION,
+            value: NAValue::Int(0),
+        }]);
+        for pkt in pkts[8..].iter() {
+            let frm = dec.decode(&mut dsupp, pkt).unwrap();
+            let frm_pp = dec_pp.decode(&mut dsupp_pp, pkt).unwrap();
+            let data = frm.get_buffer().get_vbuf().unwrap();
+            let data_pp = frm_pp.get_buffer().get_vbuf().unwrap();
+            assert!(data.get_data() == data_pp.get_data());
+        }
+    }
+    #[test]
+    fn test_vp6_postproc_ilace() {
+        let pkts = read_packets("assets/selection_720x576_300kBit_vp60i.avi", 8);
+        let mut dsupp = Box::new(NADecoderSupport::new());
+        let mut dsupp_pp = Box::new(NADecoderSupport::new());
+        let mut dec = super::get_decoder_vp6();
+        let mut dec_pp = super::get_decoder_vp6();
+        dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+            .unwrap();
+        dec_pp
+            .init(&mut dsupp_pp, pkts[0].get_stream().get_info())
+            .unwrap();
+        dec_pp.set_options(&[
+            NAOption {
+                name: super::POSTPROC_OPTION,
+                value: NAValue::Int(1),
+            },
+            NAOption {
+                name: super::MB_INFO_OPTION,
+                value: NAValue::Bool(true),
+            },
+        ]);
+        let mut ilace_mbs = 0;
+        for pkt in pkts.iter() {
+            let frm = dec.decode(&mut dsupp, pkt).unwrap();
+            let frm_pp = dec_pp.decode(&mut dsupp_pp, pkt).unwrap();
+            let info = match frm_pp.side_data[0] {
+                NASideData::Custom(ref data) => data.downcast_ref::<crate::VP56FrameInfo>(),
+                _ => None,
+            }
+            .unwrap();
+            let buf = frm.get_buffer().get_vbuf().unwrap();
+            let buf_pp = frm_pp.get_buffer().get_vbuf().unwrap();
+            let stride = buf.get_stride(0);
+            let off = buf.get_offset(0);
+            let (data, data_pp) = (buf.get_data(), buf_pp.get_data());
+            for (mb_idx, mb) in info.mb_params.iter().enumerate() {
+                if !mb.ilace {
+                    continue;
+                }
+                ilace_mbs += 1;
+                let mb_off = off + (mb_idx % info.mb_w) * 16 + (mb_idx / info.mb_w) * 16 * stride;
+                // lines around the middle of field-coded macroblock are not an edge
+                for line in 7..9 {
+                    let pos = mb_off + line * stride;
+                    for x in (1..7).chain(9..15) {
+                        assert_eq!(data[pos + x], data_pp[pos + x]);
+                    }
+                }
+            }
+        }
+        assert!(ilace_mbs > 0);
+    }
+    #[test]
+    fn test_vp6_mb_info() {
+        let pkts = read_packets("assets/selection_720x576_300kBit_vp60i.avi", 8);
+        let mut dec = super::get_decoder_vp6();
+        let mut dsupp = Box::new(NADecoderSupport::new());
+        dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+            .unwrap();
+        assert!(dec
+            .decode(&mut dsupp, &pkts[0])
+            .unwrap()
+            .side_data
+            .is_empty());
+        dec.set_options(&[NAOption {
+            name: super::MB_INFO_OPTION,
+            value: NAValue::Bool(true),
+        }]);
+        let mut has_mvs = false;
+        for pkt in pkts[1..].iter() {
+            let frm = dec.decode(&mut dsupp, pkt).unwrap();
+            assert_eq!(frm.side_data.len(), 1);
+            let info = match frm.side_data[0] {
+                NASideData::Custom(ref data) => data.downcast_ref::<crate::VP56FrameInfo>(),
+                _ => None,
+            }
+            .unwrap();
+            assert_eq!((info.mb_w, info.mb_h), (45, 36));
+            assert_eq!(info.mb_params.len(), 45 * 36);
+            assert!(info.quant < 64);
+            for mb in info.mb_params.iter() {
+                if frm.is_keyframe() {
+                    assert!(mb.mb_type.is_intra());
+                }
+                if mb.mb_type.get_ref_id() != 0 && mb.mvs.iter().any(|mv| mv.x != 0 || mv.y != 0) {
+                    has_mvs = true;
+                }
+            }
+        }
+        assert!(has_mvs);
+    }
+    #[test]
+    fn test_vp6_header() {
+        use crate::vp6::*;
+
+        let pkts = read_packets("assets/selection_720x576_300kBit_vp60i.avi", 8);
+        // inter frames without keyframe context report only the common fields
+        let hdr = parse_frame_header(&pkts[1].get_buffer(), false)
+            .unwrap()
+            .main;
+        assert!(!hdr.is_intra && hdr.quant < 64);
+        assert_eq!(hdr.is_golden, Some(false));
+        assert!(hdr.coef_offset.is_some());
+        assert_eq!((hdr.use_huffman, hdr.kf_params), (None, None));
+
+        let mut parser = VP6HeaderParser::new(false);
+        for (i, pkt) in pkts.iter().enumerate() {
+            let hdr = parser.parse_frame_header(&pkt.get_buffer()).unwrap();
+            assert!(hdr.alpha.is_none());
+            let hdr = hdr.main;
+            let kf_hdr = parse_frame_header(&pkt.get_buffer(), false).unwrap().main;
+            assert_eq!((kf_hdr.is_intra, kf_hdr.quant), (hdr.is_intra, hdr.quant));
+            assert_eq!(kf_hdr.is_golden, hdr.is_golden);
+            assert_eq!(hdr.is_intra, i == 0 || i == 5);
+            assert_eq!(hdr.is_golden, Some(hdr.is_intra));
+            assert_eq!(hdr.use_huffman, Some(hdr.is_intra));
+            let params = hdr.kf_params.unwrap();
+            assert_eq!(
+                (params.version, params.profile, params.interlaced),
+                (6, 0, true)
+            );
+            assert_eq!((params.mb_w, params.mb_h), (45, 36));
+            assert_eq!(hdr.data_offset, 0);
+            assert!(hdr.coef_offset.is_some());
+        }
+        let hdr = parse_frame_header(&pkts[0].get_buffer(), false).unwrap();
+        assert_eq!((hdr.main.quant, hdr.main.coef_offset), (20, Some(237)));
+
+        let pkts = read_packets("assets/vp6a.avi", 4);
+        let mut parser = VP6HeaderParser::new(true);
+        for (i, pkt) in pkts.iter().enumerate() {
+            let hdr = parser.parse_frame_header(&pkt.get_buffer()).unwrap();
+            let ahdr = hdr.alpha.unwrap();
+            let hdr = hdr.main;
+            assert_eq!(hdr.is_intra, i == 0);
+            assert_eq!(ahdr.is_intra, i == 0);
+            let params = hdr.kf_params.unwrap();
+            assert_eq!((params.version, params.profile), (8, 3));
+            let aparams = ahdr.kf_params.unwrap();
+            assert_eq!((aparams.mb_w, aparams.mb_h), (48, 13));
+            assert!(hdr.use_huffman.is_some() && ahdr.is_golden.is_some());
+            assert_eq!(hdr.data_offset, 3);
+            assert!(ahdr.data_offset > hdr.data_offset);
+            assert_eq!(ahdr.coef_offset, None);
+        }
+        // advanced profile inter frames in single stream mode need the keyframe
+        let hdr = parse_frame_header(&pkts[1].get_buffer(), true).unwrap();
+        assert!(!hdr.main.is_intra && !hdr.alpha.unwrap().is_intra);
+        assert_eq!((hdr.main.is_golden, hdr.main.kf_params), (None, None));
+    }
+    #[test]
+    fn test_vp6_display_size() {
+        for (name, has_alpha) in [
+            ("assets/selection_720x576_300kBit_vp60i.avi", false),
+            ("assets/vp6a.avi", true),
+        ]
+        .iter()
+        {
+            let pkts = read_packets(name, 1);
+            let mut dec = super::VP6Decoder::new(!has_alpha, *has_alpha);
+            let mut dsupp = Box::new(NADecoderSupport::new());
+            dec.init(&mut dsupp, pkts[0].get_stream().get_info())
+                .unwrap();
+            let frm = dec.decode(&mut dsupp, &pkts[0]).unwrap();
+            let vinfo = frm.get_info().get_properties().get_video_info().unwrap();
+            assert!(dec.info.get_properties().get_video_info() == Some(vinfo));
+            let src = frm.get_buffer();
+
+            let dst = dec.output_picture(src.clone(), (64, 48), 3).unwrap();
+            let sbuf = src.get_vbuf().unwrap();
+            let dbuf = dst.get_vbuf().unwrap();
+            assert_eq!(
+                (dbuf.get_info().get_width(), dbuf.get_info().get_height()),
+                (64, 48)
+            );
+            for plane in 0..dbuf.get_num_components() {
+                let (w, h) = dbuf.get_dimensions(plane);
+                let sstride = sbuf.get_stride(plane);
+                let dstride = dbuf.get_stride(plane);
+                for y in 0..h {
+                    let soff = sbuf.get_offset(plane) + y * sstride;
+                    let doff = dbuf.get_offset(plane) + y * dstride;
+                    assert_eq!(&sbuf.get_data()[soff..][..w], &dbuf.get_data()[doff..][..w]);
+                }
+            }
+
+            dec.set_options(&[NAOption {
+                name: super::UPSCALE_OPTION,
+                value: NAValue::Bool(true),
+            }]);
+            let dst = dec.output_picture(src.clone(), (64, 48), 0).unwrap();
+            let dinfo = dst.get_video_info().unwrap();
+            assert_eq!((dinfo.get_width(), dinfo.get_height()), (64, 48));
+            for &(scale, width, height) in [(1, 80, 60), (2, 106, 80), (3, 128, 96)].iter() {
+                let dst = dec.output_picture(src.clone(), (64, 48), scale).unwrap();
+                let dinfo = dst.get_video_info().unwrap();
+                assert_eq!((dinfo.get_width(), dinfo.get_height()), (width, height));
+                assert!(dinfo.get_format() == vinfo.get_format());
+                assert_eq!(dinfo.is_flipped(), vinfo.is_flipped());
+            }
+        }
+    }
+    #[test]
+    fn test_vp6_size_change() {
+        let pkts1 = read_packets("assets/selection_720x576_300kBit_vp60i.avi", 3);
+        let pkts2 = read_packets("assets/vp6_crash.avi", 5);
+        for &threads in [1, 2].iter() {
+            let opts = [NAOption {
+                name: super::THREADS_OPTION,
+                value: NAValue::Int(threads),
+            }];
+            let mut dsupp = Box::new(NADecoderSupport::new());
+            let mut ref_dsupp = Box::new(NADecoderSupport::new());
+            let mut dec = super::get_decoder_vp6();
+            let mut ref_dec = super::get_decoder_vp6();
+            dec.init(&mut dsupp, pkts1[0].get_stream().get_info())
+                .unwrap();
+            ref_dec
+                .init(&mut ref_dsupp, pkts2[0].get_stream().get_info())
+                .unwrap();
+            dec.set_options(&opts);
+            ref_dec.set_options(&opts);
+            for pkt in pkts1.iter() {
+                assert!(!dec.decode(&mut dsupp, pkt).unwrap().is_format_changed());
+            }
+            for (i, pkt) in pkts2.iter().enumerate() {
+                let frm = dec.decode(&mut dsupp, pkt).unwrap();
+                let ref_frm = ref_dec.decode(&mut ref_dsupp, pkt).unwrap();
+                assert_eq!(frm.is_format_changed(), i == 0);
+                assert!(!ref_frm.is_format_changed());
+                let vinfo = frm.get_info().get_properties().get_video_info().unwrap();
+                let buf = frm.get_buffer().get_vbuf().unwrap();
+                assert!(vinfo == buf.get_info());
+                let ref_buf = ref_frm.get_buffer().get_vbuf().unwrap();
+                assert!(buf.get_info() == ref_buf.get_info());
+                assert!(buf.get_data() == ref_buf.get_data());
+            }
+        }
+    }
     #[test]
     fn test_vp6_huff() {
         let mut dmx_reg = RegisteredDemuxers::new();
@@ -0,0 +1,228 @@
+//! Postprocessing (deblocking and deringing) for decoded frames.
+use super::super::vp56::{MBParams, VP56_FILTER_LIMITS};
+use super::super::vpcommon::*;
+use nihav_codec_support::codecs::MV;
+use nihav_core::frame::*;
+
+/// Maximum postprocessing level.
+pub const PP_MAX_LEVEL: u8 = 3;
+
+#[derive(Clone, Copy)]
+struct BlockInfo {
+    ref_id: u8,
+    mvs: [MV; 4],
+    coded: bool,
+    has_ac: bool,
+}
+
+impl BlockInfo {
+    fn new(params: &[MBParams], mb_w: usize, plane: usize, bx: usize, by: usize) -> Self {
+        if plane == 0 {
+            let mb = &params[bx / 2 + (by / 2) * mb_w];
+            let idx = (bx & 1) + (by & 1) * 2;
+            Self {
+                ref_id: mb.mb_type.get_ref_id(),
+                mvs: [mb.mvs[idx]; 4],
+                coded: mb.coded[idx],
+                has_ac: mb.has_ac[idx],
+            }
+        } else {
+            let mb = &params[bx + by * mb_w];
+            Self {
+                ref_id: mb.mb_type.get_ref_id(),
+                mvs: mb.mvs,
+                coded: mb.coded[3 + plane],
+                has_ac: mb.has_ac[3 + plane],
+            }
+        }
+    }
+    /// Reports whether the edge between two blocks may have a blocking artefact.
+    ///
+    /// Two blocks without residue predicted from the same place in the same
+    /// reference are continuous and do not need filtering.
+    fn needs_filter(&self, other: &Self) -> bool {
+        self.coded || other.coded || self.ref_id != other.ref_id || self.mvs != other.mvs
+    }
+}
+
+/// Filters the edge if both sides are smooth, returns false otherwise.
+fn filter_strong(data: &mut [u8], off: usize, step: usize, limit: i16) -> bool {
+    let p3 = i16::from(data[off - step * 4]);
+    let p2 = i16::from(data[off - step * 3]);
+    let p1 = i16::from(data[off - step * 2]);
+    let p0 = i16::from(data[off - step]);
+    let q0 = i16::from(data[off]);
+    let q1 = i16::from(data[off + step]);
+    let q2 = i16::from(data[off + step * 2]);
+    let q3 = i16::from(data[off + step * 3]);
+
+    let flat = limit / 4 + 1;
+    if (p0 - q0).abs() >= limit * 2
+        || (p1 - p0).abs() >= flat
+        || (p2 - p0).abs() >= flat
+        || (q1 - q0).abs() >= flat
+        || (q2 - q0).abs() >= flat
+    {
+        return false;
+    }
+    data[off - step * 3] = ((p3 * 2 + p2 * 3 + p1 + p0 + q0 + 4) >> 3) as u8;
+    data[off - step * 2] = ((p2 + p1 + p0 + q0 + 2) >> 2) as u8;
+    data[off - step] = ((p2 + p1 * 2 + p0 * 2 + q0 * 2 + q1 + 4) >> 3) as u8;
+    data[off] = ((p1 + p0 * 2 + q0 * 2 + q1 * 2 + q2 + 4) >> 3) as u8;
+    data[off + step] = ((p0 + q0 + q1 + q2 + 2) >> 2) as u8;
+    data[off + step * 2] = ((p0 + q0 + q1 + q2 * 3 + q3 * 2 + 4) >> 3) as u8;
+    true
+}
+
+/// Smooths flat areas inside a block while preserving the edges.
+fn dering_block(
+    data: &mut [u8],
+    off: usize,
+    stride: usize,
+    (w, h): (usize, usize),
+    (x0, y0): (usize, usize),
+    limit: i16,
+) {
+    let mut blk = [0u8; 10 * 10];
+    for (y, line) in blk.chunks_exact_mut(10).enumerate() {
+        let sy = (y0 + y).max(1).min(h) - 1;
+        for (x, el) in line.iter_mut().enumerate() {
+            let sx = (x0 + x).max(1).min(w) - 1;
+            *el = data[off + sx + sy * stride];
+        }
+    }
+    let mut min = 255;
+    let mut max = 0;
+    for line in blk.chunks_exact(10).skip(1).take(8) {
+        for &el in line[1..9].iter() {
+            min = min.min(el);
+            max = max.max(el);
+        }
+    }
+    if i16::from(max - min) < (limit * 2).max(16) {
+        return;
+    }
+    let thr = ((u16::from(min) + u16::from(max) + 1) >> 1) as u8;
+    let max_diff = limit / 2 + 1;
+    for y in 0..8 {
+        for x in 0..8 {
+            let pos = x + 1 + (y + 1) * 10;
+            let mut above = 0;
+            let mut sum = 0;
+            for (dy, weights) in [[1, 2, 1], [2, 4, 2], [1, 2, 1]].iter().enumerate() {
+                for (dx, &weight) in weights.iter().enumerate() {
+                    let pix = blk[pos + dx + dy * 10 - 11];
+                    if pix >= thr {
+                        above += 1;
+                    }
+                    sum += i16::from(pix) * weight;
+                }
+            }
+            if above != 0 && above != 9 {
+                continue;
+            }
+            let cur = i16::from(blk[pos]);
+            let val = ((sum + 8) >> 4).max(cur - max_diff).min(cur + max_diff);
+            data[off + x0 + x + (y0 + y) * stride] = val as u8;
+        }
+    }
+}
+
+/// Postprocessor for decoded frames.
+pub struct PostProcessor {
+    level: u8,
+    loop_thr: i16,
+    loop_tab: [i8; 256],
+}
+
+impl PostProcessor {
+    pub fn new() -> Self {
+        Self {
+            level: 0,
+            loop_thr: 0,
+            loop_tab: [0; 256],
+        }
+    }
+    /// Sets postprocessing level.
+    ///
+    /// Level 1 applies a weak deblocking filter, level 2 uses stronger filter
+    /// on smooth areas and level 3 adds deringing.
+    pub fn set_level(&mut self, level: u8) {
+        self.level = level.min(PP_MAX_LEVEL);
+    }
+    pub fn get_level(&self) -> u8 {
+        self.level
+    }
+    /// Filters the frame using the macroblock parameters it was decoded with.
+    pub fn process(
+        &mut self,
+        buf: &mut NAVideoBuffer<u8>,
+        mb_w: usize,
+        mb_h: usize,
+        quant: u8,
+        params: &[MBParams],
+    ) {
+        if self.level == 0 {
+            return;
+        }
+        let limit = i16::from(VP56_FILTER_LIMITS[usize::from(quant)]);
+        if self.loop_thr != limit {
+            self.loop_thr = limit;
+            vp31_build_lf_tab(&mut self.loop_tab, limit);
+        }
+        for plane in 0..3 {
+            let blk_w = if plane == 0 { mb_w * 2 } else { mb_w };
+            let blk_h = if plane == 0 { mb_h * 2 } else { mb_h };
+            let off = buf.get_offset(plane);
+            let stride = buf.get_stride(plane);
+            let data = buf.get_data_mut().unwrap();
+
+            for by in 0..blk_h {
+                for bx in 1..blk_w {
+                    let left = BlockInfo::new(params, mb_w, plane, bx - 1, by);
+                    let cur = BlockInfo::new(params, mb_w, plane, bx, by);
+                    if left.needs_filter(&cur) {
+                        self.filter_edge(data, off + bx * 8 + by * 8 * stride, 1, stride, limit);
+                    }
+                }
+            }
+            for by in 1..blk_h {
+                for bx in 0..blk_w {
+                    // blocks of field-coded macroblock are interleaved so there is no edge
+                    if plane == 0 && (by & 1) == 1 && params[bx / 2 + (by / 2) * mb_w].ilace {
+                        continue;
+                    }
+                    let top = BlockInfo::new(params, mb_w, plane, bx, by - 1);
+                    let cur = BlockInfo::new(params, mb_w, plane, bx, by);
+                    if top.needs_filter(&cur) {
+                        self.filter_edge(data, off + bx * 8 + by * 8 * stride, stride, 1, limit);
+                    }
+                }
+            }
+            if self.level >= 3 {
+                for by in 0..blk_h {
+                    for bx in 0..blk_w {
+                        if BlockInfo::new(params, mb_w, plane, bx, by).has_ac {
+                            dering_block(
+                                data,
+                                off,
+                                stride,
+                                (blk_w * 8, blk_h * 8),
+                                (bx * 8, by * 8),
+                                limit,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+    fn filter_edge(&self, data: &mut [u8], mut off: usize, step: usize, stride: usize, limit: i16) {
+        for _ in 0..8 {
+            if self.level < 2 || !filter_strong(data, off, step, limit) {
+                vp31_loop_filter(data, off, step, stride, 1, &self.loop_tab);
+            }
+            off += stride;
+        }
+    }
+}
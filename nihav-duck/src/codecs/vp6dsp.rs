@@ -46,6 +46,16 @@ pub fn get_block(
 }
 
 pub fn calc_variance(src: &[u8], stride: usize) -> u16 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::vpsimd::cpu_features().sse2 {
+            return unsafe { super::vpsimd::calc_variance_sse2(src, stride) };
+        }
+    }
+    calc_variance_scalar(src, stride)
+}
+
+pub fn calc_variance_scalar(src: &[u8], stride: usize) -> u16 {
     let mut sum = 0;
     let mut ssum = 0;
     for line in src.chunks(stride * 2).take(4) {
@@ -132,11 +142,44 @@ pub fn mc_bilinear<const SOFF: usize, const SSTRIDE: usize>(
 }
 
 pub fn mc_bilinear16(dst: &mut [u8], dstride: usize, src: &[u8], mx: u16, my: u16) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let features = super::vpsimd::cpu_features();
+        if features.ssse3 {
+            unsafe { super::vpsimd::mc_bilinear16_ssse3(dst, dstride, src, mx, my) };
+            return;
+        }
+        if features.sse2 {
+            unsafe { super::vpsimd::mc_bilinear16_sse2(dst, dstride, src, mx, my) };
+            return;
+        }
+    }
     mc_bilinear::<{ 16 * 2 + 2 }, 16>(dst, dstride, src, mx, my)
 }
 
-#[allow(clippy::trivially_copy_pass_by_ref)]
 pub fn mc_bicubic(
+    dst: &mut [u8],
+    dstride: usize,
+    src: &[u8],
+    soff: usize,
+    sstride: usize,
+    coeffs_w: &[i16; 4],
+    coeffs_h: &[i16; 4],
+) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::vpsimd::cpu_features().sse2 {
+            unsafe {
+                super::vpsimd::mc_bicubic_sse2(dst, dstride, src, soff, sstride, coeffs_w, coeffs_h)
+            };
+            return;
+        }
+    }
+    mc_bicubic_scalar(dst, dstride, src, soff, sstride, coeffs_w, coeffs_h);
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+pub fn mc_bicubic_scalar(
     dst: &mut [u8],
     dstride: usize,
     src: &[u8],
@@ -345,6 +345,75 @@ impl<'a, 'b> BoolEncoder<'a, 'b> {
     }
 }
 
+fn tree_nits<T: PartialEq>(el: T, tree: &[TokenSeq<T>], probs: &[u8]) -> u32 {
+    let mut nits = 0;
+    for entry in tree.iter() {
+        if entry.val == el {
+            for seq in entry.seq.iter() {
+                let prob = probs[seq.idx as usize];
+                nits += u32::from(if seq.bit {
+                    PROB_BITS[256 - usize::from(prob)]
+                } else {
+                    PROB_BITS[usize::from(prob)]
+                });
+            }
+            break;
+        }
+    }
+    nits
+}
+
+/// Returns coefficient token cost (without extra bits and sign) in nits.
+///
+/// Tokens 0-4 are the coefficient values, tokens 5-10 are large value categories.
+pub fn coef_token_nits(token: usize, has_nnz: bool, probs: &[u8; 11]) -> u32 {
+    let cat = if token <= 4 {
+        token as i8
+    } else {
+        4 - (token as i8)
+    };
+    let tree = if has_nnz { COEF_TREE } else { NZ_COEF_TREE };
+    tree_nits(cat, tree, probs)
+}
+
+pub fn eob_nits(probs: &[u8; 11]) -> u32 {
+    tree_nits(EOB, COEF_TREE, probs)
+}
+
+pub fn large_coef_nits(aval: i16, cat: usize) -> u32 {
+    let add = aval - VP56_COEF_BASE[cat];
+    let mut nits = 0;
+    for (i, &prob) in VP56_COEF_ADD_PROBS[cat]
+        .iter()
+        .take(usize::from(VP6_COEF_ADD_BITS[cat]))
+        .enumerate()
+    {
+        let bit = (add >> (VP6_COEF_ADD_BITS[cat] as usize - 1 - i)) & 1;
+        nits += u32::from(if bit != 0 {
+            PROB_BITS[256 - usize::from(prob)]
+        } else {
+            PROB_BITS[usize::from(prob)]
+        });
+    }
+    nits
+}
+
+pub fn zero_run_nits(run: usize, probs: &[u8; 14]) -> u32 {
+    let mut nits = tree_nits(run.min(9) as u8, ZERO_RUN_TREE, probs);
+    if run >= 9 {
+        let add = run - 9;
+        for i in 0..6 {
+            let prob = usize::from(probs[i + 8]);
+            nits += u32::from(if ((add >> i) & 1) != 0 {
+                PROB_BITS[256 - prob]
+            } else {
+                PROB_BITS[prob]
+            });
+        }
+    }
+    nits
+}
+
 fn rescale_mb_mode_prob(prob: u32, total: u32) -> u8 {
     (255 * prob / (1 + total)) as u8
 }
@@ -631,16 +700,22 @@ pub fn encode_coeff_models(
         }
     }
 
-    bc.put_bool(false, 128)?;
-    reset_scan(&mut models.vp6models, interlaced);
-    /* for scan
+    // some decoders keep the previous scan if no update is transmitted and others
+    // reset it to the default one, so updates are sent while custom scan is in use
+    let def_order = default_scan_order(interlaced);
+    let custom_scan =
+        models.vp6models.scan_order != *def_order || pmodels.vp6models.scan_order != *def_order;
+    bc.put_bool(custom_scan, 128)?;
+    if custom_scan {
         for i in 1..64 {
-            if bc.read_prob(HAS_SCAN_UPD_PROBS[i]) {
-                models.vp6models.scan_order[i]  = bc.read_bits(4) as usize;
+            let band = models.vp6models.scan_order[i];
+            let changed = band != pmodels.vp6models.scan_order[i];
+            bc.put_bool(changed, HAS_SCAN_UPD_PROBS[i])?;
+            if changed {
+                bc.put_bits(band as u32, 4)?;
             }
         }
-        update_scan(&mut models.vp6models);
-    */
+    }
 
     for comp in 0..2 {
         for i in 0..14 {
@@ -772,6 +847,26 @@ pub fn encode_mb_type(
     Ok(())
 }
 
+pub fn calc_ilace_prob(ilace: &[bool]) -> u8 {
+    let num_frame = ilace.iter().filter(|&&flag| !flag).count();
+    (num_frame * 256 / ilace.len().max(1)).clamp(1, 255) as u8
+}
+
+pub fn encode_ilace_flag(
+    bc: &mut BoolEncoder,
+    ilace: bool,
+    last_ilace: Option<bool>,
+    iprob: u8,
+) -> EncoderResult<()> {
+    // context is the flag of the previous macroblock in the same row
+    let prob = match last_ilace {
+        None => iprob,
+        Some(false) => iprob + (((256 - u16::from(iprob)) >> 1) as u8),
+        Some(true) => iprob - (iprob >> 1),
+    };
+    bc.put_bool(ilace, prob)
+}
+
 fn encode_mv_component(bc: &mut BoolEncoder, mv: i16, model: &VP56MVModel) -> EncoderResult<()> {
     let aval = mv.abs();
     bc.put_bool(aval >= 8, model.nz_prob)?;
@@ -859,6 +954,8 @@ pub fn estimate_block(
 ) {
     let bc = Estimator::new();
 
+    model.huff_runs.add_block(blk);
+
     let mut last = 64;
     for i in (0..64).rev() {
         if blk[scan[i]] != 0 {
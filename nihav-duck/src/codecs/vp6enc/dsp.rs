@@ -46,6 +46,37 @@ impl std::fmt::Display for MVSearchMode {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SubpelMode {
+    None,
+    Half,
+    #[default]
+    Quarter,
+}
+
+impl FromStr for SubpelMode {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SubpelMode::None),
+            "half" => Ok(SubpelMode::Half),
+            "quarter" => Ok(SubpelMode::Quarter),
+            _ => Err(ParseError {}),
+        }
+    }
+}
+
+impl std::fmt::Display for SubpelMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            SubpelMode::None => write!(f, "none"),
+            SubpelMode::Half => write!(f, "half"),
+            SubpelMode::Quarter => write!(f, "quarter"),
+        }
+    }
+}
+
 const C1S7: i32 = 64277;
 const C2S6: i32 = 60547;
 const C3S5: i32 = 54491;
@@ -94,8 +125,19 @@ macro_rules! fdct_step {
     };
 }
 
-#[allow(clippy::erasing_op)]
 pub fn vp_fdct(blk: &mut [i16; 64]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::super::vpsimd::cpu_features().avx2 {
+            unsafe { super::super::vpsimd::vp_fdct_avx2(blk) };
+            return;
+        }
+    }
+    vp_fdct_scalar(blk);
+}
+
+#[allow(clippy::erasing_op)]
+pub fn vp_fdct_scalar(blk: &mut [i16; 64]) {
     for i in 0..8 {
         let row = &mut blk[(i * 8)..(i * 8) + 8];
         fdct_step!(
@@ -201,7 +243,7 @@ impl MVSearch for FullMVSearch {
                 }
             }
         }
-        (best_mv, best_dist)
+        mv_est.refine_mb(cur_blk, mb_x, mb_y, best_mv, best_dist)
     }
     fn search_blk(
         &mut self,
@@ -237,7 +279,7 @@ impl MVSearch for FullMVSearch {
                 }
             }
         }
-        (best_mv, best_dist)
+        mv_est.refine_blk(cur_blk, xpos, ypos, best_mv, best_dist)
     }
 }
 
@@ -270,8 +312,20 @@ const REFINEMENT: [MV; 4] = [
     MV { x: 0, y: -1 },
 ];
 
+const SUBPEL_REFINEMENT: [MV; 8] = [
+    MV { x: -1, y: -1 },
+    MV { x: 0, y: -1 },
+    MV { x: 1, y: -1 },
+    MV { x: -1, y: 0 },
+    MV { x: 1, y: 0 },
+    MV { x: -1, y: 1 },
+    MV { x: 0, y: 1 },
+    MV { x: 1, y: 1 },
+];
+
 macro_rules! search_template {
-    ($self: expr, $mv_est: expr, $cur_blk: expr, $mb_x: expr, $mb_y: expr, $sad_func: ident) => {{
+    ($self: expr, $mv_est: expr, $cur_blk: expr, $mb_x: expr, $mb_y: expr,
+     $sad_func: ident, $refine_func: ident) => {{
         let mut best_dist = MAX_DIST;
         let mut best_mv;
 
@@ -335,8 +389,15 @@ macro_rules! search_template {
         if best_dist <= DIST_THRESH {
             return (best_mv, best_dist);
         }
+        match $mv_est.subpel {
+            SubpelMode::None => return (best_mv, best_dist),
+            SubpelMode::Half => {
+                return $mv_est.$refine_func($cur_blk, $mb_x, $mb_y, best_mv, best_dist);
+            }
+            SubpelMode::Quarter => {}
+        };
 
-        // subpel refinement
+        // quarter-pel refinement with the same search pattern
         $self.set_new_point(best_mv, best_dist);
         loop {
             let mut cur_best_dist = best_dist;
@@ -388,6 +449,42 @@ macro_rules! search_template {
     }};
 }
 
+macro_rules! subpel_refine {
+    ($mv_est: expr, $cur_blk: expr, $xpos: expr, $ypos: expr, $sad_func: ident,
+     $mv: expr, $dist: expr) => {{
+        let mut best_mv = $mv;
+        let mut best_dist = $dist;
+
+        let steps: &[i16] = match $mv_est.subpel {
+            SubpelMode::None => &[],
+            SubpelMode::Half => &[2],
+            SubpelMode::Quarter => &[2, 1],
+        };
+        let mv_limit = $mv_est.mv_range * 4;
+        for &step in steps.iter() {
+            if best_dist <= DIST_THRESH {
+                break;
+            }
+            let center = best_mv;
+            for &dir in SUBPEL_REFINEMENT.iter() {
+                let mv = MV {
+                    x: center.x + dir.x * step,
+                    y: center.y + dir.y * step,
+                };
+                if mv.x.abs() > mv_limit || mv.y.abs() > mv_limit {
+                    continue;
+                }
+                let dist = $mv_est.$sad_func($cur_blk, $xpos, $ypos, mv, best_dist);
+                if dist < best_dist {
+                    best_dist = dist;
+                    best_mv = mv;
+                }
+            }
+        }
+        (best_mv, best_dist)
+    }};
+}
+
 macro_rules! pattern_search {
     ($struct_name: ident, $patterns: expr) => {
         pub struct $struct_name {
@@ -444,7 +541,7 @@ macro_rules! pattern_search {
                 mb_x: usize,
                 mb_y: usize,
             ) -> (MV, u32) {
-                search_template!(self, mv_est, cur_blk, mb_x, mb_y, sad_mb)
+                search_template!(self, mv_est, cur_blk, mb_x, mb_y, sad_mb, refine_mb)
             }
             fn search_blk(
                 &mut self,
@@ -453,7 +550,7 @@ macro_rules! pattern_search {
                 xpos: usize,
                 ypos: usize,
             ) -> (MV, u32) {
-                search_template!(self, mv_est, cur_blk, xpos, ypos, sad_blk)
+                search_template!(self, mv_est, cur_blk, xpos, ypos, sad_blk, refine_blk)
             }
         }
     };
@@ -462,18 +559,23 @@ macro_rules! pattern_search {
 pattern_search!(DiaSearch, DIA_PATTERN);
 pattern_search!(HexSearch, HEX_PATTERN);
 
+#[derive(Clone, Copy, Default)]
+pub struct MCParams {
+    pub adv_profile: bool,
+    pub autosel_pm: bool,
+    pub mv_thresh: u8,
+    pub var_thresh: u16,
+    pub filter_alpha: usize,
+}
+
 pub struct MVEstimator {
     pub ref_blk: [[u8; 64]; 6],
     mc_buf: NAVideoBufferRef<u8>,
     ref_frame: NAVideoBufferRef<u8>,
-    adv_profile: bool,
-    bicubic: bool,
-    autosel_pm: bool,
-    mv_thresh: u8,
-    var_thresh: u16,
-    filter_alpha: usize,
+    mcp: MCParams,
     loop_tab: [i8; 256],
     mv_range: i16,
+    subpel: SubpelMode,
     pub count: usize,
     pub count2: usize,
 }
@@ -484,6 +586,8 @@ impl MVEstimator {
         mc_buf: NAVideoBufferRef<u8>,
         loop_thr: i16,
         mv_range: i16,
+        subpel: SubpelMode,
+        mcp: MCParams,
     ) -> Self {
         let mut loop_tab = [0; 256];
         vp31_build_lf_tab(&mut loop_tab, loop_thr);
@@ -491,14 +595,10 @@ impl MVEstimator {
             ref_blk: [[0; 64]; 6],
             ref_frame,
             mc_buf,
-            adv_profile: false,
-            bicubic: false,
-            autosel_pm: false,
-            mv_thresh: 0,
-            var_thresh: 0,
-            filter_alpha: 0,
+            mcp,
             loop_tab,
             mv_range,
+            subpel,
             count: 0,
             count2: 0,
         }
@@ -530,12 +630,12 @@ impl MVEstimator {
             vp31_loop_filter_step16_stride1(tmp_blk, off, 12, &self.loop_tab);
         }
         let copy_mode = (mx == 0) && (my == 0);
-        let mut bicubic = !copy_mode && is_luma && self.bicubic;
-        if is_luma && !copy_mode && self.adv_profile {
-            if !self.autosel_pm {
+        let mut bicubic = false;
+        if is_luma && !copy_mode && self.mcp.adv_profile {
+            if !self.mcp.autosel_pm {
                 bicubic = true;
             } else {
-                let mv_limit = 1 << (self.mv_thresh + 1);
+                let mv_limit = 1 << (self.mcp.mv_thresh + 1);
                 if (mv.x.abs() <= mv_limit) && (mv.y.abs() <= mv_limit) {
                     let mut var_off = 16 * 2 + 2;
                     if mv.x < 0 {
@@ -545,7 +645,7 @@ impl MVEstimator {
                         var_off += 16;
                     }
                     let var = calc_variance(&tmp_blk[var_off..], 16);
-                    if var >= self.var_thresh {
+                    if var >= self.mcp.var_thresh {
                         bicubic = true;
                     }
                 }
@@ -558,8 +658,8 @@ impl MVEstimator {
                 dline.copy_from_slice(&sline[..8]);
             }
         } else if bicubic {
-            let coeff_h = &VP6_BICUBIC_COEFFS[self.filter_alpha][mx as usize];
-            let coeff_v = &VP6_BICUBIC_COEFFS[self.filter_alpha][my as usize];
+            let coeff_h = &VP6_BICUBIC_COEFFS[self.mcp.filter_alpha][mx as usize];
+            let coeff_v = &VP6_BICUBIC_COEFFS[self.mcp.filter_alpha][my as usize];
             mc_bicubic(dst, 8, tmp_blk, 16 * 2 + 2, 16, coeff_h, coeff_v);
         } else {
             mc_bilinear16(dst, 8, tmp_blk, mx as u16, my as u16);
@@ -605,9 +705,39 @@ impl MVEstimator {
         self.mc_block(0, 0, xpos, ypos, cur_mv);
         sad(cur_blk, &self.ref_blk[0])
     }
+    fn refine_mb(
+        &mut self,
+        cur_blk: &[[u8; 64]; 6],
+        mb_x: usize,
+        mb_y: usize,
+        mv: MV,
+        dist: u32,
+    ) -> (MV, u32) {
+        subpel_refine!(self, cur_blk, mb_x, mb_y, sad_mb, mv, dist)
+    }
+    fn refine_blk(
+        &mut self,
+        cur_blk: &[u8; 64],
+        xpos: usize,
+        ypos: usize,
+        mv: MV,
+        dist: u32,
+    ) -> (MV, u32) {
+        subpel_refine!(self, cur_blk, xpos, ypos, sad_blk, mv, dist)
+    }
 }
 
 fn sad(src1: &[u8; 64], src2: &[u8; 64]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::super::vpsimd::cpu_features().sse2 {
+            return unsafe { super::super::vpsimd::sad_sse2(src1, src2) };
+        }
+    }
+    sad_scalar(src1, src2)
+}
+
+fn sad_scalar(src1: &[u8; 64], src2: &[u8; 64]) -> u32 {
     let mut sum = 0;
     for (&p1, &p2) in src1.iter().zip(src2.iter()) {
         sum += (i32::from(p1) - i32::from(p2)).abs() as u32;
@@ -630,3 +760,34 @@ pub fn calc_mb_dist(mb1: &ResidueMB, mb2: &ResidueMB) -> u32 {
     }
     sum
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod test {
+    use super::super::super::vpsimd::test::Rng;
+    use super::super::super::vpsimd::*;
+    use super::*;
+
+    #[test]
+    fn test_simd_fdct_sad() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let mut rng = Rng(0x8765_4321);
+        for _ in 0..4096 {
+            let mut blk = [0i16; 64];
+            for el in blk.iter_mut() {
+                *el = rng.range(-255, 255);
+            }
+            let mut ref_blk = blk;
+            vp_fdct_scalar(&mut ref_blk);
+            unsafe { vp_fdct_avx2(&mut blk) };
+            assert_eq!(blk, ref_blk);
+
+            let mut src1 = [0u8; 64];
+            let mut src2 = [0u8; 64];
+            rng.fill_pixels(&mut src1);
+            rng.fill_pixels(&mut src2);
+            assert_eq!(unsafe { sad_sse2(&src1, &src2) }, sad_scalar(&src1, &src2));
+        }
+    }
+}
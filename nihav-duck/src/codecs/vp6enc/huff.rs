@@ -20,6 +20,16 @@ impl HuffState {
 
 pub const MAX_EOB_RUN: usize = 63 + 10;
 
+/// Returns the number of bits needed to code DC or EOB run length.
+pub fn eob_run_bits(val: usize) -> u32 {
+    match val {
+        0 | 1 => 2,
+        2..=5 => 4,
+        6..=9 => 5,
+        _ => 9,
+    }
+}
+
 pub struct HuffEncoder<'a, 'b> {
     bw: &'a mut ByteWriter<'b>,
     bitbuf: u32,
@@ -1,6 +1,7 @@
 use super::super::vpcommon::*;
 use super::dsp::*;
 use super::rdo::*;
+use super::trellis::*;
 use super::VP56DCPred;
 use nihav_codec_support::codecs::{MV, ZERO_MV};
 use nihav_core::frame::*;
@@ -140,6 +141,7 @@ pub struct FrameEncoder {
     pub golden_mbs: Vec<InterMB>,
 
     pub mb_types: Vec<VPMBType>,
+    pub ilace: Vec<bool>,
     pub num_mv: Vec<u8>,
     pub coded_mv: Vec<[MV; 4]>,
     pub fmv_sub: Vec<[VPMBType; 4]>,
@@ -149,6 +151,10 @@ pub struct FrameEncoder {
 
     pub me_mode: MVSearchMode,
     pub me_range: i16,
+    pub me_subpel: SubpelMode,
+    pub mc_params: MCParams,
+    pub threads: usize,
+    mc_bufs: Vec<NAVideoBufferRef<u8>>,
 }
 
 macro_rules! read_block {
@@ -169,6 +175,299 @@ macro_rules! write_block {
     };
 }
 
+fn read_frame_mbs(vbuf: &NAVideoBuffer<u8>, mb_w: usize, mb_h: usize, mbs: &mut Vec<ResidueMB>) {
+    let src = vbuf.get_data();
+    let y = &src[vbuf.get_offset(0)..];
+    let ystride = vbuf.get_stride(0);
+    let u = &src[vbuf.get_offset(1)..];
+    let ustride = vbuf.get_stride(1);
+    let v = &src[vbuf.get_offset(2)..];
+    let vstride = vbuf.get_stride(2);
+
+    mbs.clear();
+    for (ys, (us, vs)) in y
+        .chunks(ystride * 16)
+        .zip(u.chunks(ustride * 8).zip(v.chunks(vstride * 8)))
+        .take(mb_h)
+    {
+        for x in (0..mb_w * 16).step_by(16) {
+            let mut mb = ResidueMB::new();
+            for (i, blk) in mb.coeffs[..4].iter_mut().enumerate() {
+                read_block!(blk, ys[x + (i & 1) * 8 + (i >> 1) * 8 * ystride..], ystride);
+            }
+            read_block!(mb.coeffs[4], us[x / 2..], ustride);
+            read_block!(mb.coeffs[5], vs[x / 2..], vstride);
+            mbs.push(mb);
+        }
+    }
+}
+
+/// Rearranges luma blocks so that the top blocks hold even lines and the bottom ones odd lines.
+fn frame_to_field(blocks: &mut Coeffs) {
+    let mut fblocks = [[0; 64]; 4];
+    for (i, fblk) in fblocks.iter_mut().enumerate() {
+        for (y, drow) in fblk.chunks_exact_mut(8).enumerate() {
+            let src_y = y * 2 + (i >> 1);
+            let sblk = &blocks[(i & 1) + (src_y >> 3) * 2];
+            drow.copy_from_slice(&sblk[(src_y & 7) * 8..][..8]);
+        }
+    }
+    blocks[..4].copy_from_slice(&fblocks);
+}
+
+/// Compares vertical activity between adjacent frame lines and adjacent field lines.
+fn prefer_field_dct(blocks: &Coeffs) -> bool {
+    let pix = |x: usize, y: usize| blocks[(x >> 3) + (y >> 3) * 2][(x & 7) + (y & 7) * 8];
+    let mut frame_act = 0;
+    let mut field_act = 0;
+    for x in 0..16 {
+        for y in 0..14 {
+            frame_act += u32::from((pix(x, y) - pix(x, y + 1)).unsigned_abs());
+            field_act += u32::from((pix(x, y) - pix(x, y + 2)).unsigned_abs());
+        }
+    }
+    field_act < frame_act
+}
+
+/// Frame data shared by motion search workers.
+struct MotionSearchCtx<'a> {
+    src_mbs: &'a [ResidueMB],
+    ilace: &'a [bool],
+    ref_frame: NAVideoBufferRef<u8>,
+    mb_w: usize,
+    loop_thr: i16,
+    me_mode: MVSearchMode,
+    me_range: i16,
+    me_subpel: SubpelMode,
+    mc_params: MCParams,
+}
+
+impl MotionSearchCtx<'_> {
+    fn new_estimator(&self, mc_buf: NAVideoBufferRef<u8>) -> MVEstimator {
+        MVEstimator::new(
+            self.ref_frame.clone(),
+            mc_buf,
+            self.loop_thr,
+            self.me_range,
+            self.me_subpel,
+            self.mc_params,
+        )
+    }
+    fn new_search(&self) -> Box<dyn MVSearch> {
+        match self.me_mode {
+            MVSearchMode::Full => Box::new(FullMVSearch::new()),
+            MVSearchMode::Diamond => Box::new(DiaSearch::new()),
+            MVSearchMode::Hexagon => Box::new(HexSearch::new()),
+        }
+    }
+    /// Estimates motion for a band of macroblocks starting at `start`.
+    fn estimate_band(&self, mc_buf: NAVideoBufferRef<u8>, start: usize, mbs: &mut [InterMB]) {
+        let mut mv_est = self.new_estimator(mc_buf);
+        let mut mv_search = self.new_search();
+        let mut cur_blk = [[0u8; 64]; 6];
+
+        for (mb_pos, mb) in mbs.iter_mut().enumerate() {
+            let mb_pos = start + mb_pos;
+            let mb_x = mb_pos % self.mb_w;
+            let mb_y = mb_pos / self.mb_w;
+            self.src_mbs[mb_pos].fill(&mut cur_blk);
+
+            let (best_mv, _best_dist) = mv_search.search_mb(&mut mv_est, &cur_blk, mb_x, mb_y);
+            mb.mv[3] = best_mv;
+
+            for i in 0..4 {
+                mv_est.mc_block(
+                    i,
+                    0,
+                    mb_x * 16 + (i & 1) * 8,
+                    mb_y * 16 + (i >> 1) * 8,
+                    best_mv,
+                );
+                sub_blk(&mut mb.residue.coeffs[i], &cur_blk[i], &mv_est.ref_blk[i]);
+            }
+            for plane in 1..3 {
+                mv_est.mc_block(plane + 3, plane, mb_x * 8, mb_y * 8, best_mv);
+                sub_blk(
+                    &mut mb.residue.coeffs[plane + 3],
+                    &cur_blk[plane + 3],
+                    &mv_est.ref_blk[plane + 3],
+                );
+            }
+
+            for (dblk, sblk) in mb.reference.iter_mut().zip(mv_est.ref_blk.iter()) {
+                for (dst, &src) in dblk.iter_mut().zip(sblk.iter()) {
+                    *dst = i16::from(src);
+                }
+            }
+            if self.ilace[mb_pos] {
+                frame_to_field(&mut mb.residue.coeffs);
+                frame_to_field(&mut mb.reference);
+            }
+        }
+    }
+    fn estimate_fourmv(
+        &self,
+        mv_est: &mut MVEstimator,
+        mv_search: &mut dyn MVSearch,
+        mb_pos: usize,
+        mb: &mut InterMB,
+    ) -> bool {
+        let mb_x = mb_pos % self.mb_w;
+        let mb_y = mb_pos / self.mb_w;
+
+        let mut cur_blk = [[0u8; 64]; 6];
+        self.src_mbs[mb_pos].fill(&mut cur_blk);
+
+        for i in 0..4 {
+            let xpos = mb_x * 16 + (i & 1) * 8;
+            let ypos = mb_y * 16 + (i >> 1) * 8;
+            let (best_mv, _best_dist) = mv_search.search_blk(mv_est, &cur_blk[i], xpos, ypos);
+            mb.mv[i] = best_mv;
+        }
+        let mvsum = mb.mv[0] + mb.mv[1] + mb.mv[2] + mb.mv[3];
+        let chroma_mv = MV {
+            x: mvsum.x / 4,
+            y: mvsum.y / 4,
+        };
+
+        for (i, blk) in mb.residue.coeffs[..4].iter_mut().enumerate() {
+            let xpos = mb_x * 16 + (i & 1) * 8;
+            let ypos = mb_y * 16 + (i >> 1) * 8;
+            mv_est.mc_block(i, 0, xpos, ypos, mb.mv[i]);
+            sub_blk(blk, &cur_blk[i], &mv_est.ref_blk[i]);
+        }
+        for plane in 1..3 {
+            mv_est.mc_block(plane + 3, plane, mb_x * 8, mb_y * 8, chroma_mv);
+            sub_blk(
+                &mut mb.residue.coeffs[plane + 3],
+                &cur_blk[plane + 3],
+                &mv_est.ref_blk[plane + 3],
+            );
+        }
+
+        for (dblk, sblk) in mb.reference.iter_mut().zip(mv_est.ref_blk.iter()) {
+            for (dst, &src) in dblk.iter_mut().zip(sblk.iter()) {
+                *dst = i16::from(src);
+            }
+        }
+        if self.ilace[mb_pos] {
+            frame_to_field(&mut mb.residue.coeffs);
+            frame_to_field(&mut mb.reference);
+        }
+
+        (mb.mv[0] != mb.mv[1]) || (mb.mv[0] != mb.mv[2]) || (mb.mv[0] != mb.mv[3])
+    }
+}
+
+/// Macroblock candidates compared by the coding mode decision workers.
+struct ModeSelectCtx<'a> {
+    me: &'a MotionSearchCtx<'a>,
+    intra_mbs: &'a [ResidueMB],
+    inter_mbs: &'a [InterMB],
+    golden_mbs: Option<&'a [InterMB]>,
+    quant: usize,
+    lambda: f32,
+}
+
+impl ModeSelectCtx<'_> {
+    /// Selects macroblock types for a band of macroblocks starting at `start`.
+    fn select_band(
+        &self,
+        mc_buf: NAVideoBufferRef<u8>,
+        start: usize,
+        mb_types: &mut [VPMBType],
+        fourmv_mbs: &mut [InterMB],
+    ) {
+        let mut mv_est = self.me.new_estimator(mc_buf);
+        let mut mv_search = self.me.new_search();
+        let mut tmp_mb = ResidueMB::new();
+        let mut src_mb = ResidueMB::new();
+        let quant = self.quant;
+        let lambda = self.lambda;
+        for (mb_idx, (mb_type, fourmv_mb)) in
+            mb_types.iter_mut().zip(fourmv_mbs.iter_mut()).enumerate()
+        {
+            let mb_idx = start + mb_idx;
+            // distortion is measured in the same block layout as the coded residue
+            src_mb.coeffs = self.me.src_mbs[mb_idx].coeffs;
+            if self.me.ilace[mb_idx] {
+                frame_to_field(&mut src_mb.coeffs);
+            }
+            tmp_mb.dequant_from(&self.intra_mbs[mb_idx], quant);
+            tmp_mb.idct();
+            for blk in tmp_mb.coeffs.iter_mut() {
+                for coef in blk.iter_mut() {
+                    *coef = (*coef + 128).max(0).min(255);
+                }
+            }
+            let intra_dist = calc_mb_dist(&src_mb, &tmp_mb);
+            let intra_nits = estimate_intra_mb_nits(&self.intra_mbs[mb_idx].coeffs, quant);
+            let intra_cost = (intra_dist as f32) + lambda * (intra_nits as f32);
+
+            let inter_mb = &self.inter_mbs[mb_idx];
+            tmp_mb.dequant_from(&inter_mb.residue, quant);
+            tmp_mb.idct();
+            for (blk, res) in tmp_mb.coeffs.iter_mut().zip(inter_mb.reference.iter()) {
+                for (coef, add) in blk.iter_mut().zip(res.iter()) {
+                    *coef = (*coef + add).max(0).min(255);
+                }
+            }
+            let inter_dist = calc_mb_dist(&src_mb, &tmp_mb);
+            let mut inter_nits = estimate_inter_mb_nits(inter_mb, quant, false);
+            if inter_mb.mv[3] != ZERO_MV {
+                inter_nits += estimate_mv_nits(inter_mb.mv[3]);
+            }
+            let mut inter_cost = (inter_dist as f32) + lambda * (inter_nits as f32);
+
+            if inter_cost < intra_cost {
+                *mb_type = VPMBType::InterMV;
+
+                if inter_dist > 512 {
+                    self.me
+                        .estimate_fourmv(&mut mv_est, mv_search.as_mut(), mb_idx, fourmv_mb);
+                    fourmv_mb.residue.fdct();
+                    fourmv_mb.residue.quant(quant);
+
+                    tmp_mb.dequant_from(&fourmv_mb.residue, quant);
+                    tmp_mb.idct();
+                    for (blk, res) in tmp_mb.coeffs.iter_mut().zip(fourmv_mb.reference.iter()) {
+                        for (coef, add) in blk.iter_mut().zip(res.iter()) {
+                            *coef = (*coef + add).max(0).min(255);
+                        }
+                    }
+                    let fourmv_dist = calc_mb_dist(&src_mb, &tmp_mb);
+                    let fourmv_nits = estimate_inter_mb_nits(fourmv_mb, quant, true);
+                    let fourmv_cost = (fourmv_dist as f32) + lambda * (fourmv_nits as f32);
+                    if fourmv_cost < inter_cost {
+                        *mb_type = VPMBType::InterFourMV;
+                        inter_cost = fourmv_cost;
+                    }
+                }
+            }
+
+            if let Some(golden_mbs) = self.golden_mbs {
+                let golden_mb = &golden_mbs[mb_idx];
+                tmp_mb.dequant_from(&golden_mb.residue, quant);
+                tmp_mb.idct();
+                for (blk, res) in tmp_mb.coeffs.iter_mut().zip(golden_mb.reference.iter()) {
+                    for (coef, add) in blk.iter_mut().zip(res.iter()) {
+                        *coef = (*coef + add).max(0).min(255);
+                    }
+                }
+                let golden_dist = calc_mb_dist(&src_mb, &tmp_mb);
+                let golden_nits = estimate_inter_mb_nits(golden_mb, quant, false);
+                let golden_cost = (golden_dist as f32) + lambda * (golden_nits as f32);
+
+                if (mb_type.is_intra() && golden_cost < intra_cost)
+                    || (!mb_type.is_intra() && golden_cost < inter_cost)
+                {
+                    *mb_type = VPMBType::GoldenMV;
+                }
+            }
+        }
+    }
+}
+
 impl FrameEncoder {
     pub fn new() -> Self {
         Self::default()
@@ -191,6 +490,8 @@ impl FrameEncoder {
 
         self.mb_types.clear();
         self.mb_types.reserve(num_mbs);
+        self.ilace.clear();
+        self.ilace.reserve(num_mbs);
         self.num_mv.clear();
         self.num_mv.reserve(num_mbs);
         self.coded_mv.clear();
@@ -202,29 +503,29 @@ impl FrameEncoder {
         self.quant = quant;
     }
     pub fn read_mbs(&mut self, vbuf: &NAVideoBuffer<u8>) {
-        let src = vbuf.get_data();
-        let y = &src[vbuf.get_offset(0)..];
-        let ystride = vbuf.get_stride(0);
-        let u = &src[vbuf.get_offset(1)..];
-        let ustride = vbuf.get_stride(1);
-        let v = &src[vbuf.get_offset(2)..];
-        let vstride = vbuf.get_stride(2);
-        let (w, _) = vbuf.get_dimensions(0);
-
-        self.src_mbs.clear();
-        for (ys, (us, vs)) in y
-            .chunks(ystride * 16)
-            .zip(u.chunks(ustride * 8).zip(v.chunks(vstride * 8)))
-        {
-            for x in (0..w).step_by(16) {
-                let mut mb = ResidueMB::new();
-                for (i, blk) in mb.coeffs[..4].iter_mut().enumerate() {
-                    read_block!(blk, ys[x + (i & 1) * 8 + (i >> 1) * 8 * ystride..], ystride);
+        read_frame_mbs(vbuf, self.mb_w, self.mb_h, &mut self.src_mbs);
+    }
+    /// Returns the largest mean absolute difference between source and reference macroblocks.
+    pub fn max_mb_diff(&self, ref_frame: &NAVideoBuffer<u8>) -> u32 {
+        let mut ref_mbs = Vec::with_capacity(self.src_mbs.len());
+        read_frame_mbs(ref_frame, self.mb_w, self.mb_h, &mut ref_mbs);
+        let mut max_diff = 0;
+        for (smb, rmb) in self.src_mbs.iter().zip(ref_mbs.iter()) {
+            let mut diff = 0;
+            for (sblk, rblk) in smb.coeffs.iter().zip(rmb.coeffs.iter()) {
+                for (&s, &r) in sblk.iter().zip(rblk.iter()) {
+                    diff += u32::from((s - r).unsigned_abs());
                 }
-                read_block!(mb.coeffs[4], us[x / 2..], ustride);
-                read_block!(mb.coeffs[5], vs[x / 2..], vstride);
-                self.src_mbs.push(mb);
             }
+            max_diff = max_diff.max(diff);
+        }
+        max_diff / (64 * 6)
+    }
+    /// Selects field or frame DCT for each macroblock.
+    pub fn decide_ilace(&mut self, interlaced: bool) {
+        self.ilace.clear();
+        for mb in self.src_mbs.iter() {
+            self.ilace.push(interlaced && prefer_field_dct(&mb.coeffs));
         }
     }
     pub fn reconstruct_frame(&mut self, dc_pred: &mut VP56DCPred, mut vbuf: NAVideoBufferRef<u8>) {
@@ -282,12 +583,22 @@ impl FrameEncoder {
                     }
                 }
 
-                for i in 0..4 {
-                    write_block!(
-                        &mut dst[yoff + mb_x * 16 + (i & 1) * 8 + (i >> 1) * 8 * ystride..],
-                        blocks[i],
-                        ystride
-                    );
+                if !self.ilace[mb_pos] {
+                    for i in 0..4 {
+                        write_block!(
+                            &mut dst[yoff + mb_x * 16 + (i & 1) * 8 + (i >> 1) * 8 * ystride..],
+                            blocks[i],
+                            ystride
+                        );
+                    }
+                } else {
+                    for i in 0..4 {
+                        write_block!(
+                            &mut dst[yoff + mb_x * 16 + (i & 1) * 8 + (i >> 1) * ystride..],
+                            blocks[i],
+                            ystride * 2
+                        );
+                    }
                 }
                 write_block!(&mut dst[uoff + mb_x * 8..], blocks[4], ustride);
                 write_block!(&mut dst[voff + mb_x * 8..], blocks[5], vstride);
@@ -334,8 +645,11 @@ impl FrameEncoder {
     pub fn prepare_intra_blocks(&mut self) {
         self.intra_mbs.clear();
         self.mb_types.clear();
-        for smb in self.src_mbs.iter() {
+        for (smb, &ilace) in self.src_mbs.iter().zip(self.ilace.iter()) {
             let mut dmb = smb.clone();
+            if ilace {
+                frame_to_field(&mut dmb.coeffs);
+            }
             dmb.fdct();
             for blk in dmb.coeffs.iter_mut() {
                 blk[0] -= 4096;
@@ -345,25 +659,88 @@ impl FrameEncoder {
             self.intra_mbs.push(dmb);
         }
     }
+    /// Re-quantises coefficients of the selected macroblock types using the provided token costs.
+    pub fn trellis_quant(&mut self, costs: &TokenCosts, scan: &[usize; 64]) {
+        let qstep = VP56_AC_QUANTS[self.quant] * 4;
+        for mb_pos in 0..self.mb_w * self.mb_h {
+            let mut dct_mb = self.src_mbs[mb_pos].clone();
+            if self.ilace[mb_pos] {
+                frame_to_field(&mut dct_mb.coeffs);
+            }
+            let mb_type = self.mb_types[mb_pos];
+            if !mb_type.is_intra() {
+                let ref_mb = match mb_type.get_ref_id() {
+                    1 => {
+                        if mb_type != VPMBType::InterFourMV {
+                            &self.inter_mbs[mb_pos].reference
+                        } else {
+                            &self.fourmv_mbs[mb_pos].reference
+                        }
+                    }
+                    _ => &self.golden_mbs[mb_pos].reference,
+                };
+                for (dblk, rblk) in dct_mb.coeffs.iter_mut().zip(ref_mb.iter()) {
+                    for (dst, &pred) in dblk.iter_mut().zip(rblk.iter()) {
+                        *dst -= pred;
+                    }
+                }
+            }
+            dct_mb.fdct();
+
+            let mb = self.get_mb_mut(mb_pos);
+            for (i, (blk, dct)) in mb.coeffs.iter_mut().zip(dct_mb.coeffs.iter()).enumerate() {
+                let plane = if i < 4 { 0 } else { 1 };
+                trellis_quant_block(blk, dct, qstep, plane, scan, costs);
+            }
+        }
+    }
     pub fn prepare_inter_blocks(&mut self, golden: bool) {
         let inter_mbs = if !golden {
             &mut self.inter_mbs
         } else {
             &mut self.golden_mbs
         };
-        for (mb_idx, mb) in inter_mbs.iter_mut().enumerate() {
+        for mb in inter_mbs.iter_mut() {
             mb.residue.fdct();
             mb.residue.quant(self.quant);
-            self.mb_types[mb_idx] = VPMBType::InterMV;
         }
     }
+    /// Marks all macroblocks as copied from the previous frame without residue.
+    pub fn prepare_skip_frame(&mut self) {
+        let num_mbs = self.mb_w * self.mb_h;
+        self.mb_types.clear();
+        self.mb_types.resize(num_mbs, VPMBType::InterNoMV);
+        self.ilace.clear();
+        self.ilace.resize(num_mbs, false);
+        self.inter_mbs.resize(num_mbs, InterMB::new());
+        for mb in self.inter_mbs.iter_mut() {
+            mb.residue = ResidueMB::new();
+            mb.mv = [ZERO_MV; 4];
+        }
+    }
+    /// Prepares per-thread motion compensation buffers and returns the band size in macroblocks.
+    fn prepare_workers(&mut self, mc_buf: NAVideoBufferRef<u8>) -> usize {
+        let num_threads = self.threads.max(1).min(self.mb_h.max(1));
+        let info = mc_buf.get_info();
+        if self.mc_bufs.is_empty() {
+            self.mc_bufs.push(mc_buf);
+        } else {
+            self.mc_bufs[0] = mc_buf;
+        }
+        while self.mc_bufs.len() < num_threads {
+            let vt = alloc_video_buffer(info, 4).unwrap();
+            self.mc_bufs.push(vt.get_vbuf().unwrap());
+        }
+        self.mb_h.div_ceil(num_threads) * self.mb_w
+    }
     pub fn estimate_mvs(
         &mut self,
         ref_frame: NAVideoBufferRef<u8>,
         mc_buf: NAVideoBufferRef<u8>,
         golden: bool,
     ) {
-        let loop_thr = i16::from(VP56_FILTER_LIMITS[self.quant as usize]);
+        let num_mbs = self.mb_w * self.mb_h;
+        let band_size = self.prepare_workers(mc_buf);
 
         let inter_mbs = if !golden {
             &mut self.inter_mbs
@@ -372,220 +749,102 @@ impl FrameEncoder {
         };
 
         if inter_mbs.is_empty() {
-            for _ in 0..self.mb_w * self.mb_h {
+            for _ in 0..num_mbs {
                 inter_mbs.push(InterMB::new());
             }
         }
 
-        let mut cur_blk = [[0u8; 64]; 6];
-
-        let mut mv_est = MVEstimator::new(ref_frame, mc_buf, loop_thr, self.me_range);
-
-        let mut mv_search: Box<dyn MVSearch> = match self.me_mode {
-            MVSearchMode::Full => Box::new(FullMVSearch::new()),
-            MVSearchMode::Diamond => Box::new(DiaSearch::new()),
-            MVSearchMode::Hexagon => Box::new(HexSearch::new()),
+        let ctx = MotionSearchCtx {
+            src_mbs: &self.src_mbs,
+            ilace: &self.ilace,
+            ref_frame,
+            mb_w: self.mb_w,
+            loop_thr: i16::from(VP56_FILTER_LIMITS[self.quant as usize]),
+            me_mode: self.me_mode,
+            me_range: self.me_range,
+            me_subpel: self.me_subpel,
+            mc_params: self.mc_params,
         };
-        let mut mb_pos = 0;
-        for (mb_y, row) in inter_mbs.chunks_mut(self.mb_w).enumerate() {
-            for (mb_x, mb) in row.iter_mut().enumerate() {
-                self.src_mbs[mb_pos].fill(&mut cur_blk);
-
-                let (best_mv, _best_dist) = mv_search.search_mb(&mut mv_est, &cur_blk, mb_x, mb_y);
-                mb.mv[3] = best_mv;
-
-                for i in 0..4 {
-                    mv_est.mc_block(
-                        i,
-                        0,
-                        mb_x * 16 + (i & 1) * 8,
-                        mb_y * 16 + (i >> 1) * 8,
-                        best_mv,
-                    );
-                    sub_blk(&mut mb.residue.coeffs[i], &cur_blk[i], &mv_est.ref_blk[i]);
-                }
-                for plane in 1..3 {
-                    mv_est.mc_block(plane + 3, plane, mb_x * 8, mb_y * 8, best_mv);
-                    sub_blk(
-                        &mut mb.residue.coeffs[plane + 3],
-                        &cur_blk[plane + 3],
-                        &mv_est.ref_blk[plane + 3],
-                    );
-                }
-
-                for (dblk, sblk) in mb.reference.iter_mut().zip(mv_est.ref_blk.iter()) {
-                    for (dst, &src) in dblk.iter_mut().zip(sblk.iter()) {
-                        *dst = i16::from(src);
-                    }
+        let mc_bufs = &self.mc_bufs;
+        if band_size >= num_mbs {
+            ctx.estimate_band(mc_bufs[0].clone(), 0, inter_mbs);
+        } else {
+            std::thread::scope(|s| {
+                let ctx = &ctx;
+                for ((band, mbs), mc_buf) in inter_mbs
+                    .chunks_mut(band_size)
+                    .enumerate()
+                    .zip(mc_bufs.iter())
+                {
+                    s.spawn(move || ctx.estimate_band(mc_buf.clone(), band * band_size, mbs));
                 }
-                mb_pos += 1;
-            }
+            });
         }
     }
-    fn estimate_fourmv(
+    pub fn select_inter_blocks(
         &mut self,
         ref_frame: NAVideoBufferRef<u8>,
         mc_buf: NAVideoBufferRef<u8>,
-        mb_x: usize,
-        mb_y: usize,
-    ) -> bool {
-        let loop_thr = i16::from(VP56_FILTER_LIMITS[self.quant as usize]);
-
+        has_golden_frame: bool,
+        lambda: f32,
+    ) {
+        let num_mbs = self.mb_w * self.mb_h;
         if self.fourmv_mbs.is_empty() {
-            for _ in 0..self.mb_w * self.mb_h {
+            for _ in 0..num_mbs {
                 self.fourmv_mbs.push(InterMB::new());
             }
         }
         if self.fmv_sub.is_empty() {
-            self.fmv_sub
-                .resize(self.mb_w * self.mb_h, [VPMBType::Intra; 4]);
+            self.fmv_sub.resize(num_mbs, [VPMBType::Intra; 4]);
         }
+        let band_size = self.prepare_workers(mc_buf);
 
-        let mb_pos = mb_x + mb_y * self.mb_w;
-        let mb = &mut self.fourmv_mbs[mb_pos];
-
-        let mut cur_blk = [[0u8; 64]; 6];
-        self.src_mbs[mb_pos].fill(&mut cur_blk);
-
-        let mut mv_est = MVEstimator::new(ref_frame, mc_buf, loop_thr, self.me_range);
-
-        let mut mv_search: Box<dyn MVSearch> = match self.me_mode {
-            MVSearchMode::Full => Box::new(FullMVSearch::new()),
-            MVSearchMode::Diamond => Box::new(DiaSearch::new()),
-            MVSearchMode::Hexagon => Box::new(HexSearch::new()),
+        let me = MotionSearchCtx {
+            src_mbs: &self.src_mbs,
+            ilace: &self.ilace,
+            ref_frame,
+            mb_w: self.mb_w,
+            loop_thr: i16::from(VP56_FILTER_LIMITS[self.quant as usize]),
+            me_mode: self.me_mode,
+            me_range: self.me_range,
+            me_subpel: self.me_subpel,
+            mc_params: self.mc_params,
         };
-
-        for i in 0..4 {
-            let xpos = mb_x * 16 + (i & 1) * 8;
-            let ypos = mb_y * 16 + (i >> 1) * 8;
-            let (best_mv, _best_dist) = mv_search.search_blk(&mut mv_est, &cur_blk[i], xpos, ypos);
-            mb.mv[i] = best_mv;
-        }
-        let mvsum = mb.mv[0] + mb.mv[1] + mb.mv[2] + mb.mv[3];
-        let chroma_mv = MV {
-            x: mvsum.x / 4,
-            y: mvsum.y / 4,
+        let ctx = ModeSelectCtx {
+            me: &me,
+            intra_mbs: &self.intra_mbs,
+            inter_mbs: &self.inter_mbs,
+            golden_mbs: if has_golden_frame {
+                Some(&self.golden_mbs)
+            } else {
+                None
+            },
+            quant: self.quant,
+            lambda,
         };
-
-        for (i, blk) in mb.residue.coeffs[..4].iter_mut().enumerate() {
-            let xpos = mb_x * 16 + (i & 1) * 8;
-            let ypos = mb_y * 16 + (i >> 1) * 8;
-            mv_est.mc_block(i, 0, xpos, ypos, mb.mv[i]);
-            sub_blk(blk, &cur_blk[i], &mv_est.ref_blk[i]);
-        }
-        for plane in 1..3 {
-            mv_est.mc_block(plane + 3, plane, mb_x * 8, mb_y * 8, chroma_mv);
-            sub_blk(
-                &mut mb.residue.coeffs[plane + 3],
-                &cur_blk[plane + 3],
-                &mv_est.ref_blk[plane + 3],
+        let mc_bufs = &self.mc_bufs;
+        if band_size >= num_mbs {
+            ctx.select_band(
+                mc_bufs[0].clone(),
+                0,
+                &mut self.mb_types,
+                &mut self.fourmv_mbs,
             );
-        }
-
-        for (dblk, sblk) in mb.reference.iter_mut().zip(mv_est.ref_blk.iter()) {
-            for (dst, &src) in dblk.iter_mut().zip(sblk.iter()) {
-                *dst = i16::from(src);
-            }
-        }
-
-        (mb.mv[0] != mb.mv[1]) || (mb.mv[0] != mb.mv[2]) || (mb.mv[0] != mb.mv[3])
-    }
-    pub fn select_inter_blocks(
-        &mut self,
-        ref_frame: NAVideoBufferRef<u8>,
-        mc_buf: NAVideoBufferRef<u8>,
-        has_golden_frame: bool,
-        lambda: f32,
-    ) {
-        let mut tmp_mb = ResidueMB::new();
-        for mb_idx in 0..self.mb_w * self.mb_h {
-            tmp_mb.dequant_from(&self.intra_mbs[mb_idx], self.quant);
-            tmp_mb.idct();
-            for blk in tmp_mb.coeffs.iter_mut() {
-                for coef in blk.iter_mut() {
-                    *coef = (*coef + 128).max(0).min(255);
-                }
-            }
-            let intra_dist = calc_mb_dist(&self.src_mbs[mb_idx], &tmp_mb);
-            let intra_nits = estimate_intra_mb_nits(&self.intra_mbs[mb_idx].coeffs, self.quant);
-            let intra_cost = (intra_dist as f32) + lambda * (intra_nits as f32);
-
-            tmp_mb.dequant_from(&self.inter_mbs[mb_idx].residue, self.quant);
-            tmp_mb.idct();
-            for (blk, res) in tmp_mb
-                .coeffs
-                .iter_mut()
-                .zip(self.inter_mbs[mb_idx].reference.iter())
-            {
-                for (coef, add) in blk.iter_mut().zip(res.iter()) {
-                    *coef = (*coef + add).max(0).min(255);
-                }
-            }
-            let inter_dist = calc_mb_dist(&self.src_mbs[mb_idx], &tmp_mb);
-            let mut inter_nits = estimate_inter_mb_nits(&self.inter_mbs[mb_idx], self.quant, false);
-            if self.inter_mbs[mb_idx].mv[3] != ZERO_MV {
-                inter_nits += estimate_mv_nits(self.inter_mbs[mb_idx].mv[3]);
-            }
-            let mut inter_cost = (inter_dist as f32) + lambda * (inter_nits as f32);
-
-            if inter_cost < intra_cost {
-                self.mb_types[mb_idx] = VPMBType::InterMV;
-
-                if inter_dist > 512 {
-                    self.estimate_fourmv(
-                        ref_frame.clone(),
-                        mc_buf.clone(),
-                        mb_idx % self.mb_w,
-                        mb_idx / self.mb_w,
-                    );
-                    self.fourmv_mbs[mb_idx].residue.fdct();
-                    self.fourmv_mbs[mb_idx].residue.quant(self.quant);
-
-                    tmp_mb.dequant_from(&self.fourmv_mbs[mb_idx].residue, self.quant);
-                    tmp_mb.idct();
-                    for (blk, res) in tmp_mb
-                        .coeffs
-                        .iter_mut()
-                        .zip(self.fourmv_mbs[mb_idx].reference.iter())
-                    {
-                        for (coef, add) in blk.iter_mut().zip(res.iter()) {
-                            *coef = (*coef + add).max(0).min(255);
-                        }
-                    }
-                    let fourmv_dist = calc_mb_dist(&self.src_mbs[mb_idx], &tmp_mb);
-                    let fourmv_nits =
-                        estimate_inter_mb_nits(&self.fourmv_mbs[mb_idx], self.quant, true);
-                    let fourmv_cost = (fourmv_dist as f32) + lambda * (fourmv_nits as f32);
-                    if fourmv_cost < inter_cost {
-                        self.mb_types[mb_idx] = VPMBType::InterFourMV;
-                        inter_cost = fourmv_cost;
-                    }
-                }
-            }
-
-            if has_golden_frame {
-                tmp_mb.dequant_from(&self.golden_mbs[mb_idx].residue, self.quant);
-                tmp_mb.idct();
-                for (blk, res) in tmp_mb
-                    .coeffs
-                    .iter_mut()
-                    .zip(self.golden_mbs[mb_idx].reference.iter())
-                {
-                    for (coef, add) in blk.iter_mut().zip(res.iter()) {
-                        *coef = (*coef + add).max(0).min(255);
-                    }
-                }
-                let golden_dist = calc_mb_dist(&self.src_mbs[mb_idx], &tmp_mb);
-                let golden_nits =
-                    estimate_inter_mb_nits(&self.golden_mbs[mb_idx], self.quant, false);
-                let golden_cost = (golden_dist as f32) + lambda * (golden_nits as f32);
-
-                if (self.mb_types[mb_idx].is_intra() && golden_cost < intra_cost)
-                    || (!self.mb_types[mb_idx].is_intra() && golden_cost < inter_cost)
-                {
-                    self.mb_types[mb_idx] = VPMBType::GoldenMV;
+        } else {
+            let bands = self
+                .mb_types
+                .chunks_mut(band_size)
+                .zip(self.fourmv_mbs.chunks_mut(band_size))
+                .zip(mc_bufs.iter())
+                .enumerate();
+            std::thread::scope(|s| {
+                let ctx = &ctx;
+                for (band, ((mb_types, fourmv_mbs), mc_buf)) in bands {
+                    s.spawn(move || {
+                        ctx.select_band(mc_buf.clone(), band * band_size, mb_types, fourmv_mbs)
+                    });
                 }
-            }
+            });
         }
     }
     pub fn decide_frame_type(&self) -> (bool, bool) {
@@ -600,6 +859,24 @@ impl FrameEncoder {
         }
         (intra_count > non_intra * 3, intra_count > non_intra)
     }
+    /// Returns estimated intra and inter coding costs and total motion vector length for the frame.
+    pub fn get_complexity(&self, is_intra: bool) -> (u32, u32, u32) {
+        let intra_cost = self
+            .intra_mbs
+            .iter()
+            .map(|mb| estimate_intra_mb_nits(&mb.coeffs, self.quant))
+            .sum();
+        if is_intra {
+            return (intra_cost, 0, 0);
+        }
+        let mut inter_cost = 0;
+        let mut motion = 0;
+        for mb in self.inter_mbs.iter() {
+            inter_cost += estimate_inter_mb_nits(mb, self.quant, false);
+            motion += (mb.mv[3].x.abs() + mb.mv[3].y.abs()) as u32;
+        }
+        (intra_cost, inter_cost, motion)
+    }
     fn find_mv_pred(&self, mb_x: usize, mb_y: usize, ref_id: u8) -> (usize, MV, MV, MV) {
         const CAND_POS: [(i8, i8); 12] = [
             (-1, 0),
@@ -9,7 +9,7 @@ use nihav_core::io::byteio::*;
 mod coder;
 use coder::*;
 mod dsp;
-use dsp::MVSearchMode;
+use dsp::{MCParams, MVSearchMode, SubpelMode};
 mod huff;
 use huff::*;
 mod mb;
@@ -19,6 +19,8 @@ use models::*;
 mod ratectl;
 use ratectl::*;
 mod rdo;
+mod trellis;
+use trellis::TokenCosts;
 
 const VERSION_VP61: u8 = VERSION_VP60 + 1;
 
@@ -46,6 +48,10 @@ pub struct VP56DCPred {
 
 const INVALID_REF: u8 = 42;
 
+// golden frame usage thresholds (in percent of macroblocks)
+const GOLD_USAGE_LOW: usize = 5;
+const GOLD_USAGE_HIGH: usize = 20;
+
 impl VP56DCPred {
     fn new() -> Self {
         Self::default()
@@ -173,6 +179,8 @@ struct VP6Encoder {
     stream: Option<NAStreamRef>,
     pkt: Option<NAPacket>,
     key_int: u8,
+    key_int_min: u8,
+    scene_cut: bool,
     frmcount: u8,
     mb_w: usize,
     mb_h: usize,
@@ -184,31 +192,69 @@ struct VP6Encoder {
 
     flipped: bool,
     huffman: bool,
+    huff_auto: bool,
+    /// Huffman coding is selected for the current frame.
+    use_huffman: bool,
+    huff_bias: u8,
 
     version: u8,
     profile: u8,
+    adv_profile: bool,
+    ilace_mode: bool,
+    interlaced: bool,
+    scale_mode: u8,
+    autosel_pm: bool,
+    var_thresh: u8,
+    mv_thresh: u8,
+    filter_alpha: u8,
 
     models: VP56Models,
     stats: VP56ModelsStat,
+    scan_stats: ScanStats,
+    adaptive_scan: bool,
+    trellis: bool,
     pmodels: VP56Models,
 
     last_frame: NABufferType,
     gold_frame: NABufferType,
     last_gold: bool,
+    gold_int: u8,
+    gold_boost: u8,
+    gold_adapt: bool,
+    gold_age: usize,
+    gold_mbs: usize,
+    gold_usage: usize,
     me_mode: MVSearchMode,
     me_range: i16,
+    me_subpel: SubpelMode,
+    threads: usize,
 
     force_q: Option<usize>,
+    rc_pass: RCPass,
+    stats_name: String,
+    vbv_size: u32,
+    vbv_init: u8,
+    budget_skip: bool,
+    frame_drop: bool,
+    static_thresh: u8,
+
+    has_alpha: bool,
+    alpha_enc: Option<Box<VP6Encoder>>,
+    alpha_src: NABufferType,
+    alpha_q: Option<usize>,
+    alpha_rate: u8,
 }
 
 impl VP6Encoder {
-    fn new(flipped: bool) -> Self {
+    fn new(flipped: bool, has_alpha: bool) -> Self {
         let vt = alloc_video_buffer(NAVideoInfo::new(24, 24, false, VP_YUVA420_FORMAT), 4).unwrap();
         let mc_buf = vt.get_vbuf().unwrap();
         Self {
             stream: None,
             pkt: None,
             key_int: 10,
+            key_int_min: 3,
+            scene_cut: true,
             frmcount: 0,
             mb_w: 0,
             mb_h: 0,
@@ -220,25 +266,310 @@ impl VP6Encoder {
 
             flipped,
             huffman: false,
+            use_huffman: false,
+            huff_auto: false,
+            huff_bias: 0,
 
             version: VERSION_VP60,
             profile: VP6_SIMPLE_PROFILE,
+            adv_profile: false,
+            ilace_mode: false,
+            interlaced: false,
+            scale_mode: 0,
+            autosel_pm: true,
+            var_thresh: 4,
+            mv_thresh: 3,
+            filter_alpha: 8,
 
             models: VP56Models::new(),
             pmodels: VP56Models::new(),
             stats: VP56ModelsStat::new(),
+            scan_stats: ScanStats::new(),
+            adaptive_scan: true,
+            trellis: false,
 
             last_frame: NABufferType::None,
             gold_frame: NABufferType::None,
             last_gold: false,
+            gold_int: 16,
+            gold_boost: 4,
+            gold_adapt: true,
+            gold_age: 0,
+            gold_mbs: 0,
+            gold_usage: 10,
             me_mode: MVSearchMode::default(),
             me_range: 16,
+            me_subpel: SubpelMode::default(),
+            threads: 1,
 
             force_q: None,
+            rc_pass: RCPass::Single,
+            stats_name: "vp6enc.stats".to_string(),
+            vbv_size: 0,
+            vbv_init: 90,
+            budget_skip: false,
+            frame_drop: false,
+            static_thresh: 0,
+
+            has_alpha,
+            alpha_enc: None,
+            alpha_src: NABufferType::None,
+            alpha_q: None,
+            alpha_rate: 20,
+        }
+    }
+    fn init_coder(
+        &mut self,
+        width: usize,
+        height: usize,
+        bitrate: u32,
+        tb_num: u32,
+        tb_den: u32,
+    ) -> EncoderResult<()> {
+        let ref_info = NAVideoInfo::new(width, height, self.flipped, YUV420_FORMAT);
+        self.last_frame = alloc_video_buffer(ref_info, 4)?;
+        self.gold_frame = alloc_video_buffer(ref_info, 4)?;
+
+        self.mb_w = (width + 15) >> 4;
+        self.mb_h = (height + 15) >> 4;
+        self.fenc.resize(self.mb_w, self.mb_h);
+        self.ratectl
+            .init(self.mb_w, self.mb_h, bitrate, tb_num, tb_den);
+
+        self.dc_pred.resize(self.mb_w);
+        self.top_ctx = [
+            vec![false; self.mb_w * 2],
+            vec![false; self.mb_w],
+            vec![false; self.mb_w],
+            vec![false; self.mb_w * 2],
+        ];
+        Ok(())
+    }
+    fn init_alpha(
+        &mut self,
+        width: usize,
+        height: usize,
+        bitrate: u32,
+        tb_num: u32,
+        tb_den: u32,
+    ) -> EncoderResult<()> {
+        let mut aenc = VP6Encoder::new(self.flipped, false);
+        aenc.init_coder(width, height, bitrate, tb_num, tb_den)?;
+        aenc.use_huffman = self.huffman;
+        self.alpha_enc = Some(Box::new(aenc));
+
+        // alpha plane is coded as luma, chroma planes are kept neutral
+        let ainfo = NAVideoInfo::new(width, height, self.flipped, YUV420_FORMAT);
+        self.alpha_src = alloc_video_buffer(ainfo, 4)?;
+        if let Some(ref mut abuf) = self.alpha_src.get_vbuf() {
+            let data = abuf.get_data_mut().unwrap();
+            for el in data.iter_mut() {
+                *el = 0x80;
+            }
+        }
+        Ok(())
+    }
+    fn encode_alpha(&mut self, vbuf: &NAVideoBuffer<u8>, is_intra: bool) -> EncoderResult<Vec<u8>> {
+        let mut abuf = self.alpha_src.get_vbuf().unwrap();
+        let sstride = vbuf.get_stride(3);
+        let dstride = abuf.get_stride(0);
+        let (w, h) = vbuf.get_dimensions(3);
+        let soff = vbuf.get_offset(3);
+        let doff = abuf.get_offset(0);
+        let src = vbuf.get_data();
+        let dst = abuf.get_data_mut().unwrap();
+        for (dline, sline) in dst[doff..]
+            .chunks_mut(dstride)
+            .zip(src[soff..].chunks(sstride))
+            .take(h)
+        {
+            dline[..w].copy_from_slice(&sline[..w]);
+        }
+
+        let aenc = self.alpha_enc.as_mut().unwrap();
+        aenc.huffman = self.huffman;
+        aenc.huff_auto = self.huff_auto;
+        aenc.huff_bias = self.huff_bias;
+        aenc.version = self.version;
+        aenc.adv_profile = self.adv_profile;
+        aenc.ilace_mode = self.ilace_mode;
+        aenc.scale_mode = self.scale_mode;
+        aenc.autosel_pm = self.autosel_pm;
+        aenc.var_thresh = self.var_thresh;
+        aenc.mv_thresh = self.mv_thresh;
+        aenc.filter_alpha = self.filter_alpha;
+        aenc.me_mode = self.me_mode;
+        aenc.me_range = self.me_range;
+        aenc.me_subpel = self.me_subpel;
+        aenc.threads = self.threads;
+        aenc.adaptive_scan = self.adaptive_scan;
+        aenc.trellis = self.trellis;
+        aenc.gold_int = self.gold_int;
+        aenc.gold_boost = self.gold_boost;
+        aenc.gold_adapt = self.gold_adapt;
+        aenc.force_q = self.alpha_q;
+        let (dbuf, _) = aenc
+            .encode_picture(&abuf, is_intra, false)?
+            .ok_or(EncoderError::Bug)?;
+        Ok(dbuf)
+    }
+    fn get_format(&self) -> NAPixelFormaton {
+        if !self.has_alpha {
+            YUV420_FORMAT
+        } else {
+            VP_YUVA420_FORMAT
+        }
+    }
+    fn is_multistream(&self) -> bool {
+        // macroblock headers are not interleaved with coefficients so single stream mode is not used
+        self.use_huffman || self.version != VERSION_VP60 || self.profile == VP6_ADVANCED_PROFILE
+    }
+    fn get_mc_params(&self) -> MCParams {
+        let is_vp62 = self.version == VERSION_VP62;
+        MCParams {
+            adv_profile: self.profile == VP6_ADVANCED_PROFILE,
+            autosel_pm: self.autosel_pm,
+            mv_thresh: self.mv_thresh,
+            var_thresh: if is_vp62 {
+                u16::from(self.var_thresh)
+            } else {
+                u16::from(self.var_thresh) << 5
+            },
+            filter_alpha: if is_vp62 {
+                usize::from(self.filter_alpha)
+            } else {
+                16
+            },
+        }
+    }
+    fn golden_refresh_due(&self) -> bool {
+        if self.gold_int == 0 {
+            return false;
+        }
+        let gold_int = usize::from(self.gold_int);
+        let interval = if !self.gold_adapt {
+            gold_int
+        } else if self.gold_usage >= GOLD_USAGE_HIGH {
+            // static background, keep the golden frame longer
+            gold_int * 2
+        } else if self.gold_usage < GOLD_USAGE_LOW {
+            (gold_int / 2).max(1)
+        } else {
+            gold_int
+        };
+        self.gold_age + 1 >= interval
+    }
+    fn boost_quant(&self, quant: usize) -> usize {
+        let boost = if !self.gold_adapt {
+            usize::from(self.gold_boost)
+        } else {
+            let usage = self.gold_usage.min(GOLD_USAGE_HIGH);
+            (usize::from(self.gold_boost) * usage + GOLD_USAGE_HIGH / 2) / GOLD_USAGE_HIGH
+        };
+        (quant + boost).min(63)
+    }
+    fn update_golden_stats(&mut self, is_intra: bool, golden_frame: bool) {
+        if !is_intra {
+            self.gold_age += 1;
+            self.gold_mbs += self
+                .fenc
+                .mb_types
+                .iter()
+                .filter(|mbt| mbt.get_ref_id() == VP_REF_GOLDEN)
+                .count();
+        }
+        if golden_frame {
+            if self.gold_age > 0 {
+                self.gold_usage = self.gold_mbs * 100 / (self.gold_age * self.mb_w * self.mb_h);
+            }
+            self.gold_age = 0;
+            self.gold_mbs = 0;
         }
     }
-    fn decide_encoding(&mut self) -> bool {
-        false
+    fn decide_encoding(&mut self) -> (bool, bool) {
+        self.fenc.prepare_intra_blocks();
+        self.fenc.prepare_inter_blocks(false);
+        if !self.last_gold {
+            self.fenc.prepare_inter_blocks(true);
+        }
+        let lambda = if self.force_q.is_some() {
+            1.0
+        } else {
+            self.ratectl.lambda
+        };
+        self.fenc.select_inter_blocks(
+            self.last_frame.get_vbuf().unwrap(),
+            self.mc_buf.clone(),
+            !self.last_gold,
+            lambda,
+        );
+        self.fenc.decide_frame_type()
+    }
+    /// Selects between the default, the previous and a newly derived scan order.
+    fn select_scan(&mut self) {
+        let def_order = default_scan_order(self.interlaced);
+        if !self.adaptive_scan {
+            self.models.vp6models.scan_order = *def_order;
+            update_scan(&mut self.models.vp6models);
+            return;
+        }
+        self.scan_stats.reset();
+        for mb_idx in 0..self.mb_w * self.mb_h {
+            for blk in self.fenc.get_mb(mb_idx).coeffs.iter() {
+                self.scan_stats.add_block(blk);
+            }
+        }
+        let prev_order = self.pmodels.vp6models.scan_order;
+        let mut new_order = [0; 64];
+        self.scan_stats.derive_scan_order(&mut new_order);
+
+        let flag_cost = u32::from(PROB_BITS[128]);
+        let mut best_order = *def_order;
+        let mut best_cost = self.scan_stats.estimate_cost(def_order) + flag_cost;
+        if prev_order != *def_order {
+            // returning to the default scan has to be transmitted explicitly
+            best_cost += scan_update_cost(def_order, &prev_order);
+            let cost = self.scan_stats.estimate_cost(&prev_order)
+                + flag_cost
+                + scan_update_cost(&prev_order, &prev_order);
+            if cost < best_cost {
+                best_cost = cost;
+                best_order = prev_order;
+            }
+        }
+        let cost = self.scan_stats.estimate_cost(&new_order)
+            + flag_cost
+            + scan_update_cost(&new_order, &prev_order);
+        // the estimation is rough so require noticeable gain
+        if cost + cost / 16 < best_cost {
+            best_order = new_order;
+        }
+        self.models.vp6models.scan_order = best_order;
+        update_scan(&mut self.models.vp6models);
+    }
+    /// Re-quantises coefficients with token costs derived from the current frame statistics.
+    ///
+    /// Coefficient coding mode is selected here as well since token costs depend on it.
+    fn trellis_requant(&mut self, is_intra: bool) {
+        let mut tmodels = self.models.clone();
+        self.stats.generate(&mut tmodels, is_intra);
+        self.use_huffman = self.select_coeff_coding(&tmodels);
+        let costs = TokenCosts::new(&tmodels, self.use_huffman);
+        self.fenc
+            .trellis_quant(&costs, &self.models.vp6models.zigzag);
+        self.stats.reset();
+        self.estimate_blocks(is_intra);
+    }
+    /// Selects bool or Huffman coefficient coding for the current frame.
+    fn select_coeff_coding(&self, models: &VP56Models) -> bool {
+        if self.huff_auto {
+            let bool_nits = u64::from(self.stats.estimate_coeff_nits(models));
+            let huff_nits = u64::from(self.stats.estimate_coeff_huff_nits(models));
+            // Huffman data decodes faster so it is allowed to be somewhat larger
+            huff_nits * 100 <= bool_nits * (100 + u64::from(self.huff_bias))
+        } else {
+            self.huffman
+        }
     }
     fn estimate_blocks(&mut self, is_intra: bool) {
         for top_ctx in self.top_ctx.iter_mut() {
@@ -450,23 +781,29 @@ impl VP6Encoder {
         }
     }
     fn encode_intra(&mut self, bw: &mut ByteWriter, quant: usize) -> EncoderResult<bool> {
-        self.models.reset(false);
+        self.models.reset(self.interlaced);
         self.models.reset_mbtype_models();
         self.stats.reset();
 
-        self.pmodels.reset(false);
+        self.pmodels.reset(self.interlaced);
         self.pmodels.reset_mbtype_models();
 
-        let multistream = self.huffman || self.version != VERSION_VP60;
-
         self.fenc.prepare_intra_blocks();
         self.fenc.apply_dc_prediction(&mut self.dc_pred);
+        self.select_scan();
         self.estimate_blocks(true);
+        if self.trellis {
+            self.trellis_requant(true);
+        }
         self.stats.generate(&mut self.models, true);
+        if !self.trellis {
+            self.use_huffman = self.select_coeff_coding(&self.models);
+        }
+        let multistream = self.is_multistream();
 
         // header
         bw.write_byte(((quant as u8) << 1) | (multistream as u8))?;
-        bw.write_byte((self.version << 3) | (self.profile << 1))?;
+        bw.write_byte((self.version << 3) | (self.profile << 1) | (self.interlaced as u8))?;
         bw.write_u16be(0)?; // part 2 offset placeholder
 
         let mut bc = BoolEncoder::new(bw);
@@ -475,13 +812,43 @@ impl VP6Encoder {
         bc.put_bits(self.mb_w as u32, 8)?;
         bc.put_bits(self.mb_h as u32, 8)?; // display MB height
         bc.put_bits(self.mb_w as u32, 8)?; // display MB width
-        bc.put_bits(0, 2)?; // scaline mode
-                            // todo other advanced profile bits
-        bc.put_bits(self.huffman as u32, 1)?; // Huffman mode
+        bc.put_bits(u32::from(self.scale_mode), 2)?;
+        if self.profile == VP6_ADVANCED_PROFILE {
+            bc.put_bits(self.autosel_pm as u32, 1)?;
+            if self.autosel_pm {
+                bc.put_bits(u32::from(self.var_thresh), 5)?;
+                bc.put_bits(u32::from(self.mv_thresh), 3)?;
+            } else {
+                bc.put_bits(1, 1)?; // bicubic MC
+            }
+            if self.version == VERSION_VP62 {
+                bc.put_bits(u32::from(self.filter_alpha), 4)?;
+            }
+        }
+        bc.put_bits(self.use_huffman as u32, 1)?; // Huffman mode
 
-        encode_coeff_models(&mut bc, &mut self.models, &self.pmodels, true, false)?;
+        encode_coeff_models(
+            &mut bc,
+            &mut self.models,
+            &self.pmodels,
+            true,
+            self.interlaced,
+        )?;
         self.pmodels = self.models.clone();
 
+        if self.interlaced {
+            let iprob = calc_ilace_prob(&self.fenc.ilace);
+            bc.put_bits(u32::from(iprob), 8)?;
+            for (mb_idx, &ilace) in self.fenc.ilace.iter().enumerate() {
+                let last_ilace = if (mb_idx % self.mb_w) > 0 {
+                    Some(self.fenc.ilace[mb_idx - 1])
+                } else {
+                    None
+                };
+                encode_ilace_flag(&mut bc, ilace, last_ilace, iprob)?;
+            }
+        }
+
         if multistream || (self.profile == VP6_SIMPLE_PROFILE) {
             bc.flush()?;
 
@@ -496,7 +863,7 @@ impl VP6Encoder {
 
             bc = BoolEncoder::new(bw);
         }
-        let writer = if !self.huffman {
+        let writer = if !self.use_huffman {
             VP6Writer::BoolCoder(bc)
         } else {
             VP6Writer::Huffman(HuffEncoder::new(bw))
@@ -504,35 +871,31 @@ impl VP6Encoder {
         self.encode_coeffs(writer)?;
         Ok(true)
     }
-    fn encode_inter(&mut self, bw: &mut ByteWriter, quant: usize) -> EncoderResult<bool> {
+    fn encode_inter(
+        &mut self,
+        bw: &mut ByteWriter,
+        quant: usize,
+        golden_frame: bool,
+        trellis: bool,
+    ) -> EncoderResult<()> {
         self.stats.reset();
 
-        let multistream = self.huffman || self.version != VERSION_VP60;
-        let loop_filter = false;
+        // edge filtering is always applied during motion compensation
+        let loop_filter = true;
 
-        self.fenc.prepare_intra_blocks();
-        self.fenc.prepare_inter_blocks(false);
-        if !self.last_gold {
-            self.fenc.prepare_inter_blocks(true);
-        }
-        let lambda = if self.force_q.is_some() {
-            1.0
-        } else {
-            self.ratectl.lambda
-        };
-        self.fenc.select_inter_blocks(
-            self.last_frame.get_vbuf().unwrap(),
-            self.mc_buf.clone(),
-            !self.last_gold,
-            lambda,
-        );
-        // todo implement forced intra
-        let (_force_intra, golden_frame) = self.fenc.decide_frame_type();
         self.fenc.apply_dc_prediction(&mut self.dc_pred);
         self.fenc.predict_mvs();
+        self.select_scan();
         self.estimate_blocks(false);
+        if trellis {
+            self.trellis_requant(false);
+        }
 
         self.stats.generate(&mut self.models, false);
+        if !trellis {
+            self.use_huffman = self.select_coeff_coding(&self.models);
+        }
+        let multistream = self.is_multistream();
 
         // header
         bw.write_byte(0x80 | ((quant as u8) << 1) | (multistream as u8))?;
@@ -547,11 +910,10 @@ impl VP6Encoder {
                 bc.put_bits(0, 1)?; // loop filter selector
             }
             if self.version == VERSION_VP62 {
-                bc.put_bits(0, 1)?; // auto select PM
+                bc.put_bits(0, 1)?; // keep prediction mode parameters
             }
         }
-        // todo other advanced profile bits
-        bc.put_bits(self.huffman as u32, 1)?;
+        bc.put_bits(self.use_huffman as u32, 1)?;
 
         encode_mode_prob_models(
             &mut bc,
@@ -560,12 +922,31 @@ impl VP6Encoder {
             &self.stats.mbtype_models,
         )?;
         encode_mv_models(&mut bc, &self.models.mv_models, &self.pmodels.mv_models)?;
-        encode_coeff_models(&mut bc, &mut self.models, &self.pmodels, false, false)?;
+        encode_coeff_models(
+            &mut bc,
+            &mut self.models,
+            &self.pmodels,
+            false,
+            self.interlaced,
+        )?;
         self.pmodels = self.models.clone();
 
+        let iprob = calc_ilace_prob(&self.fenc.ilace);
+        if self.interlaced {
+            bc.put_bits(u32::from(iprob), 8)?;
+        }
+
         let mut last_mbt = VPMBType::InterNoMV;
         for mb_idx in 0..self.mb_w * self.mb_h {
             let mb_type = self.fenc.mb_types[mb_idx];
+            if self.interlaced {
+                let last_ilace = if (mb_idx % self.mb_w) > 0 {
+                    Some(self.fenc.ilace[mb_idx - 1])
+                } else {
+                    None
+                };
+                encode_ilace_flag(&mut bc, self.fenc.ilace[mb_idx], last_ilace, iprob)?;
+            }
             encode_mb_type(
                 &mut bc,
                 self.fenc.mb_types[mb_idx],
@@ -616,16 +997,242 @@ impl VP6Encoder {
 
             bc = BoolEncoder::new(bw);
         }
-        let writer = if !self.huffman {
+        let writer = if !self.use_huffman {
             VP6Writer::BoolCoder(bc)
         } else {
             VP6Writer::Huffman(HuffEncoder::new(bw))
         };
         self.encode_coeffs(writer)?;
-        Ok(golden_frame)
+        Ok(())
+    }
+    /// Codes a frame that repeats the previous one.
+    fn encode_skip(&mut self, quant: usize) -> EncoderResult<Vec<u8>> {
+        let mut dbuf = Vec::with_capacity(4);
+        let mut gw = GrowableMemoryWriter::new_write(&mut dbuf);
+        let mut bw = ByteWriter::new(&mut gw);
+        self.fenc.prepare_skip_frame();
+        self.encode_inter(&mut bw, quant, false, false)?;
+        Ok(dbuf)
+    }
+    fn advance_key_counter(&mut self, is_intra: bool) {
+        // postponed keyframe is retried on the next frame
+        if self.key_int > 0 && (is_intra || self.frmcount > 0) {
+            self.frmcount += 1;
+        }
+        if self.frmcount == self.key_int {
+            self.frmcount = 0;
+        }
+    }
+    fn select_quant(&mut self, is_intra: bool) -> usize {
+        if let Some(q) = self.force_q {
+            q
+        } else {
+            self.ratectl.guess_quant(is_intra, self.use_huffman)
+        }
+    }
+    fn encode_picture(
+        &mut self,
+        vbuf: &NAVideoBuffer<u8>,
+        mut is_intra: bool,
+        allow_scene_cut: bool,
+    ) -> EncoderResult<Option<(Vec<u8>, bool)>> {
+        let mut dbuf = Vec::with_capacity(4);
+        let mut gw = GrowableMemoryWriter::new_write(&mut dbuf);
+        let mut bw = ByteWriter::new(&mut gw);
+
+        if is_intra
+            && self.key_int > 0
+            && self.force_q.is_none()
+            && self.ratectl.vbv_postpone_key(self.use_huffman)
+        {
+            // even the coarsest keyframe would underflow the decoder buffer
+            is_intra = false;
+        }
+        let mut quant = self.select_quant(is_intra);
+        let gold_refresh = !is_intra && self.golden_refresh_due();
+        if gold_refresh {
+            quant = self.boost_quant(quant);
+            if self.force_q.is_none() {
+                quant = self.ratectl.limit_quant(false, self.use_huffman, quant);
+            }
+        }
+
+        self.fenc.read_mbs(vbuf);
+        self.fenc.decide_ilace(self.interlaced);
+        self.fenc.set_quant(quant);
+        self.fenc.me_mode = self.me_mode;
+        self.fenc.me_range = self.me_range;
+        self.fenc.me_subpel = self.me_subpel;
+        self.fenc.threads = self.threads;
+        let mut golden_frame = false;
+        let mut skip_frame = false;
+        let static_frame = !is_intra
+            && self.static_thresh > 0
+            && self.fenc.max_mb_diff(&self.last_frame.get_vbuf().unwrap())
+                < u32::from(self.static_thresh);
+        if !is_intra && !static_frame {
+            self.fenc.estimate_mvs(
+                self.last_frame.get_vbuf().unwrap(),
+                self.mc_buf.clone(),
+                false,
+            );
+            if !self.last_gold {
+                self.fenc.estimate_mvs(
+                    self.gold_frame.get_vbuf().unwrap(),
+                    self.mc_buf.clone(),
+                    true,
+                );
+            }
+            let (force_intra, golden) = self.decide_encoding();
+            if force_intra && allow_scene_cut {
+                let intra_quant = self.select_quant(true);
+                if self.ratectl.vbv_allows(true, self.use_huffman, intra_quant) {
+                    // most macroblocks cannot be predicted from the reference frames
+                    is_intra = true;
+                    quant = intra_quant;
+                    self.fenc.set_quant(quant);
+                }
+            }
+            golden_frame = golden || gold_refresh;
+        }
+        // bit budget may be exceeded or decoder buffer may be about to underflow
+        let action = if is_intra || static_frame {
+            FrameAction::Code
+        } else {
+            self.ratectl.frame_action(self.use_huffman, quant)
+        };
+        if action != FrameAction::Code {
+            self.ratectl.relax_size_model();
+        }
+        if is_intra {
+            let saved_state = if self.ratectl.has_vbv() {
+                Some((
+                    self.models.clone(),
+                    self.pmodels.clone(),
+                    self.profile,
+                    self.interlaced,
+                    self.fenc.mc_params,
+                ))
+            } else {
+                None
+            };
+            // profile, interlacing and prediction mode parameters can change only on keyframes
+            self.profile = if self.adv_profile {
+                VP6_ADVANCED_PROFILE
+            } else {
+                VP6_SIMPLE_PROFILE
+            };
+            self.fenc.mc_params = self.get_mc_params();
+            if self.interlaced != self.ilace_mode {
+                self.interlaced = self.ilace_mode;
+                self.fenc.decide_ilace(self.interlaced);
+            }
+            golden_frame = self.encode_intra(&mut bw, quant)?;
+            if let Some((models, pmodels, profile, interlaced, mc_params)) = saved_state {
+                // keyframe size is easily mispredicted after a scene change
+                if !self.ratectl.vbv_key_fits(dbuf.len() * 8) {
+                    self.ratectl
+                        .update_size_model(true, self.use_huffman, quant, dbuf.len() * 8);
+                    self.models = models;
+                    self.pmodels = pmodels;
+                    self.profile = profile;
+                    self.fenc.mc_params = mc_params;
+                    if self.interlaced != interlaced {
+                        self.interlaced = interlaced;
+                        self.fenc.decide_ilace(interlaced);
+                    }
+                    if self.ratectl.overflow_action() == FrameAction::Drop {
+                        self.ratectl.update(0);
+                        return Ok(None);
+                    }
+                    is_intra = false;
+                    skip_frame = true;
+                    golden_frame = false;
+                    dbuf = self.encode_skip(quant)?;
+                }
+            }
+        } else if static_frame {
+            // nothing has changed since the previous frame
+            skip_frame = true;
+            dbuf = self.encode_skip(quant)?;
+        } else if action == FrameAction::Drop {
+            self.ratectl.update(0);
+            return Ok(None);
+        } else if action == FrameAction::Skip {
+            skip_frame = true;
+            golden_frame = false;
+            dbuf = self.encode_skip(quant)?;
+        } else {
+            let saved_models = if self.ratectl.has_vbv() {
+                Some((self.models.clone(), self.pmodels.clone()))
+            } else {
+                None
+            };
+            self.encode_inter(&mut bw, quant, golden_frame, self.trellis)?;
+            if let Some((models, pmodels)) = saved_models {
+                if !self.ratectl.vbv_fits(dbuf.len() * 8) {
+                    self.ratectl
+                        .update_size_model(false, self.use_huffman, quant, dbuf.len() * 8);
+                    self.models = models;
+                    self.pmodels = pmodels;
+                    if self.ratectl.overflow_action() == FrameAction::Drop {
+                        self.ratectl.update(0);
+                        return Ok(None);
+                    }
+                    skip_frame = true;
+                    golden_frame = false;
+                    dbuf = self.encode_skip(quant)?;
+                }
+            }
+        }
+        if !skip_frame {
+            self.fenc
+                .reconstruct_frame(&mut self.dc_pred, self.last_frame.get_vbuf().unwrap());
+            if self.ratectl.has_vbv() {
+                self.ratectl
+                    .update_size_model(is_intra, self.use_huffman, quant, dbuf.len() * 8);
+            }
+            if !is_intra && self.ratectl.is_constant_quality() {
+                let (_, _, motion) = self.fenc.get_complexity(false);
+                let num_mbs = (self.mb_w * self.mb_h) as u32;
+                self.ratectl.update_motion(motion / num_mbs);
+            }
+        }
+        self.last_gold = golden_frame;
+        self.update_golden_stats(is_intra, golden_frame);
+        if golden_frame {
+            let mut dfrm = self.gold_frame.get_vbuf().unwrap();
+            let src = self.last_frame.get_vbuf().unwrap();
+
+            let dst = dfrm.get_data_mut().unwrap();
+            dst.copy_from_slice(src.get_data());
+        }
+
+        if self.ratectl.is_first_pass() {
+            // static frames are coded without any analysis
+            let (intra_cost, inter_cost, motion) = if static_frame {
+                (0, 0, 0)
+            } else {
+                self.fenc.get_complexity(is_intra)
+            };
+            let fstats = FrameStats {
+                is_intra,
+                quant,
+                bits: dbuf.len() * 8,
+                intra_cost,
+                inter_cost,
+                motion,
+            };
+            self.ratectl.add_stats(&fstats)?;
+        }
+        if self.force_q.is_none() {
+            self.ratectl.update(dbuf.len() * 8);
+        }
+
+        Ok(Some((dbuf, is_intra)))
     }
     fn encode_coeffs(&mut self, mut writer: VP6Writer) -> EncoderResult<()> {
-        if self.huffman {
+        if self.use_huffman {
             self.prepare_huff_models();
         }
 
@@ -651,7 +1258,7 @@ impl VP6Encoder {
                     }
                     self.top_ctx[0][cur_idx] = mb.coeffs[i][0] != 0;
                     left_dc[i >> 1] = mb.coeffs[i][0] != 0;
-                    if self.huffman {
+                    if self.use_huffman {
                         self.determine_coeff_runs_luma(&mut hstate, mb_pos, i);
                     }
                     match writer {
@@ -683,7 +1290,7 @@ impl VP6Encoder {
                     }
                     self.top_ctx[plane][mb_x] = mb.coeffs[plane + 3][0] != 0;
                     left_dc[plane + 1] = mb.coeffs[plane + 3][0] != 0;
-                    if self.huffman {
+                    if self.use_huffman {
                         self.determine_coeff_runs_chroma(&mut hstate, mb_pos, plane);
                     }
                     match writer {
@@ -724,7 +1331,7 @@ impl NAEncoder for VP6Encoder {
             NACodecTypeInfo::None => {
                 let mut ofmt = EncodeParameters::default();
                 ofmt.format =
-                    NACodecTypeInfo::Video(NAVideoInfo::new(0, 0, self.flipped, YUV420_FORMAT));
+                    NACodecTypeInfo::Video(NAVideoInfo::new(0, 0, self.flipped, self.get_format()));
                 Ok(ofmt)
             }
             NACodecTypeInfo::Audio(_) => Err(EncoderError::FormatError),
@@ -733,7 +1340,7 @@ impl NAEncoder for VP6Encoder {
                     (vinfo.width + 3) & !3,
                     (vinfo.height + 3) & !3,
                     self.flipped,
-                    YUV420_FORMAT,
+                    self.get_format(),
                 );
                 let mut ofmt = *encinfo;
                 ofmt.format = NACodecTypeInfo::Video(outinfo);
@@ -746,7 +1353,7 @@ impl NAEncoder for VP6Encoder {
             NACodecTypeInfo::None => Err(EncoderError::FormatError),
             NACodecTypeInfo::Audio(_) => Err(EncoderError::FormatError),
             NACodecTypeInfo::Video(vinfo) => {
-                if vinfo.format != YUV420_FORMAT {
+                if vinfo.format != self.get_format() {
                     return Err(EncoderError::FormatError);
                 }
                 if ((vinfo.width | vinfo.height) & 15) != 0 {
@@ -755,14 +1362,21 @@ impl NAEncoder for VP6Encoder {
                 if (vinfo.width | vinfo.height) >= (1 << 12) {
                     return Err(EncoderError::FormatError);
                 }
+                // constant quality and target bitrate are mutually exclusive
+                if encinfo.quality > 0 && encinfo.bitrate > 0 {
+                    return Err(EncoderError::FormatError);
+                }
 
                 let out_info =
                     NAVideoInfo::new(vinfo.width, vinfo.height, self.flipped, vinfo.format);
-                let info = NACodecInfo::new(
-                    if self.flipped { "vp6" } else { "vp6f" },
-                    NACodecTypeInfo::Video(out_info),
-                    None,
-                );
+                let name = if self.has_alpha {
+                    "vp6a"
+                } else if self.flipped {
+                    "vp6"
+                } else {
+                    "vp6f"
+                };
+                let info = NACodecInfo::new(name, NACodecTypeInfo::Video(out_info), None);
                 let mut stream = NAStream::new(
                     StreamType::Video,
                     stream_id,
@@ -774,32 +1388,40 @@ impl NAEncoder for VP6Encoder {
                 stream.set_num(stream_id as usize);
                 let stream = stream.into_ref();
 
-                self.last_frame = alloc_video_buffer(out_info, 4)?;
-                self.gold_frame = alloc_video_buffer(out_info, 4)?;
-
                 self.stream = Some(stream.clone());
 
-                self.mb_w = (vinfo.width + 15) >> 4;
-                self.mb_h = (vinfo.height + 15) >> 4;
-                self.fenc.resize(self.mb_w, self.mb_h);
-                self.ratectl.init(
-                    self.mb_w,
-                    self.mb_h,
-                    encinfo.bitrate,
+                let alpha_bitrate = if self.has_alpha {
+                    ((u64::from(encinfo.bitrate) * u64::from(self.alpha_rate)) / 100) as u32
+                } else {
+                    0
+                };
+                self.init_coder(
+                    vinfo.width,
+                    vinfo.height,
+                    encinfo.bitrate - alpha_bitrate,
                     encinfo.tb_num,
                     encinfo.tb_den,
-                );
-
-                self.dc_pred.resize(self.mb_w);
-                self.top_ctx = [
-                    vec![false; self.mb_w * 2],
-                    vec![false; self.mb_w],
-                    vec![false; self.mb_w],
-                    vec![false; self.mb_w * 2],
-                ];
-
-                self.version = VERSION_VP60;
-                self.profile = VP6_SIMPLE_PROFILE;
+                )?;
+                self.use_huffman = self.huffman;
+                // alpha plane is coded in a single pass
+                self.ratectl
+                    .start_pass(self.rc_pass, &self.stats_name, self.huffman)?;
+                if self.force_q.is_none() {
+                    self.ratectl.set_quality(encinfo.quality);
+                    self.ratectl
+                        .set_vbv((self.vbv_size as usize) * 1000, self.vbv_init);
+                    self.ratectl
+                        .set_frame_skip(self.budget_skip, self.frame_drop);
+                }
+                if self.has_alpha {
+                    self.init_alpha(
+                        vinfo.width,
+                        vinfo.height,
+                        alpha_bitrate,
+                        encinfo.tb_num,
+                        encinfo.tb_den,
+                    )?;
+                }
 
                 Ok(stream)
             }
@@ -808,52 +1430,34 @@ impl NAEncoder for VP6Encoder {
     fn encode(&mut self, frm: &NAFrame) -> EncoderResult<()> {
         let buf = frm.get_buffer();
         if let Some(ref vbuf) = buf.get_vbuf() {
-            let mut dbuf = Vec::with_capacity(4);
-            let mut gw = GrowableMemoryWriter::new_write(&mut dbuf);
-            let mut bw = ByteWriter::new(&mut gw);
-
+            self.ratectl.check_frame()?;
             // todo integrate with rate control
-            let is_intra = (self.frmcount == 0) || self.decide_encoding();
-            let quant = if let Some(q) = self.force_q {
-                q
+            let allow_scene_cut = self.scene_cut && self.frmcount >= self.key_int_min;
+            let (mut dbuf, is_intra) = if let Some(ret) =
+                self.encode_picture(vbuf, self.frmcount == 0, allow_scene_cut)?
+            {
+                ret
             } else {
-                self.ratectl.guess_quant(is_intra, self.huffman)
+                // dropped frame produces no packet, the next ones keep their own timestamps
+                self.advance_key_counter(false);
+                return Ok(());
             };
-
-            self.fenc.read_mbs(vbuf);
-            self.fenc.set_quant(quant);
-            self.fenc.me_mode = self.me_mode;
-            self.fenc.me_range = self.me_range;
-            let golden_frame = if is_intra {
-                self.encode_intra(&mut bw, quant)?
-            } else {
-                self.fenc.estimate_mvs(
-                    self.last_frame.get_vbuf().unwrap(),
-                    self.mc_buf.clone(),
-                    false,
-                );
-                if !self.last_gold {
-                    self.fenc.estimate_mvs(
-                        self.gold_frame.get_vbuf().unwrap(),
-                        self.mc_buf.clone(),
-                        true,
-                    );
-                }
-                self.encode_inter(&mut bw, quant)?
-            };
-            self.fenc
-                .reconstruct_frame(&mut self.dc_pred, self.last_frame.get_vbuf().unwrap());
-            self.last_gold = golden_frame;
-            if golden_frame {
-                let mut dfrm = self.gold_frame.get_vbuf().unwrap();
-                let src = self.last_frame.get_vbuf().unwrap();
-
-                let dst = dfrm.get_data_mut().unwrap();
-                dst.copy_from_slice(src.get_data());
+            if is_intra {
+                self.frmcount = 0;
             }
-
-            if self.force_q.is_none() {
-                self.ratectl.update(dbuf.len() * 8);
+            if self.has_alpha {
+                let abuf = self.encode_alpha(vbuf, is_intra)?;
+                let aoffset = dbuf.len();
+                if aoffset >= (1 << 24) {
+                    return Err(EncoderError::Bug);
+                }
+                let mut data = Vec::with_capacity(aoffset + abuf.len() + 3);
+                data.push((aoffset >> 16) as u8);
+                data.push((aoffset >> 8) as u8);
+                data.push(aoffset as u8);
+                data.extend_from_slice(&dbuf);
+                data.extend_from_slice(&abuf);
+                dbuf = data;
             }
 
             self.pkt = Some(NAPacket::new(
@@ -862,12 +1466,7 @@ impl NAEncoder for VP6Encoder {
                 is_intra,
                 dbuf,
             ));
-            if self.key_int > 0 {
-                self.frmcount += 1;
-            }
-            if self.frmcount == self.key_int {
-                self.frmcount = 0;
-            }
+            self.advance_key_counter(is_intra);
             Ok(())
         } else {
             Err(EncoderError::InvalidParameters)
@@ -880,15 +1479,43 @@ impl NAEncoder for VP6Encoder {
     }
     fn flush(&mut self) -> EncoderResult<()> {
         self.frmcount = 0;
+        self.ratectl.flush_stats()?;
         Ok(())
     }
 }
 
+const KEY_MIN_OPTION: &str = "key_int_min";
+const SCENE_CUT_OPTION: &str = "scene_cut";
 const HUFFMAN_OPTION: &str = "huffman";
+const HUFFMAN_AUTO_OPTION: &str = "huffman_auto";
+const HUFFMAN_BIAS_OPTION: &str = "huffman_bias";
+const GOLDEN_INT_OPTION: &str = "golden_int";
+const GOLDEN_BOOST_OPTION: &str = "golden_boost";
+const GOLDEN_ADAPT_OPTION: &str = "golden_adapt";
 const QUANT_OPTION: &str = "quant";
 const VERSION_OPTION: &str = "version";
+const PROFILE_OPTION: &str = "profile";
+const AUTOSEL_PM_OPTION: &str = "autosel_pm";
+const VAR_THRESH_OPTION: &str = "pm_var_thresh";
+const MV_THRESH_OPTION: &str = "pm_mv_thresh";
+const FILTER_ALPHA_OPTION: &str = "filter_alpha";
+const ADAPTIVE_SCAN_OPTION: &str = "adaptive_scan";
+const INTERLACED_OPTION: &str = "interlaced";
+const SCALE_MODE_OPTION: &str = "scale_mode";
+const TRELLIS_OPTION: &str = "trellis";
 const MV_SEARCH_OPTION: &str = "mv_mode";
 const MV_RANGE_OPTION: &str = "mv_range";
+const MV_SUBPEL_OPTION: &str = "mv_subpel";
+const THREADS_OPTION: &str = "threads";
+const PASS_OPTION: &str = "pass";
+const STATS_FILE_OPTION: &str = "stats_file";
+const VBV_SIZE_OPTION: &str = "vbv_size";
+const VBV_INIT_OPTION: &str = "vbv_init";
+const BUDGET_SKIP_OPTION: &str = "budget_skip";
+const FRAME_DROP_OPTION: &str = "frame_drop";
+const STATIC_THRESH_OPTION: &str = "static_thresh";
+const ALPHA_QUANT_OPTION: &str = "alpha_quant";
+const ALPHA_RATE_OPTION: &str = "alpha_rate";
 
 const ENCODER_OPTS: &[NAOptionDefinition] = &[
     NAOptionDefinition {
@@ -896,21 +1523,137 @@ const ENCODER_OPTS: &[NAOptionDefinition] = &[
         description: KEYFRAME_OPTION_DESC,
         opt_type: NAOptionDefinitionType::Int(Some(0), Some(128)),
     },
+    NAOptionDefinition {
+        name: KEY_MIN_OPTION,
+        description: "minimum distance between keyframes inserted on scene change",
+        opt_type: NAOptionDefinitionType::Int(Some(1), Some(128)),
+    },
+    NAOptionDefinition {
+        name: SCENE_CUT_OPTION,
+        description: "insert keyframes on scene change",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
     NAOptionDefinition {
         name: HUFFMAN_OPTION,
         description: "use Huffman encoding",
         opt_type: NAOptionDefinitionType::Bool,
     },
+    NAOptionDefinition {
+        name: HUFFMAN_AUTO_OPTION,
+        description: "select bool or Huffman coefficient coding per frame",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: HUFFMAN_BIAS_OPTION,
+        description: "allowed Huffman data size increase (in percents) for faster decoding",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(100)),
+    },
+    NAOptionDefinition {
+        name: GOLDEN_INT_OPTION,
+        description: "golden frame refresh interval (0 - refresh only on mode decision)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(128)),
+    },
+    NAOptionDefinition {
+        name: GOLDEN_BOOST_OPTION,
+        description: "quantiser boost for golden frames",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(16)),
+    },
+    NAOptionDefinition {
+        name: GOLDEN_ADAPT_OPTION,
+        description: "adapt golden frame interval and boost to golden frame usage",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
     NAOptionDefinition {
         name: VERSION_OPTION,
         description: "codec minor version",
         opt_type: NAOptionDefinitionType::String(Some(&["vp60", "vp61", "vp62"])),
     },
+    NAOptionDefinition {
+        name: PROFILE_OPTION,
+        description: "codec profile",
+        opt_type: NAOptionDefinitionType::String(Some(&["simple", "advanced"])),
+    },
+    NAOptionDefinition {
+        name: INTERLACED_OPTION,
+        description: "interlaced coding (field or frame DCT is selected per macroblock)",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: SCALE_MODE_OPTION,
+        description: "upscaling requested from player (0 - none, 1 - 5/4, 2 - 5/3, 3 - 2/1)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(3)),
+    },
+    NAOptionDefinition {
+        name: AUTOSEL_PM_OPTION,
+        description: "select bicubic or bilinear motion compensation per block (advanced profile)",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: VAR_THRESH_OPTION,
+        description: "block variance threshold for bicubic motion compensation",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(31)),
+    },
+    NAOptionDefinition {
+        name: MV_THRESH_OPTION,
+        description: "motion vector length threshold for bicubic motion compensation",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(7)),
+    },
+    NAOptionDefinition {
+        name: FILTER_ALPHA_OPTION,
+        description: "bicubic filter sharpness (VP62 only)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(15)),
+    },
     NAOptionDefinition {
         name: QUANT_OPTION,
         description: "force fixed quantiser for encoding",
         opt_type: NAOptionDefinitionType::Int(Some(-1), Some(63)),
     },
+    NAOptionDefinition {
+        name: PASS_OPTION,
+        description:
+            "rate control pass (0 - single pass, 1 - collect statistics, 2 - use statistics)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(2)),
+    },
+    NAOptionDefinition {
+        name: STATS_FILE_OPTION,
+        description: "two-pass statistics file name",
+        opt_type: NAOptionDefinitionType::String(None),
+    },
+    NAOptionDefinition {
+        name: VBV_SIZE_OPTION,
+        description: "decoder buffer size in kbits (0 - unconstrained)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(100000)),
+    },
+    NAOptionDefinition {
+        name: VBV_INIT_OPTION,
+        description: "initial decoder buffer fullness (in percent)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(100)),
+    },
+    NAOptionDefinition {
+        name: BUDGET_SKIP_OPTION,
+        description: "skip frames when the bit budget is overspent",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: FRAME_DROP_OPTION,
+        description: "drop frames instead of coding skip frames",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: STATIC_THRESH_OPTION,
+        description: "skip frames with mean macroblock difference below this value (0 - disabled)",
+        opt_type: NAOptionDefinitionType::Int(Some(0), Some(64)),
+    },
+    NAOptionDefinition {
+        name: ADAPTIVE_SCAN_OPTION,
+        description: "transmit coefficient scan order adapted to frame content",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
+    NAOptionDefinition {
+        name: TRELLIS_OPTION,
+        description: "select coefficient levels by rate-distortion optimisation",
+        opt_type: NAOptionDefinitionType::Bool,
+    },
     NAOptionDefinition {
         name: MV_SEARCH_OPTION,
         description: "motion search mode",
@@ -921,12 +1664,32 @@ const ENCODER_OPTS: &[NAOptionDefinition] = &[
         description: "motion search range (in pixels)",
         opt_type: NAOptionDefinitionType::Int(Some(0), Some(30)),
     },
-];
-
-impl NAOptionHandler for VP6Encoder {
-    fn get_supported_options(&self) -> &[NAOptionDefinition] {
-        ENCODER_OPTS
-    }
+    NAOptionDefinition {
+        name: MV_SUBPEL_OPTION,
+        description: "sub-pixel motion vector refinement",
+        opt_type: NAOptionDefinitionType::String(Some(&["none", "half", "quarter"])),
+    },
+    NAOptionDefinition {
+        name: THREADS_OPTION,
+        description: "number of threads for motion estimation and mode decision",
+        opt_type: NAOptionDefinitionType::Int(Some(1), Some(64)),
+    },
+    NAOptionDefinition {
+        name: ALPHA_QUANT_OPTION,
+        description: "force fixed quantiser for alpha plane",
+        opt_type: NAOptionDefinitionType::Int(Some(-1), Some(63)),
+    },
+    NAOptionDefinition {
+        name: ALPHA_RATE_OPTION,
+        description: "share of bitrate allocated to alpha plane (in percent)",
+        opt_type: NAOptionDefinitionType::Int(Some(1), Some(90)),
+    },
+];
+
+impl NAOptionHandler for VP6Encoder {
+    fn get_supported_options(&self) -> &[NAOptionDefinition] {
+        ENCODER_OPTS
+    }
     fn set_options(&mut self, options: &[NAOption]) {
         for option in options.iter() {
             for opt_def in ENCODER_OPTS.iter() {
@@ -937,11 +1700,46 @@ impl NAOptionHandler for VP6Encoder {
                                 self.key_int = intval as u8;
                             }
                         }
+                        KEY_MIN_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.key_int_min = intval as u8;
+                            }
+                        }
+                        SCENE_CUT_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.scene_cut = bval;
+                            }
+                        }
                         HUFFMAN_OPTION => {
                             if let NAValue::Bool(bval) = option.value {
                                 self.huffman = bval;
                             }
                         }
+                        HUFFMAN_AUTO_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.huff_auto = bval;
+                            }
+                        }
+                        HUFFMAN_BIAS_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.huff_bias = intval as u8;
+                            }
+                        }
+                        GOLDEN_INT_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.gold_int = intval as u8;
+                            }
+                        }
+                        GOLDEN_BOOST_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.gold_boost = intval as u8;
+                            }
+                        }
+                        GOLDEN_ADAPT_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.gold_adapt = bval;
+                            }
+                        }
                         VERSION_OPTION => {
                             if let NAValue::String(ref string) = option.value {
                                 self.version = match string.as_str() {
@@ -952,6 +1750,41 @@ impl NAOptionHandler for VP6Encoder {
                                 };
                             }
                         }
+                        PROFILE_OPTION => {
+                            if let NAValue::String(ref string) = option.value {
+                                self.adv_profile = string == "advanced";
+                            }
+                        }
+                        INTERLACED_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.ilace_mode = bval;
+                            }
+                        }
+                        SCALE_MODE_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.scale_mode = intval as u8;
+                            }
+                        }
+                        AUTOSEL_PM_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.autosel_pm = bval;
+                            }
+                        }
+                        VAR_THRESH_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.var_thresh = intval as u8;
+                            }
+                        }
+                        MV_THRESH_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.mv_thresh = intval as u8;
+                            }
+                        }
+                        FILTER_ALPHA_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.filter_alpha = intval as u8;
+                            }
+                        }
                         QUANT_OPTION => {
                             if let NAValue::Int(intval) = option.value {
                                 self.force_q = if intval < 0 {
@@ -961,6 +1794,55 @@ impl NAOptionHandler for VP6Encoder {
                                 };
                             }
                         }
+                        PASS_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.rc_pass = match intval {
+                                    1 => RCPass::First,
+                                    2 => RCPass::Second,
+                                    _ => RCPass::Single,
+                                };
+                            }
+                        }
+                        STATS_FILE_OPTION => {
+                            if let NAValue::String(ref string) = option.value {
+                                self.stats_name = string.clone();
+                            }
+                        }
+                        VBV_SIZE_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.vbv_size = intval as u32;
+                            }
+                        }
+                        VBV_INIT_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.vbv_init = intval as u8;
+                            }
+                        }
+                        BUDGET_SKIP_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.budget_skip = bval;
+                            }
+                        }
+                        FRAME_DROP_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.frame_drop = bval;
+                            }
+                        }
+                        STATIC_THRESH_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.static_thresh = intval as u8;
+                            }
+                        }
+                        ADAPTIVE_SCAN_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.adaptive_scan = bval;
+                            }
+                        }
+                        TRELLIS_OPTION => {
+                            if let NAValue::Bool(bval) = option.value {
+                                self.trellis = bval;
+                            }
+                        }
                         MV_SEARCH_OPTION => {
                             if let NAValue::String(ref string) = option.value {
                                 if let Ok(mv_mode) = string.parse::<MVSearchMode>() {
@@ -973,6 +1855,32 @@ impl NAOptionHandler for VP6Encoder {
                                 self.me_range = intval as i16;
                             }
                         }
+                        MV_SUBPEL_OPTION => {
+                            if let NAValue::String(ref string) = option.value {
+                                if let Ok(subpel) = string.parse::<SubpelMode>() {
+                                    self.me_subpel = subpel;
+                                }
+                            }
+                        }
+                        THREADS_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.threads = intval as usize;
+                            }
+                        }
+                        ALPHA_QUANT_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.alpha_q = if intval < 0 {
+                                    None
+                                } else {
+                                    Some(intval as usize)
+                                };
+                            }
+                        }
+                        ALPHA_RATE_OPTION => {
+                            if let NAValue::Int(intval) = option.value {
+                                self.alpha_rate = intval as u8;
+                            }
+                        }
                         _ => {}
                     };
                 }
@@ -982,7 +1890,14 @@ impl NAOptionHandler for VP6Encoder {
     fn query_option_value(&self, name: &str) -> Option<NAValue> {
         match name {
             KEYFRAME_OPTION => Some(NAValue::Int(i64::from(self.key_int))),
+            KEY_MIN_OPTION => Some(NAValue::Int(i64::from(self.key_int_min))),
+            SCENE_CUT_OPTION => Some(NAValue::Bool(self.scene_cut)),
             HUFFMAN_OPTION => Some(NAValue::Bool(self.huffman)),
+            HUFFMAN_AUTO_OPTION => Some(NAValue::Bool(self.huff_auto)),
+            HUFFMAN_BIAS_OPTION => Some(NAValue::Int(i64::from(self.huff_bias))),
+            GOLDEN_INT_OPTION => Some(NAValue::Int(i64::from(self.gold_int))),
+            GOLDEN_BOOST_OPTION => Some(NAValue::Int(i64::from(self.gold_boost))),
+            GOLDEN_ADAPT_OPTION => Some(NAValue::Bool(self.gold_adapt)),
             VERSION_OPTION => {
                 let ver = match self.version {
                     VERSION_VP60 => "vp60",
@@ -992,6 +1907,20 @@ impl NAOptionHandler for VP6Encoder {
                 };
                 Some(NAValue::String(ver.to_string()))
             }
+            PROFILE_OPTION => {
+                let profile = if self.adv_profile {
+                    "advanced"
+                } else {
+                    "simple"
+                };
+                Some(NAValue::String(profile.to_string()))
+            }
+            INTERLACED_OPTION => Some(NAValue::Bool(self.ilace_mode)),
+            SCALE_MODE_OPTION => Some(NAValue::Int(i64::from(self.scale_mode))),
+            AUTOSEL_PM_OPTION => Some(NAValue::Bool(self.autosel_pm)),
+            VAR_THRESH_OPTION => Some(NAValue::Int(i64::from(self.var_thresh))),
+            MV_THRESH_OPTION => Some(NAValue::Int(i64::from(self.mv_thresh))),
+            FILTER_ALPHA_OPTION => Some(NAValue::Int(i64::from(self.filter_alpha))),
             QUANT_OPTION => {
                 if let Some(q) = self.force_q {
                     Some(NAValue::Int(q as i64))
@@ -999,19 +1928,49 @@ impl NAOptionHandler for VP6Encoder {
                     Some(NAValue::Int(-1))
                 }
             }
+            PASS_OPTION => {
+                let pass = match self.rc_pass {
+                    RCPass::Single => 0,
+                    RCPass::First => 1,
+                    RCPass::Second => 2,
+                };
+                Some(NAValue::Int(pass))
+            }
+            STATS_FILE_OPTION => Some(NAValue::String(self.stats_name.clone())),
+            VBV_SIZE_OPTION => Some(NAValue::Int(i64::from(self.vbv_size))),
+            VBV_INIT_OPTION => Some(NAValue::Int(i64::from(self.vbv_init))),
+            BUDGET_SKIP_OPTION => Some(NAValue::Bool(self.budget_skip)),
+            FRAME_DROP_OPTION => Some(NAValue::Bool(self.frame_drop)),
+            STATIC_THRESH_OPTION => Some(NAValue::Int(i64::from(self.static_thresh))),
+            ADAPTIVE_SCAN_OPTION => Some(NAValue::Bool(self.adaptive_scan)),
+            TRELLIS_OPTION => Some(NAValue::Bool(self.trellis)),
             MV_SEARCH_OPTION => Some(NAValue::String(self.me_mode.to_string())),
             MV_RANGE_OPTION => Some(NAValue::Int(i64::from(self.me_range))),
+            MV_SUBPEL_OPTION => Some(NAValue::String(self.me_subpel.to_string())),
+            THREADS_OPTION => Some(NAValue::Int(self.threads as i64)),
+            ALPHA_QUANT_OPTION => {
+                if let Some(q) = self.alpha_q {
+                    Some(NAValue::Int(q as i64))
+                } else {
+                    Some(NAValue::Int(-1))
+                }
+            }
+            ALPHA_RATE_OPTION => Some(NAValue::Int(i64::from(self.alpha_rate))),
             _ => None,
         }
     }
 }
 
 pub fn get_encoder() -> Box<dyn NAEncoder + Send> {
-    Box::new(VP6Encoder::new(true))
+    Box::new(VP6Encoder::new(true, false))
 }
 
 pub fn get_encoder_flv() -> Box<dyn NAEncoder + Send> {
-    Box::new(VP6Encoder::new(false))
+    Box::new(VP6Encoder::new(false, false))
+}
+
+pub fn get_encoder_alpha() -> Box<dyn NAEncoder + Send> {
+    Box::new(VP6Encoder::new(false, true))
 }
 
 #[cfg(test)]
@@ -1024,6 +1983,14 @@ mod test {
     use nihav_core::muxers::*;
 
     fn encode_test(out_name: &'static str, enc_options: &[NAOption], hash: &[u32; 4]) {
+        encode_test_quality(out_name, enc_options, 0, hash);
+    }
+    fn encode_test_quality(
+        out_name: &'static str,
+        enc_options: &[NAOption],
+        quality: u8,
+        hash: &[u32; 4],
+    ) {
         let mut dmx_reg = RegisteredDemuxers::new();
         generic_register_all_demuxers(&mut dmx_reg);
         let mut dec_reg = RegisteredDecoders::new();
@@ -1058,8 +2025,8 @@ mod test {
         };
         let enc_params = EncodeParameters {
             format: NACodecTypeInfo::Video(dst_vinfo),
-            quality: 0,
-            bitrate: 25000,
+            quality,
+            bitrate: if quality > 0 { 0 } else { 25000 },
             tb_num: 0,
             tb_den: 0,
             flags: 0,
@@ -1067,6 +2034,91 @@ mod test {
         //test_encoding_to_file(&dec_config, &enc_config, enc_params, enc_options);
         test_encoding_md5(&dec_config, &enc_config, enc_params, enc_options, hash);
     }
+    // copies picture so decoder can reuse its buffers
+    fn copy_picture(buf: &NABufferType) -> NABufferType {
+        let mut vbuf = NAVideoBuffer::clone(&buf.get_vbuf().unwrap());
+        NABufferType::Video(vbuf.copy_buffer().into_ref())
+    }
+    fn gen_frame<F: Fn(usize, usize, usize) -> u8>(vinfo: NAVideoInfo, gen: F) -> NABufferType {
+        let buf = alloc_video_buffer(vinfo, 4).unwrap();
+        let mut vbuf = buf.get_vbuf().unwrap();
+        for plane in 0..vbuf.get_num_components() {
+            let (w, h) = vbuf.get_dimensions(plane);
+            let stride = vbuf.get_stride(plane);
+            let off = vbuf.get_offset(plane);
+            let data = vbuf.get_data_mut().unwrap();
+            for (y, line) in data[off..].chunks_mut(stride).take(h).enumerate() {
+                for (x, pix) in line[..w].iter_mut().enumerate() {
+                    *pix = gen(plane, x, y);
+                }
+            }
+        }
+        buf
+    }
+    fn encode_frames(
+        enc_name: &str,
+        frames: &[NABufferType],
+        quality: u8,
+        bitrate: u32,
+        enc_options: &[NAOption],
+    ) -> (NAStreamRef, Vec<NAPacket>) {
+        let mut enc_reg = RegisteredEncoders::new();
+        duck_register_all_encoders(&mut enc_reg);
+        let mut enc = (enc_reg.find_encoder(enc_name).unwrap())();
+        let vinfo = frames[0].get_video_info().unwrap();
+        let enc_params = EncodeParameters {
+            format: NACodecTypeInfo::Video(vinfo),
+            quality,
+            bitrate,
+            tb_num: 1,
+            tb_den: 25,
+            flags: 0,
+        };
+        enc.set_options(enc_options);
+        let stream = enc.init(0, enc_params).unwrap();
+        let mut pkts = Vec::new();
+        for (i, buf) in frames.iter().enumerate() {
+            let ts = NATimeInfo::new(Some(i as u64), None, None, 1, 25);
+            let info = stream.get_info();
+            let frm = NAFrame::new(ts, FrameType::Other, false, info, buf.clone());
+            enc.encode(&frm).unwrap();
+            while let Some(pkt) = enc.get_packet().unwrap() {
+                pkts.push(pkt);
+            }
+        }
+        enc.flush().unwrap();
+        while let Some(pkt) = enc.get_packet().unwrap() {
+            pkts.push(pkt);
+        }
+        (stream, pkts)
+    }
+    fn decode_packets(stream: &NAStreamRef, pkts: &[NAPacket]) -> Vec<NABufferType> {
+        let mut dec_reg = RegisteredDecoders::new();
+        duck_register_all_decoders(&mut dec_reg);
+        let mut dec = (dec_reg.find_decoder(stream.get_info().get_name()).unwrap())();
+        let mut dsupp = Box::new(NADecoderSupport::new());
+        dec.init(&mut dsupp, stream.get_info()).unwrap();
+        pkts.iter()
+            .map(|pkt| copy_picture(&dec.decode(&mut dsupp, pkt).unwrap().get_buffer()))
+            .collect()
+    }
+    // returns mean absolute difference between the same planes of two pictures
+    fn plane_diff(src: &NABufferType, dst: &NABufferType, plane: usize) -> f64 {
+        let src = src.get_vbuf().unwrap();
+        let dst = dst.get_vbuf().unwrap();
+        let (w, h) = src.get_dimensions(plane);
+        let mut sum = 0;
+        for (sline, dline) in src.get_data()[src.get_offset(plane)..]
+            .chunks(src.get_stride(plane))
+            .zip(dst.get_data()[dst.get_offset(plane)..].chunks(dst.get_stride(plane)))
+            .take(h)
+        {
+            for (&s, &d) in sline[..w].iter().zip(dline[..w].iter()) {
+                sum += u32::from((i16::from(s) - i16::from(d)).unsigned_abs());
+            }
+        }
+        f64::from(sum) / ((w * h) as f64)
+    }
     #[test]
     fn test_vp6_encoder_bc() {
         let enc_options = &[NAOption {
@@ -1081,14 +2133,75 @@ mod test {
     }
     #[test]
     fn test_vp6_encoder_rc() {
-        let enc_options = &[];
+        let enc_options = &[NAOption {
+            name: super::SCENE_CUT_OPTION,
+            value: NAValue::Bool(false),
+        }];
         encode_test(
             "vp6-rc.avi",
             enc_options,
-            &[0x7e55b29c, 0x754f65c2, 0x7f26c010, 0x6f577b35],
+            &[0x66e1d1e4, 0x47bbe6d3, 0xf18b0ad1, 0xcfd107dc],
         );
     }
     #[test]
+    fn test_vp6_encoder_scene_cut() {
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        // hard cuts at frames 6 and 8
+        let frames: Vec<NABufferType> = (0..18)
+            .map(|t| {
+                gen_frame(vinfo, |plane, x, y| match (plane, t) {
+                    (0, 0..=5) => (x + y + t) as u8,
+                    (0, 6..=7) => (255 - x * 2 + t) as u8,
+                    (0, _) => {
+                        if ((y + t) / 8) % 2 == 0 {
+                            20
+                        } else {
+                            220
+                        }
+                    }
+                    (_, 0..=5) => 0x60,
+                    (_, 6..=7) => 0xA0,
+                    (_, _) => (0x40 + x) as u8,
+                })
+            })
+            .collect();
+        let tests: [(bool, i64, &[usize]); 3] = [
+            (true, 1, &[0, 6, 8]),
+            // the second cut is too close to the previous keyframe
+            (true, 3, &[0, 6, 16]),
+            (false, 3, &[0, 10]),
+        ];
+        for &(scene_cut, key_int_min, ref_kf) in tests.iter() {
+            let enc_options = &[
+                NAOption {
+                    name: super::KEYFRAME_OPTION,
+                    value: NAValue::Int(10),
+                },
+                NAOption {
+                    name: super::KEY_MIN_OPTION,
+                    value: NAValue::Int(key_int_min),
+                },
+                NAOption {
+                    name: super::SCENE_CUT_OPTION,
+                    value: NAValue::Bool(scene_cut),
+                },
+                NAOption {
+                    name: super::QUANT_OPTION,
+                    value: NAValue::Int(42),
+                },
+            ];
+            let (_, pkts) = encode_frames("vp6", &frames, 0, 0, enc_options);
+            assert_eq!(pkts.len(), frames.len());
+            let keyframes: Vec<usize> = pkts
+                .iter()
+                .enumerate()
+                .filter(|(_, pkt)| pkt.is_keyframe())
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(keyframes.as_slice(), ref_kf);
+        }
+    }
+    #[test]
     fn test_vp6_encoder_huff() {
         let enc_options = &[
             NAOption {
@@ -1106,4 +2219,1031 @@ mod test {
             &[0x8a247df3, 0xe47fe0c4, 0x983a85d7, 0x73f82b05],
         );
     }
+    #[test]
+    fn test_vp6_encoder_golden() {
+        let enc_options = &[
+            NAOption {
+                name: super::GOLDEN_INT_OPTION,
+                value: NAValue::Int(1),
+            },
+            NAOption {
+                name: super::GOLDEN_BOOST_OPTION,
+                value: NAValue::Int(8),
+            },
+            NAOption {
+                name: super::GOLDEN_ADAPT_OPTION,
+                value: NAValue::Bool(false),
+            },
+        ];
+        encode_test(
+            "vp6-golden.avi",
+            enc_options,
+            &[0x5802f50d, 0xfb9264d4, 0xf9a2f0ff, 0x7c133917],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_golden_adapt() {
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        // static background with a flickering area that is predicted well from the golden frame
+        let frames: Vec<NABufferType> = (0..40usize)
+            .map(|t| {
+                gen_frame(vinfo, move |plane, x, y| {
+                    let texture = ((x * 7 + y * 3) ^ (x * y / 16)) as u8;
+                    match plane {
+                        0 if (t & 1) == 1 && x < 64 => 255 - texture,
+                        0 => texture,
+                        _ => 0x80,
+                    }
+                })
+            })
+            .collect();
+        for &(adapt, ref_gold) in [
+            (false, &[4, 8, 12, 16, 20, 24, 28, 32, 36][..]),
+            // the refresh interval is doubled for the frequently used golden frame
+            (true, &[4, 12, 20, 28, 36][..]),
+        ]
+        .iter()
+        {
+            let enc_options = &[
+                NAOption {
+                    name: super::KEYFRAME_OPTION,
+                    value: NAValue::Int(100),
+                },
+                NAOption {
+                    name: super::GOLDEN_INT_OPTION,
+                    value: NAValue::Int(4),
+                },
+                NAOption {
+                    name: super::GOLDEN_ADAPT_OPTION,
+                    value: NAValue::Bool(adapt),
+                },
+            ];
+            let (_, pkts) = encode_frames("vp6", &frames, 0, 0, enc_options);
+            assert_eq!(pkts.len(), frames.len());
+            let mut parser = crate::vp6::VP6HeaderParser::new(false);
+            let mut golden = Vec::new();
+            for (i, pkt) in pkts.iter().enumerate() {
+                let hdr = parser.parse_frame_header(&pkt.get_buffer()).unwrap().main;
+                assert_eq!(hdr.is_intra, i == 0);
+                if !hdr.is_intra && hdr.is_golden.unwrap() {
+                    golden.push(i);
+                }
+            }
+            assert_eq!(golden.as_slice(), ref_gold);
+        }
+    }
+    // first pass statistics file that is removed after the test
+    struct StatsFile(std::path::PathBuf);
+    impl StatsFile {
+        fn new(tag: &str) -> Self {
+            let name = format!("vp6enc-{}-{}.stats", tag, std::process::id());
+            Self(std::env::temp_dir().join(name))
+        }
+        fn get_name(&self) -> NAValue {
+            NAValue::String(self.0.to_string_lossy().into_owned())
+        }
+    }
+    impl Drop for StatsFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_2pass() {
+        let stats_file = StatsFile::new("2pass");
+        let stats_name = stats_file.get_name();
+        let enc_options = &[
+            NAOption {
+                name: super::PASS_OPTION,
+                value: NAValue::Int(1),
+            },
+            NAOption {
+                name: super::STATS_FILE_OPTION,
+                value: stats_name.clone(),
+            },
+        ];
+        encode_test(
+            "vp6-pass1.avi",
+            enc_options,
+            &[0x4f59a9a8, 0x535e3bda, 0x2bc6bb7d, 0x8bd35251],
+        );
+        let enc_options = &[
+            NAOption {
+                name: super::PASS_OPTION,
+                value: NAValue::Int(2),
+            },
+            NAOption {
+                name: super::STATS_FILE_OPTION,
+                value: stats_name,
+            },
+        ];
+        encode_test(
+            "vp6-pass2.avi",
+            enc_options,
+            &[0x2c5eea4e, 0xde01029b, 0x2ccd6450, 0xc0e3ced7],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_2pass_size() {
+        const BITRATE: u32 = 100000;
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        let frames: Vec<NABufferType> = (0..25)
+            .map(|t| {
+                gen_frame(vinfo, |plane, x, y| {
+                    if plane == 0 {
+                        (((x + t * 2) ^ (y + t)) * (1 + t / 8)) as u8
+                    } else {
+                        (0x60 + x + t) as u8
+                    }
+                })
+            })
+            .collect();
+        let stats_file = StatsFile::new("2pass-size");
+        let stats_name = stats_file.get_name();
+        let pass_options = |pass: i64| {
+            [
+                NAOption {
+                    name: super::PASS_OPTION,
+                    value: NAValue::Int(pass),
+                },
+                NAOption {
+                    name: super::STATS_FILE_OPTION,
+                    value: stats_name.clone(),
+                },
+            ]
+        };
+        encode_frames("vp6", &frames, 0, BITRATE, &pass_options(1));
+        let (_, pkts) = encode_frames("vp6", &frames, 0, BITRATE, &pass_options(2));
+        assert_eq!(pkts.len(), frames.len());
+        let total: usize = pkts.iter().map(|pkt| pkt.get_buffer().len() * 8).sum();
+        let target = (BITRATE as usize) * frames.len() / 25;
+        assert!(total.abs_diff(target) < target / 10);
+
+        let mut enc_reg = RegisteredEncoders::new();
+        duck_register_all_encoders(&mut enc_reg);
+        let mut enc_params = EncodeParameters {
+            format: NACodecTypeInfo::Video(vinfo),
+            quality: 0,
+            bitrate: BITRATE,
+            tb_num: 1,
+            tb_den: 25,
+            flags: 0,
+        };
+        // more frames than in the first pass
+        let mut enc = (enc_reg.find_encoder("vp6").unwrap())();
+        enc.set_options(&pass_options(2));
+        let stream = enc.init(0, enc_params).unwrap();
+        for (i, buf) in frames.iter().chain(frames[..1].iter()).enumerate() {
+            let ts = NATimeInfo::new(Some(i as u64), None, None, 1, 25);
+            let frm = NAFrame::new(ts, FrameType::Other, false, stream.get_info(), buf.clone());
+            assert_eq!(enc.encode(&frm).is_ok(), i < frames.len());
+        }
+        // fewer frames than in the first pass
+        let mut enc = (enc_reg.find_encoder("vp6").unwrap())();
+        enc.set_options(&pass_options(2));
+        let stream = enc.init(0, enc_params).unwrap();
+        let ts = NATimeInfo::new(Some(0), None, None, 1, 25);
+        let frm = NAFrame::new(
+            ts,
+            FrameType::Other,
+            false,
+            stream.get_info(),
+            frames[0].clone(),
+        );
+        enc.encode(&frm).unwrap();
+        assert!(enc.flush().is_err());
+        // different frame size
+        enc_params.format = NACodecTypeInfo::Video(NAVideoInfo::new(144, 96, true, YUV420_FORMAT));
+        let mut enc = (enc_reg.find_encoder("vp6").unwrap())();
+        enc.set_options(&pass_options(2));
+        assert!(enc.init(0, enc_params).is_err());
+    }
+    #[test]
+    fn test_vp6_encoder_vbv() {
+        const BITRATE: usize = 200000;
+        const VBV_SIZE: usize = 80000;
+        let vinfo = NAVideoInfo::new(160, 128, true, YUV420_FORMAT);
+        let opts = [
+            NAOption {
+                name: super::VBV_SIZE_OPTION,
+                value: NAValue::Int((VBV_SIZE / 1000) as i64),
+            },
+            NAOption {
+                name: super::VBV_INIT_OPTION,
+                value: NAValue::Int(50),
+            },
+        ];
+        // scene change to detailed content or to noise that cannot fit into the rate at all
+        for &noise in [false, true].iter() {
+            let frames: Vec<NABufferType> = (0..30)
+                .map(|t| {
+                    gen_frame(vinfo, |plane, x, y| {
+                        let pos = x + t * 3;
+                        if plane != 0 {
+                            (pos + y * 2) as u8
+                        } else if t < 10 {
+                            (64 + ((pos / 4) * 7 + (y / 4) * 13) * (y / 4 + 3) % 128) as u8
+                        } else if !noise {
+                            (64 + ((pos / 4) * 23 + (y / 4) * 41) * (pos / 4 + 1) % 128) as u8
+                        } else {
+                            ((pos * 37 + y * 11) * (pos ^ y ^ t) % 251) as u8
+                        }
+                    })
+                })
+                .collect();
+            let (_, pkts) = encode_frames("vp6", &frames, 0, BITRATE as u32, &opts);
+            assert_eq!(pkts.len(), frames.len());
+
+            // data arrives at constant rate and each frame is removed from the buffer at once
+            let mut fullness = VBV_SIZE / 2;
+            for pkt in pkts.iter() {
+                let size = pkt.get_buffer().len() * 8;
+                assert!(size <= fullness, "buffer underflow at {:?}", pkt.get_pts());
+                fullness = fullness - size + BITRATE / 25;
+                // simple frames cannot take more bits than at the finest quantiser
+                // while noise can only be skipped
+                let quant = (pkt.get_buffer()[0] >> 1) & 0x3F;
+                assert!(
+                    fullness <= VBV_SIZE || quant == 63 || noise,
+                    "buffer overflow at {:?}",
+                    pkt.get_pts()
+                );
+                fullness = fullness.min(VBV_SIZE);
+            }
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_cq() {
+        encode_test_quality(
+            "vp6-cq.avi",
+            &[],
+            60,
+            &[0xb280ee8c, 0x21524baa, 0xa4d997e5, 0x8192bc83],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_cq_adapt() {
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        // static scene followed by fast panning
+        let frames: Vec<NABufferType> = (0..10usize)
+            .map(|t| {
+                let pos = t.saturating_sub(5) * 12;
+                gen_frame(vinfo, move |plane, x, y| {
+                    if plane == 0 {
+                        let x = x + pos;
+                        ((x * 7 + y * 3) ^ (x * y / 16)) as u8
+                    } else {
+                        0x80
+                    }
+                })
+            })
+            .collect();
+        let (_, pkts) = encode_frames("vp6", &frames, 60, 0, &[]);
+        assert_eq!(pkts.len(), frames.len());
+        // frame quantiser is stored in the first header byte
+        let quants: Vec<u8> = pkts
+            .iter()
+            .map(|pkt| (pkt.get_buffer()[0] >> 1) & 0x3F)
+            .collect();
+        assert!(pkts[0].is_keyframe() && !pkts[1..].iter().any(|pkt| pkt.is_keyframe()));
+        // keyframes and static frames are quantised finer than high-motion ones
+        assert!(quants[2..6].iter().all(|&q| q > quants[9]));
+        assert!(quants[0] > quants[9]);
+
+        // quality and bitrate cannot be requested at the same time
+        let mut enc_reg = RegisteredEncoders::new();
+        duck_register_all_encoders(&mut enc_reg);
+        let mut enc = (enc_reg.find_encoder("vp6").unwrap())();
+        let enc_params = EncodeParameters {
+            format: NACodecTypeInfo::Video(vinfo),
+            quality: 60,
+            bitrate: 100000,
+            tb_num: 1,
+            tb_den: 25,
+            flags: 0,
+        };
+        assert!(enc.init(0, enc_params).is_err());
+    }
+    #[test]
+    fn test_vp6_encoder_halfpel() {
+        let enc_options = &[
+            NAOption {
+                name: super::MV_SEARCH_OPTION,
+                value: NAValue::String("dia".to_string()),
+            },
+            NAOption {
+                name: super::MV_SUBPEL_OPTION,
+                value: NAValue::String("half".to_string()),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(42),
+            },
+        ];
+        encode_test(
+            "vp6-halfpel.avi",
+            enc_options,
+            &[0xa46a0542, 0x8f849121, 0x4b3abd03, 0xefb15b21],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_subpel_modes() {
+        // content moving by 1.25 pixels per frame
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        let frames: Vec<NABufferType> = (0..5)
+            .map(|t| {
+                gen_frame(vinfo, |plane, x, y| {
+                    let scale = if plane == 0 { 4 } else { 8 };
+                    let pos = (x * scale + t * 5) as f32 / (scale as f32);
+                    (128.0 + (pos / 5.0).sin() * 50.0 + (y as f32 / 7.0).cos() * 40.0) as u8
+                })
+            })
+            .collect();
+        for mode in ["full", "dia", "hex"].iter() {
+            for &(subpel, align) in [("none", 4), ("half", 2)].iter() {
+                let opts = [
+                    NAOption {
+                        name: super::MV_SEARCH_OPTION,
+                        value: NAValue::String(mode.to_string()),
+                    },
+                    NAOption {
+                        name: super::MV_SUBPEL_OPTION,
+                        value: NAValue::String(subpel.to_string()),
+                    },
+                    NAOption {
+                        name: super::QUANT_OPTION,
+                        value: NAValue::Int(50),
+                    },
+                ];
+                let (stream, pkts) = encode_frames("vp6", &frames, 0, 0, &opts);
+
+                let mut dec_reg = RegisteredDecoders::new();
+                duck_register_all_decoders(&mut dec_reg);
+                let mut dec = (dec_reg.find_decoder("vp6").unwrap())();
+                let mut dsupp = Box::new(NADecoderSupport::new());
+                dec.init(&mut dsupp, stream.get_info()).unwrap();
+                dec.set_options(&[NAOption {
+                    name: "mb_info",
+                    value: NAValue::Bool(true),
+                }]);
+                let mut fine_mvs = 0;
+                for pkt in pkts.iter() {
+                    let frm = dec.decode(&mut dsupp, pkt).unwrap();
+                    let info = match frm.side_data[0] {
+                        NASideData::Custom(ref data) => data.downcast_ref::<crate::VP56FrameInfo>(),
+                        _ => None,
+                    }
+                    .unwrap();
+                    for mv in info.mb_params.iter().flat_map(|mb| mb.mvs.iter()) {
+                        assert_eq!((mv.x % align, mv.y % align), (0, 0));
+                        if mv.x % (align * 2) != 0 {
+                            fine_mvs += 1;
+                        }
+                    }
+                }
+                // the finest allowed precision should be used for such motion
+                assert!(fine_mvs > 0, "{} {}", mode, subpel);
+            }
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_adv() {
+        let enc_options = &[
+            NAOption {
+                name: super::VERSION_OPTION,
+                value: NAValue::String("vp62".to_string()),
+            },
+            NAOption {
+                name: super::PROFILE_OPTION,
+                value: NAValue::String("advanced".to_string()),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(42),
+            },
+        ];
+        encode_test(
+            "vp6-adv.avi",
+            enc_options,
+            &[0xab154e3b, 0x0f6a929b, 0x39a55d23, 0x300896e2],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_adaptive_scan() {
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        // screen-like content with vertical detail only
+        let frames: Vec<NABufferType> = (0..4usize)
+            .map(|t| {
+                gen_frame(vinfo, move |plane, x, _y| {
+                    if plane == 0 {
+                        ((x * 37 + t * 11) * (x ^ 5) % 251) as u8
+                    } else {
+                        0x80
+                    }
+                })
+            })
+            .collect();
+        let mut enc = super::VP6Encoder::new(true, false);
+        let enc_params = EncodeParameters {
+            format: NACodecTypeInfo::Video(vinfo),
+            quality: 0,
+            bitrate: 0,
+            tb_num: 1,
+            tb_den: 25,
+            flags: 0,
+        };
+        enc.set_options(&[NAOption {
+            name: super::QUANT_OPTION,
+            value: NAValue::Int(50),
+        }]);
+        let stream = enc.init(0, enc_params).unwrap();
+        let def_order = *super::default_scan_order(false);
+        let mut pkts = Vec::new();
+        let mut orders = Vec::new();
+        for (i, buf) in frames.iter().enumerate() {
+            let ts = NATimeInfo::new(Some(i as u64), None, None, 1, 25);
+            let frm = NAFrame::new(ts, FrameType::Other, false, stream.get_info(), buf.clone());
+            enc.encode(&frm).unwrap();
+            pkts.push(enc.get_packet().unwrap().unwrap());
+            orders.push(enc.models.vp6models.scan_order);
+        }
+        // derived order is transmitted as changes to the default one
+        assert!(orders.iter().all(|order| *order != def_order));
+        // then updated relative to the previous order and kept when it stays the best
+        assert_ne!(orders[1], orders[0]);
+        assert_eq!(orders[2], orders[1]);
+        assert!(
+            super::scan_update_cost(&orders[2], &orders[1])
+                < super::scan_update_cost(&orders[1], &orders[0])
+        );
+
+        let dec_frames = decode_packets(&stream, &pkts);
+        for (src, dst) in frames.iter().zip(dec_frames.iter()) {
+            assert!(plane_diff(src, dst, 0) < 4.0);
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_scan_revert() {
+        use super::coder::{encode_coeff_models, BoolEncoder};
+        use super::models::VP56Models;
+        use crate::codecs::vp6data::{HAS_COEF_PROBS, HAS_SCAN_UPD_PROBS};
+        use crate::codecs::vpcommon::BoolCoder;
+
+        let def_order = *super::default_scan_order(false);
+        let mut custom_order = def_order;
+        custom_order[1..].reverse();
+        let mut pmodels = VP56Models::new();
+        pmodels.vp6models.scan_order = custom_order;
+        let mut models = pmodels.clone();
+        models.vp6models.scan_order = def_order;
+
+        let mut buf = Vec::new();
+        {
+            let mut gw = GrowableMemoryWriter::new_write(&mut buf);
+            let mut bw = ByteWriter::new(&mut gw);
+            let mut bc = BoolEncoder::new(&mut bw);
+            encode_coeff_models(&mut bc, &mut models, &pmodels, false, false).unwrap();
+            bc.flush().unwrap();
+        }
+        // decode it the way decoders keeping the previous scan order do
+        let mut bc = BoolCoder::new(&buf).unwrap();
+        for plane in 0..2 {
+            for i in 0..11 {
+                assert!(!bc.read_prob(HAS_COEF_PROBS[plane][i]));
+            }
+        }
+        assert!(bc.read_bool());
+        let mut order = custom_order;
+        for i in 1..64 {
+            if bc.read_prob(HAS_SCAN_UPD_PROBS[i]) {
+                order[i] = bc.read_bits(4) as usize;
+            }
+        }
+        assert_eq!(order, def_order);
+    }
+    #[test]
+    fn test_vp6_encoder_interlaced() {
+        let enc_options = &[
+            NAOption {
+                name: super::INTERLACED_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(42),
+            },
+        ];
+        encode_test(
+            "vp6-ilace.avi",
+            enc_options,
+            &[0xc54bce78, 0xf64710ba, 0x577eefd7, 0xc108d199],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_interlaced_roundtrip() {
+        // two unrelated fields so field DCT is selected for most macroblocks
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        let frames: Vec<NABufferType> = (0..4)
+            .map(|t| {
+                gen_frame(vinfo, |plane, x, y| match (plane, y & 1) {
+                    (0, 0) => (x + y + t * 2) as u8,
+                    (0, _) => (200 - x + y / 2) as u8,
+                    _ => (0x70 + x / 2) as u8,
+                })
+            })
+            .collect();
+        let mut enc = super::VP6Encoder::new(true, false);
+        let enc_params = EncodeParameters {
+            format: NACodecTypeInfo::Video(vinfo),
+            quality: 0,
+            bitrate: 0,
+            tb_num: 1,
+            tb_den: 25,
+            flags: 0,
+        };
+        enc.set_options(&[
+            NAOption {
+                name: super::INTERLACED_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(50),
+            },
+        ]);
+        let stream = enc.init(0, enc_params).unwrap();
+        let mut pkts = Vec::new();
+        let mut recon = Vec::new();
+        for (i, buf) in frames.iter().enumerate() {
+            let ts = NATimeInfo::new(Some(i as u64), None, None, 1, 25);
+            let frm = NAFrame::new(ts, FrameType::Other, false, stream.get_info(), buf.clone());
+            enc.encode(&frm).unwrap();
+            pkts.push(enc.get_packet().unwrap().unwrap());
+            assert!(
+                enc.fenc.ilace.iter().filter(|&&ilace| ilace).count() > enc.fenc.ilace.len() / 2
+            );
+            recon.push(copy_picture(&enc.last_frame));
+        }
+
+        // decoder should reproduce the encoder reconstruction exactly
+        let dec_frames = decode_packets(&stream, &pkts);
+        for (src, dst) in recon.iter().zip(dec_frames.iter()) {
+            for plane in 0..3 {
+                assert_eq!(plane_diff(src, dst, plane), 0.0);
+            }
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_trellis() {
+        let enc_options = &[
+            NAOption {
+                name: super::TRELLIS_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(42),
+            },
+        ];
+        encode_test(
+            "vp6-trellis.avi",
+            enc_options,
+            &[0x7067a3d1, 0x307b9041, 0x16463227, 0xad54b000],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_trellis_huff() {
+        let enc_options = &[
+            NAOption {
+                name: super::TRELLIS_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::HUFFMAN_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(42),
+            },
+        ];
+        encode_test(
+            "vp6-trellis-huff.avi",
+            enc_options,
+            &[0x823325ae, 0xaf19b3a4, 0xcac56edd, 0xa7c0d4da],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_huffman_auto() {
+        let enc_options = &[
+            NAOption {
+                name: super::HUFFMAN_AUTO_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::HUFFMAN_BIAS_OPTION,
+                value: NAValue::Int(20),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(42),
+            },
+        ];
+        encode_test(
+            "vp6-huff-auto.avi",
+            enc_options,
+            &[0x8db2ad6f, 0x5f568aeb, 0x6713b59a, 0xe5f067a0],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_huffman_auto_trellis() {
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        let frames: Vec<NABufferType> = (0..4usize)
+            .map(|t| {
+                gen_frame(vinfo, move |plane, x, y| {
+                    if plane == 0 {
+                        ((x + t * 3) * 2 + y) as u8
+                    } else {
+                        (0x70 + y / 2 + t) as u8
+                    }
+                })
+            })
+            .collect();
+        let mut enc = super::VP6Encoder::new(true, false);
+        enc.set_options(&[
+            NAOption {
+                name: super::HUFFMAN_AUTO_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::HUFFMAN_BIAS_OPTION,
+                value: NAValue::Int(100),
+            },
+            NAOption {
+                name: super::TRELLIS_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(50),
+            },
+        ]);
+        let enc_params = EncodeParameters {
+            format: NACodecTypeInfo::Video(vinfo),
+            quality: 0,
+            bitrate: 0,
+            tb_num: 1,
+            tb_den: 25,
+            flags: 0,
+        };
+        let stream = enc.init(0, enc_params).unwrap();
+        let mut pkts = Vec::new();
+        for (i, buf) in frames.iter().enumerate() {
+            let ts = NATimeInfo::new(Some(i as u64), None, None, 1, 25);
+            let frm = NAFrame::new(ts, FrameType::Other, false, stream.get_info(), buf.clone());
+            enc.encode(&frm).unwrap();
+            pkts.push(enc.get_packet().unwrap().unwrap());
+        }
+        // automatic selection does not override the user option
+        assert_eq!(
+            enc.query_option_value(super::HUFFMAN_OPTION),
+            Some(NAValue::Bool(false))
+        );
+        let mut parser = crate::vp6::VP6HeaderParser::new(false);
+        assert!(pkts.iter().any(|pkt| parser
+            .parse_frame_header(&pkt.get_buffer())
+            .unwrap()
+            .main
+            .use_huffman
+            == Some(true)));
+
+        let dec_frames = decode_packets(&stream, &pkts);
+        for (src, dst) in frames.iter().zip(dec_frames.iter()) {
+            assert!(plane_diff(src, dst, 0) < 2.0);
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_frame_drop() {
+        let enc_options = &[
+            NAOption {
+                name: super::BUDGET_SKIP_OPTION,
+                value: NAValue::Bool(true),
+            },
+            NAOption {
+                name: super::FRAME_DROP_OPTION,
+                value: NAValue::Bool(true),
+            },
+        ];
+        encode_test(
+            "vp6-drop.avi",
+            enc_options,
+            &[0x4e2f064a, 0x795f1e81, 0xb95981dd, 0xda3b63f8],
+        );
+
+        // noise cannot be coded at such low bitrate so frames have to be skipped
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        let frames: Vec<NABufferType> = (0..12usize)
+            .map(|t| {
+                gen_frame(vinfo, move |_plane, x, y| {
+                    let val = ((x * 7 + y * 131 + t * 977) as u32).wrapping_mul(0x9E37_79B1);
+                    (val >> 24) as u8
+                })
+            })
+            .collect();
+        for &drop in [false, true].iter() {
+            let enc_options = &[
+                NAOption {
+                    name: super::BUDGET_SKIP_OPTION,
+                    value: NAValue::Bool(true),
+                },
+                NAOption {
+                    name: super::FRAME_DROP_OPTION,
+                    value: NAValue::Bool(drop),
+                },
+                NAOption {
+                    name: super::SCENE_CUT_OPTION,
+                    value: NAValue::Bool(false),
+                },
+            ];
+            let (stream, pkts) = encode_frames("vp6", &frames, 0, 20000, enc_options);
+            let pts: Vec<u64> = pkts
+                .iter()
+                .map(|pkt| pkt.get_time_information().pts.unwrap())
+                .collect();
+            assert!(pts.windows(2).all(|w| w[0] < w[1]));
+            if drop {
+                assert!(pkts.len() < frames.len());
+                // remaining frames keep their timestamps
+                assert!(pts.windows(2).any(|w| w[1] > w[0] + 1));
+            } else {
+                assert_eq!(pkts.len(), frames.len());
+                assert_eq!(pts, (0..frames.len() as u64).collect::<Vec<u64>>());
+                // skip frame repeats the previous picture
+                let dec_frames = decode_packets(&stream, &pkts);
+                assert!(dec_frames
+                    .windows(2)
+                    .any(|w| (0..3).all(|plane| plane_diff(&w[0], &w[1], plane) == 0.0)));
+            }
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_scale_mode() {
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        let frames: Vec<NABufferType> = (0..3)
+            .map(|t| gen_frame(vinfo, |plane, x, y| (x + y * 2 + t * 3 + plane * 40) as u8))
+            .collect();
+        let opts = [NAOption {
+            name: super::SCALE_MODE_OPTION,
+            value: NAValue::Int(2),
+        }];
+        let (stream, pkts) = encode_frames("vp6", &frames, 0, 200000, &opts);
+        let mut parser = crate::vp6::VP6HeaderParser::new(false);
+        let hdr = parser.parse_frame_header(&pkts[0].get_buffer()).unwrap();
+        assert_eq!(hdr.main.kf_params.unwrap().scale, 2);
+
+        // scaling mode is only a hint unless upscaling is requested
+        for (src, dst) in frames.iter().zip(decode_packets(&stream, &pkts).iter()) {
+            let dinfo = dst.get_video_info().unwrap();
+            assert_eq!((dinfo.get_width(), dinfo.get_height()), (128, 96));
+            assert!(plane_diff(src, dst, 0) < 2.0);
+        }
+
+        let mut dec_reg = RegisteredDecoders::new();
+        duck_register_all_decoders(&mut dec_reg);
+        let mut dec = (dec_reg.find_decoder("vp6").unwrap())();
+        let mut dsupp = Box::new(NADecoderSupport::new());
+        dec.init(&mut dsupp, stream.get_info()).unwrap();
+        dec.set_options(&[NAOption {
+            name: "upscale",
+            value: NAValue::Bool(true),
+        }]);
+        for pkt in pkts.iter() {
+            let frm = dec.decode(&mut dsupp, pkt).unwrap();
+            let dinfo = frm.get_buffer().get_video_info().unwrap();
+            assert_eq!((dinfo.get_width(), dinfo.get_height()), (213, 160));
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_static_thresh() {
+        let vinfo = NAVideoInfo::new(128, 96, true, YUV420_FORMAT);
+        let base = |plane: usize, x: usize, y: usize| {
+            if plane == 0 {
+                (x + y) as u8
+            } else {
+                (0x70 + x / 4) as u8
+            }
+        };
+        let frames = [
+            gen_frame(vinfo, base),
+            gen_frame(vinfo, base),
+            // small change everywhere
+            gen_frame(vinfo, |plane, x, y| base(plane, x, y).wrapping_add(1)),
+            // large change in a single macroblock
+            gen_frame(vinfo, |plane, x, y| {
+                if plane == 0 && x >= 32 && x < 48 && y >= 16 && y < 32 {
+                    !base(plane, x, y)
+                } else {
+                    base(plane, x, y)
+                }
+            }),
+        ];
+        let enc_options = &[
+            NAOption {
+                name: super::STATIC_THRESH_OPTION,
+                value: NAValue::Int(4),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(50),
+            },
+        ];
+        let (stream, pkts) = encode_frames("vp6", &frames, 0, 0, enc_options);
+        assert_eq!(pkts.len(), frames.len());
+        let dec_frames = decode_packets(&stream, &pkts);
+        let same = |a: usize, b: usize| {
+            (0..3).all(|plane| plane_diff(&dec_frames[a], &dec_frames[b], plane) == 0.0)
+        };
+        assert!(same(0, 1));
+        assert!(same(1, 2));
+        assert!(!same(2, 3));
+        assert!(plane_diff(&frames[3], &dec_frames[3], 0) < 2.0);
+    }
+    #[test]
+    fn test_vp6_encoder_threads() {
+        // the output must match test_vp6_encoder_halfpel
+        let enc_options = &[
+            NAOption {
+                name: super::MV_SEARCH_OPTION,
+                value: NAValue::String("dia".to_string()),
+            },
+            NAOption {
+                name: super::MV_SUBPEL_OPTION,
+                value: NAValue::String("half".to_string()),
+            },
+            NAOption {
+                name: super::QUANT_OPTION,
+                value: NAValue::Int(42),
+            },
+            NAOption {
+                name: super::THREADS_OPTION,
+                value: NAValue::Int(3),
+            },
+        ];
+        encode_test(
+            "vp6-threads.avi",
+            enc_options,
+            &[0xa46a0542, 0x8f849121, 0x4b3abd03, 0xefb15b21],
+        );
+
+        // threaded encoding must produce the same output as the single-threaded one
+        let vinfo = NAVideoInfo::new(160, 128, true, YUV420_FORMAT);
+        let frames: Vec<NABufferType> = (0..6usize)
+            .map(|t| {
+                gen_frame(vinfo, move |plane, x, y| {
+                    if plane == 0 {
+                        let dx = x as isize - 40 - (t as isize) * 5;
+                        let dy = y as isize - 64 + (t as isize) * 3;
+                        ((x + y * 2 + t) as isize + (dx * dy) / 64) as u8
+                    } else {
+                        (0x60 + x / 2 + t * 2) as u8
+                    }
+                })
+            })
+            .collect();
+        let mode_options = [
+            vec![],
+            vec![
+                NAOption {
+                    name: super::MV_SEARCH_OPTION,
+                    value: NAValue::String("dia".to_string()),
+                },
+                NAOption {
+                    name: super::MV_SUBPEL_OPTION,
+                    value: NAValue::String("quarter".to_string()),
+                },
+                NAOption {
+                    name: super::TRELLIS_OPTION,
+                    value: NAValue::Bool(true),
+                },
+            ],
+        ];
+        for options in mode_options.iter() {
+            let encode = |threads: i64| {
+                let mut enc_options = options.clone();
+                enc_options.push(NAOption {
+                    name: super::THREADS_OPTION,
+                    value: NAValue::Int(threads),
+                });
+                let (_, pkts) = encode_frames("vp6", &frames, 0, 200000, &enc_options);
+                pkts.iter()
+                    .map(|pkt| pkt.get_buffer().to_vec())
+                    .collect::<Vec<Vec<u8>>>()
+            };
+            let ref_pkts = encode(1);
+            assert_eq!(ref_pkts.len(), frames.len());
+            for threads in [2, 3, 4].iter() {
+                assert!(encode(*threads) == ref_pkts);
+            }
+        }
+    }
+    #[test]
+    fn test_vp6_encoder_alpha() {
+        let mut dmx_reg = RegisteredDemuxers::new();
+        generic_register_all_demuxers(&mut dmx_reg);
+        let mut dec_reg = RegisteredDecoders::new();
+        duck_register_all_decoders(&mut dec_reg);
+        let mut mux_reg = RegisteredMuxers::new();
+        generic_register_all_muxers(&mut mux_reg);
+        let mut enc_reg = RegisteredEncoders::new();
+        duck_register_all_encoders(&mut enc_reg);
+
+        let dec_config = DecoderTestParams {
+            demuxer: "avi",
+            in_name: "assets/vp6a.avi",
+            stream_type: StreamType::Video,
+            limit: Some(3),
+            dmx_reg,
+            dec_reg,
+        };
+        let enc_config = EncoderTestParams {
+            muxer: "avi",
+            enc_name: "vp6a",
+            out_name: "vp6a.avi",
+            mux_reg,
+            enc_reg,
+        };
+        let dst_vinfo = NAVideoInfo {
+            width: 768,
+            height: 208,
+            format: super::VP_YUVA420_FORMAT,
+            flipped: false,
+            bits: 12,
+        };
+        let enc_params = EncodeParameters {
+            format: NACodecTypeInfo::Video(dst_vinfo),
+            quality: 0,
+            bitrate: 25000,
+            tb_num: 0,
+            tb_den: 0,
+            flags: 0,
+        };
+        let enc_options = &[NAOption {
+            name: super::ALPHA_QUANT_OPTION,
+            value: NAValue::Int(20),
+        }];
+        //test_encoding_to_file(&dec_config, &enc_config, enc_params, enc_options);
+        test_encoding_md5(
+            &dec_config,
+            &enc_config,
+            enc_params,
+            enc_options,
+            &[0xb0ae6d54, 0x14e7af8f, 0x1e77ffa0, 0x34e0285b],
+        );
+    }
+    #[test]
+    fn test_vp6_encoder_alpha_roundtrip() {
+        let vinfo = NAVideoInfo::new(128, 96, false, super::VP_YUVA420_FORMAT);
+        let frames: Vec<NABufferType> = (0..5)
+            .map(|t| {
+                gen_frame(vinfo, |plane, x, y| match plane {
+                    0 => ((x + t * 3) * 2 + y) as u8,
+                    3 => {
+                        // moving disc with soft edges
+                        let dx = (x as isize) - 40 - (t as isize) * 8;
+                        let dy = (y as isize) - 48;
+                        let dist = ((dx * dx + dy * dy) as f64).sqrt() as isize;
+                        ((40 - dist) * 32).clamp(0, 255) as u8
+                    }
+                    _ => 0x80,
+                })
+            })
+            .collect();
+        for &alpha_q in [40, -1].iter() {
+            let enc_options = &[NAOption {
+                name: super::ALPHA_QUANT_OPTION,
+                value: NAValue::Int(alpha_q),
+            }];
+            let (stream, pkts) = encode_frames("vp6a", &frames, 0, 200000, enc_options);
+            assert_eq!(pkts.len(), frames.len());
+
+            let mut hdr_parser = crate::vp6::VP6HeaderParser::new(true);
+            let mut alpha_differs = false;
+            for pkt in pkts.iter() {
+                let hdr = hdr_parser.parse_frame_header(&pkt.get_buffer()).unwrap();
+                let aquant = hdr.alpha.unwrap().quant;
+                if alpha_q >= 0 {
+                    assert_eq!(i64::from(aquant), alpha_q);
+                }
+                alpha_differs |= aquant != hdr.main.quant;
+            }
+            // alpha plane has its own quantiser or rate control
+            assert!(alpha_differs);
+
+            let dec_frames = decode_packets(&stream, &pkts);
+            for (src, dst) in frames.iter().zip(dec_frames.iter()) {
+                assert!(plane_diff(src, dst, 0) < 1.0);
+                assert!(plane_diff(src, dst, 3) < 4.0);
+            }
+        }
+    }
 }
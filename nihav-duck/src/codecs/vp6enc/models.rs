@@ -1,4 +1,5 @@
 use super::super::vp6data::*;
+use super::huff::{eob_run_bits, MAX_EOB_RUN};
 use nihav_codec_support::codecs::ZIGZAG;
 
 #[derive(Clone, Copy, Default)]
@@ -241,6 +242,110 @@ pub fn reset_scan(model: &mut VP6Models, interlaced: bool) {
     model.zigzag.copy_from_slice(&ZIGZAG);
 }
 
+pub fn update_scan(model: &mut VP6Models) {
+    let mut idx = 1;
+    for band in 0..16 {
+        for i in 1..64 {
+            if model.scan_order[i] == band {
+                model.scan[idx] = i;
+                idx += 1;
+            }
+        }
+    }
+    for i in 1..64 {
+        model.zigzag[i] = ZIGZAG[model.scan[i]];
+    }
+}
+
+pub fn default_scan_order(interlaced: bool) -> &'static [usize; 64] {
+    if !interlaced {
+        &VP6_DEFAULT_SCAN_ORDER
+    } else {
+        &VP6_INTERLACED_SCAN_ORDER
+    }
+}
+
+// approximate cost of zero run coding in nits
+const ZERO_RUN_START_NITS: u32 = 8;
+const ZERO_RUN_COEF_NITS: u32 = 4;
+
+/// Coefficient statistics used to select adaptive scan order.
+pub struct ScanStats {
+    nz_count: [u32; 64],
+    /// Non-zero AC coefficient positions (in zigzag order) for each block.
+    masks: Vec<u64>,
+}
+
+impl ScanStats {
+    pub fn new() -> Self {
+        Self {
+            nz_count: [0; 64],
+            masks: Vec::new(),
+        }
+    }
+    pub fn reset(&mut self) {
+        self.nz_count = [0; 64];
+        self.masks.clear();
+    }
+    pub fn add_block(&mut self, blk: &[i16; 64]) {
+        let mut mask = 0;
+        for i in 1..64 {
+            if blk[ZIGZAG[i]] != 0 {
+                mask |= 1 << i;
+                self.nz_count[i] += 1;
+            }
+        }
+        if mask != 0 {
+            self.masks.push(mask);
+        }
+    }
+    /// Assigns bands to coefficient positions by their frequency of being non-zero.
+    pub fn derive_scan_order(&self, scan_order: &mut [usize; 64]) {
+        let mut order: Vec<usize> = (1..64).collect();
+        order.sort_by(|&a, &b| self.nz_count[b].cmp(&self.nz_count[a]));
+        scan_order[0] = 0;
+        for (rank, &pos) in order.iter().enumerate() {
+            scan_order[pos] = rank * 16 / 63;
+        }
+    }
+    /// Estimates the cost of coding zeroes before the last non-zero coefficient.
+    pub fn estimate_cost(&self, scan_order: &[usize; 64]) -> u32 {
+        let mut model = VP6Models::new();
+        model.scan_order = *scan_order;
+        update_scan(&mut model);
+
+        let mut cost = 0;
+        for &mask in self.masks.iter() {
+            let mut run = 0;
+            for &pos in model.scan[1..].iter() {
+                if (mask & (1 << pos)) != 0 {
+                    if run > 0 {
+                        cost += ZERO_RUN_START_NITS + run * ZERO_RUN_COEF_NITS;
+                        run = 0;
+                    }
+                } else {
+                    run += 1;
+                }
+            }
+        }
+        cost
+    }
+}
+
+/// Calculates the cost of transmitting scan order relative to the previous one.
+pub fn scan_update_cost(scan_order: &[usize; 64], prev_order: &[usize; 64]) -> u32 {
+    let mut cost = 0;
+    for i in 1..64 {
+        let prob = usize::from(HAS_SCAN_UPD_PROBS[i]);
+        if scan_order[i] != prev_order[i] {
+            cost += u32::from(PROB_BITS[256 - prob]) + 4 * 8;
+        } else {
+            cost += u32::from(PROB_BITS[prob]);
+        }
+    }
+    cost
+}
+
 #[derive(Clone, Copy, Default)]
 pub struct ProbCounter {
     zeroes: u32,
@@ -290,6 +395,13 @@ impl ProbCounter {
             old_prob
         }
     }
+    pub fn est_nits(&self, prob: u8) -> u32 {
+        u32::from(PROB_BITS[prob as usize]) * self.zeroes
+            + u32::from(PROB_BITS[256 - (prob as usize)]) * (self.total - self.zeroes)
+    }
+    fn ones(&self) -> u32 {
+        self.total - self.zeroes
+    }
     fn est_bits(prob: u8, zeroes: u32, total: u32) -> u32 {
         (u32::from(PROB_BITS[prob as usize]) * zeroes
             + u32::from(PROB_BITS[256 - (prob as usize)]) * (total - zeroes)
@@ -311,6 +423,92 @@ pub struct VP56CoeffModelStat {
     pub dc_token_probs: [[[ProbCounter; 5]; 6]; 6],
     pub dc_value_probs: [ProbCounter; 11],
     pub ac_val_probs: [[[ProbCounter; 11]; 6]; 3],
+    pub huff_runs: HuffRunStat,
+}
+
+/// Statistics for zero DC and EOB runs used only in Huffman mode.
+#[derive(Clone, Copy, Default)]
+pub struct HuffRunStat {
+    dc_run: Option<usize>,
+    ac_run: Option<usize>,
+    dc_skipped: u32,
+    eob_skipped: [u32; 3],
+    run_bits: u32,
+}
+
+impl HuffRunStat {
+    pub fn add_block(&mut self, blk: &[i16; 64]) {
+        if Self::update_run(&mut self.dc_run, blk[0] == 0, &mut self.run_bits) {
+            self.dc_skipped += 1;
+        }
+        let no_ac = blk[1..].iter().all(|&el| el == 0);
+        if Self::update_run(&mut self.ac_run, no_ac, &mut self.run_bits) {
+            self.eob_skipped[blk[0].unsigned_abs().min(2) as usize] += 1;
+        }
+    }
+    // returns true if the block is covered by an already coded run
+    fn update_run(run: &mut Option<usize>, is_zero: bool, run_bits: &mut u32) -> bool {
+        if !is_zero {
+            *run = None;
+            return false;
+        }
+        match *run {
+            Some(len) if len < MAX_EOB_RUN => {
+                *run_bits += eob_run_bits(len + 1) - eob_run_bits(len);
+                *run = Some(len + 1);
+                true
+            }
+            _ => {
+                *run_bits += eob_run_bits(0);
+                *run = Some(0);
+                false
+            }
+        }
+    }
+}
+
+// derives coefficient token (and EOB) counts from the coefficient tree decisions
+fn token_counts(probs: &[ProbCounter; 11], is_dc: bool) -> [u32; 12] {
+    let mut counts = [0; 12];
+    if is_dc {
+        counts[0] = probs[0].zeroes;
+    } else {
+        counts[0] = probs[1].ones();
+        counts[11] = probs[1].zeroes;
+    }
+    counts[1] = probs[2].zeroes;
+    counts[2] = probs[4].zeroes;
+    counts[3] = probs[5].zeroes;
+    counts[4] = probs[5].ones();
+    counts[5] = probs[7].zeroes;
+    counts[6] = probs[7].ones();
+    counts[7] = probs[9].zeroes;
+    counts[8] = probs[9].ones();
+    counts[9] = probs[10].zeroes;
+    counts[10] = probs[10].ones();
+    counts
+}
+
+// derives zero run counts (run length minus one, the last one is for 9 and longer)
+fn zero_run_counts(probs: &[ProbCounter; 14]) -> [u32; 9] {
+    [
+        probs[2].zeroes,
+        probs[2].ones(),
+        probs[3].zeroes,
+        probs[3].ones(),
+        probs[6].zeroes,
+        probs[6].ones(),
+        probs[7].zeroes,
+        probs[7].ones(),
+        probs[4].ones(),
+    ]
+}
+
+fn huff_nits(counts: &[u32], huff: &VP6Huff) -> u32 {
+    counts
+        .iter()
+        .zip(huff.bits.iter())
+        .fold(0, |acc, (&count, &bits)| acc + count * u32::from(bits) * 8)
 }
 
 #[derive(Default)]
@@ -391,6 +589,76 @@ impl VP56ModelsStat {
         VPMBType::GoldenNearest => 8,
         VPMBType::GoldenNear    => 9,
     */
+    /// Estimates coefficient data size (in nits) for bool coding with the provided models.
+    ///
+    /// Signs and large coefficient bits cost about the same in both modes and are not included.
+    pub fn estimate_coeff_nits(&self, models: &VP56Models) -> u32 {
+        let mut nits = 0;
+        for (smdl, dmdl) in self.coeff_models.iter().zip(models.coeff_models.iter()) {
+            for (sp, &dp) in smdl.dc_value_probs.iter().zip(dmdl.dc_value_probs.iter()) {
+                nits += sp.est_nits(dp);
+            }
+            for (sp, dp) in smdl.ac_val_probs.iter().zip(dmdl.ac_val_probs.iter()) {
+                for (sp, dp) in sp.iter().zip(dp.iter()) {
+                    for (sp, &dp) in sp.iter().zip(dp.iter()) {
+                        nits += sp.est_nits(dp);
+                    }
+                }
+            }
+        }
+        for (sp, dp) in self
+            .vp6models
+            .zero_run_probs
+            .iter()
+            .zip(models.vp6models.zero_run_probs.iter())
+        {
+            for (sp, &dp) in sp.iter().zip(dp.iter()) {
+                nits += sp.est_nits(dp);
+            }
+        }
+        nits
+    }
+    /// Estimates coefficient data size (in nits) for Huffman coding with the provided models.
+    pub fn estimate_coeff_huff_nits(&self, models: &VP56Models) -> u32 {
+        let mut nits = 0;
+        let mut huff = VP6Huff::default();
+        for (smdl, dmdl) in self.coeff_models.iter().zip(models.coeff_models.iter()) {
+            let runs = &smdl.huff_runs;
+
+            huff.build_codes(&dmdl.dc_value_probs);
+            nits += huff_nits(&token_counts(&smdl.dc_value_probs, true), &huff);
+            nits -= runs.dc_skipped * u32::from(huff.bits[0]) * 8;
+
+            for (mode, (sp, dp)) in smdl
+                .ac_val_probs
+                .iter()
+                .zip(dmdl.ac_val_probs.iter())
+                .enumerate()
+            {
+                for (band, sp) in sp.iter().enumerate() {
+                    // Huffman coding uses only the first four bands
+                    huff.build_codes(&dp[band.min(3)]);
+                    nits += huff_nits(&token_counts(sp, false), &huff);
+                    if band == VP6_IDX_TO_AC_BAND[1] {
+                        nits -= runs.eob_skipped[mode] * u32::from(huff.bits[11]) * 8;
+                    }
+                }
+            }
+            nits += runs.run_bits * 8;
+        }
+        for (sp, dp) in self
+            .vp6models
+            .zero_run_probs
+            .iter()
+            .zip(models.vp6models.zero_run_probs.iter())
+        {
+            huff.build_codes_zero_run(dp);
+            let counts = zero_run_counts(sp);
+            nits += huff_nits(&counts, &huff);
+            nits += counts[8] * 6 * 8;
+        }
+        nits
+    }
     fn generate_prob_xmitted(probs: &mut [u8; 20], mbtype: &[[usize; 10]; 10]) {
         let mut sums = [0; 20];
         let mut total = 0;
@@ -1,4 +1,116 @@
 use super::rdo::*;
+use nihav_core::codecs::{EncoderError, EncoderResult};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// First line of the first pass statistics file (followed by frame size in macroblocks).
+const STATS_HEADER: &str = "# VP6 first pass statistics";
+/// Quantiser used for frames in the first pass.
+pub const FIRST_PASS_QUANT: usize = 42;
+
+/// Quantiser offset for keyframes in constant quality mode.
+const CQ_INTRA_OFFSET: isize = 2;
+/// Quantiser offset for static frames in constant quality mode.
+const CQ_STATIC_OFFSET: isize = 2;
+/// Maximum quantiser offset for high-motion frames in constant quality mode.
+const CQ_MAX_MOTION_OFFSET: isize = 6;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RCPass {
+    #[default]
+    Single,
+    First,
+    Second,
+}
+
+/// Coding decision for a frame that does not fit into the bit budget.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameAction {
+    /// Code the frame normally.
+    Code,
+    /// Code the frame as an exact copy of the previous one.
+    Skip,
+    /// Do not output the frame at all.
+    Drop,
+}
+
+/// Per-frame statistics collected during the first pass.
+#[derive(Clone, Copy, Default)]
+pub struct FrameStats {
+    pub is_intra: bool,
+    pub quant: usize,
+    pub bits: usize,
+    pub intra_cost: u32,
+    pub inter_cost: u32,
+    pub motion: u32,
+}
+
+impl FrameStats {
+    fn write(&self, dst: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(
+            dst,
+            "{} {} {} {} {} {}",
+            if self.is_intra { 'I' } else { 'P' },
+            self.quant,
+            self.bits,
+            self.intra_cost,
+            self.inter_cost,
+            self.motion
+        )
+    }
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let is_intra = match fields.next()? {
+            "I" => true,
+            "P" => false,
+            _ => return None,
+        };
+        let quant = fields.next()?.parse::<usize>().ok()?;
+        if quant > 63 {
+            return None;
+        }
+        Some(Self {
+            is_intra,
+            quant,
+            bits: fields.next()?.parse().ok()?,
+            intra_cost: fields.next()?.parse().ok()?,
+            inter_cost: fields.next()?.parse().ok()?,
+            motion: fields.next()?.parse().ok()?,
+        })
+    }
+    /// Predicts frame size for the provided frame type and quantiser.
+    ///
+    /// Size of an inter frame coded as a keyframe is derived from the ratio
+    /// of its estimated intra and inter coding costs.
+    fn predict_bits(
+        &self,
+        intra: bool,
+        huffman: bool,
+        q: usize,
+        mb_w: usize,
+        mb_h: usize,
+    ) -> Option<usize> {
+        let bits = match (self.is_intra, intra) {
+            (true, true) | (false, false) => self.bits,
+            (false, true) if self.inter_cost > 0 => {
+                (self.bits as u64 * u64::from(self.intra_cost) / u64::from(self.inter_cost))
+                    as usize
+            }
+            _ => return None,
+        };
+        let ref_size = estimate_frame_size(intra, huffman, self.quant, mb_w, mb_h);
+        let new_size = estimate_frame_size(intra, huffman, q, mb_w, mb_h);
+        Some(bits * new_size / ref_size.max(1))
+    }
+    /// Returns how much coarser the frame may be quantised since fast motion masks coding artefacts.
+    fn motion_offset(&self, mb_w: usize, mb_h: usize) -> usize {
+        if self.is_intra {
+            return 0;
+        }
+        let motion = self.motion / ((mb_w * mb_h) as u32).max(1);
+        ((motion / 8) as usize).min(CQ_MAX_MOTION_OFFSET as usize)
+    }
+}
 
 pub struct RateControl {
     pub lambda: f32,
@@ -10,6 +122,27 @@ pub struct RateControl {
     mb_w: usize,
     mb_h: usize,
     projected: usize,
+
+    pass: RCPass,
+    stats_out: Option<BufWriter<File>>,
+    stats: Vec<FrameStats>,
+    plan: Vec<usize>,
+    plan_left: usize,
+    spent: usize,
+    total_budget: usize,
+    frame_no: usize,
+
+    vbv_size: usize,
+    vbv_fullness: usize,
+    vbv_frac: u64,
+    /// Ratio between actual and estimated sizes for inter and intra frames.
+    vbv_scale: [f32; 2],
+    vbv_known: [bool; 2],
+
+    quality: u8,
+    motion: u32,
+    budget_skip: bool,
+    drop_frames: bool,
 }
 
 // todo intra/inter decision, better allocation for intra frames
@@ -25,6 +158,26 @@ impl RateControl {
             mb_w: 0,
             mb_h: 0,
             projected: 0,
+
+            pass: RCPass::Single,
+            stats_out: None,
+            stats: Vec::new(),
+            plan: Vec::new(),
+            plan_left: 0,
+            spent: 0,
+            total_budget: 0,
+            frame_no: 0,
+
+            vbv_size: 0,
+            vbv_fullness: 0,
+            vbv_frac: 0,
+            vbv_scale: [1.0; 2],
+            vbv_known: [false; 2],
+
+            quality: 0,
+            motion: 0,
+            budget_skip: false,
+            drop_frames: false,
         }
     }
     pub fn init(&mut self, mb_w: usize, mb_h: usize, bitrate: u32, ts_num: u32, ts_den: u32) {
@@ -41,8 +194,330 @@ impl RateControl {
             self.ts_num = ts_num;
             self.ts_den = ts_den;
         }
+        self.pass = RCPass::Single;
+        self.stats_out = None;
+        self.stats.clear();
+        self.plan.clear();
+        self.frame_no = 0;
+        self.vbv_size = 0;
+        self.quality = 0;
+        self.motion = 0;
+    }
+    /// Enables constant quality mode (quality 1-100, 0 disables it).
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality.min(100);
+    }
+    pub fn is_constant_quality(&self) -> bool {
+        self.quality > 0
+    }
+    /// Enables skipping frames when the budget is overspent
+    /// and selects whether such frames are dropped instead of being coded as skip frames.
+    pub fn set_frame_skip(&mut self, budget_skip: bool, drop_frames: bool) {
+        self.budget_skip = budget_skip;
+        self.drop_frames = drop_frames;
+    }
+    /// Sets average motion vector length (in quarter-pixels) for the last coded inter frame.
+    pub fn update_motion(&mut self, motion: u32) {
+        self.motion = motion;
+    }
+    /// Selects quantiser for constant quality mode.
+    ///
+    /// Keyframes and static scenes get finer quantisers since they are referenced
+    /// for longer while fast motion masks coding artefacts.
+    fn guess_quant_cq(&self, intra: bool) -> usize {
+        let base = (usize::from(self.quality) * 63 + 50) / 100;
+        let offset = if intra {
+            CQ_INTRA_OFFSET
+        } else if self.motion < 2 {
+            CQ_STATIC_OFFSET
+        } else {
+            -((self.motion / 8) as isize).min(CQ_MAX_MOTION_OFFSET)
+        };
+        ((base as isize) + offset).clamp(0, 63) as usize
+    }
+    /// Enables decoder buffer model with the provided size (in bits) and initial fullness (in %).
+    pub fn set_vbv(&mut self, size: usize, init_fullness: u8) {
+        if self.tgt_br == 0 {
+            self.vbv_size = 0;
+            return;
+        }
+        self.vbv_size = size;
+        self.vbv_fullness = size * usize::from(init_fullness.min(100)) / 100;
+        self.vbv_frac = 0;
+        self.vbv_scale = [1.0; 2];
+        self.vbv_known = [false; 2];
+    }
+    pub fn has_vbv(&self) -> bool {
+        self.vbv_size > 0
+    }
+    fn predict_size(&self, intra: bool, huffman: bool, q: usize) -> usize {
+        let est_size = estimate_frame_size(intra, huffman, q, self.mb_w, self.mb_h) * 8;
+        ((est_size as f32) * self.vbv_scale[intra as usize]) as usize
+    }
+    /// Returns the largest frame size that can be removed from the buffer
+    /// while leaving some reserve for the following frames.
+    fn vbv_max_frame_size(&self) -> usize {
+        self.vbv_fullness.saturating_sub(self.vbv_size / 10)
+    }
+    fn vbv_frame_bits(&self) -> usize {
+        ((u64::from(self.tgt_br) * u64::from(self.ts_num) + self.vbv_frac) / u64::from(self.ts_den))
+            as usize
+    }
+    /// Adjusts quantiser so that the predicted frame size fits into the buffer.
+    pub fn limit_quant(&self, intra: bool, huffman: bool, quant: usize) -> usize {
+        if self.vbv_size == 0 {
+            return quant;
+        }
+        let max_size = self.vbv_max_frame_size();
+        let mut q = quant;
+        while q > 0 && self.predict_size(intra, huffman, q) > max_size {
+            q -= 1;
+        }
+        // spend bits that would not fit into the buffer anyway
+        let min_size = (self.vbv_fullness + self.vbv_frame_bits()).saturating_sub(self.vbv_size);
+        while q < 63
+            && self.predict_size(intra, huffman, q) < min_size
+            && self.predict_size(intra, huffman, q + 1) <= max_size
+        {
+            q += 1;
+        }
+        q
+    }
+    /// Reports whether the frame coded with the provided quantiser should fit into the buffer.
+    pub fn vbv_allows(&self, intra: bool, huffman: bool, quant: usize) -> bool {
+        self.vbv_size == 0 || self.predict_size(intra, huffman, quant) <= self.vbv_max_frame_size()
+    }
+    /// Reports whether even the coarsest keyframe would underflow the buffer.
+    pub fn vbv_postpone_key(&self, huffman: bool) -> bool {
+        self.frame_no > 0 && !self.vbv_allows(true, huffman, 0)
+    }
+    /// Decides whether the inter frame should be coded, skipped or dropped.
+    pub fn frame_action(&self, huffman: bool, quant: usize) -> FrameAction {
+        let overflow = if self.vbv_size > 0 {
+            !self.vbv_allows(false, huffman, quant)
+        } else {
+            self.over_budget()
+        };
+        if overflow {
+            self.overflow_action()
+        } else {
+            FrameAction::Code
+        }
+    }
+    /// Returns the action for a frame that cannot be coded within the budget.
+    pub fn overflow_action(&self) -> FrameAction {
+        if self.drop_frames {
+            FrameAction::Drop
+        } else {
+            FrameAction::Skip
+        }
+    }
+    /// Reports whether more than half a second worth of bits has been overspent.
+    fn over_budget(&self) -> bool {
+        self.budget_skip
+            && self.tgt_br > 0
+            && self.pass != RCPass::First
+            && self.budget < -(self.tgt_br as isize) / 2
+    }
+    /// Relaxes inter frame size prediction after a frame was not coded.
+    ///
+    /// Skipped frames do not provide any feedback so otherwise the prediction would not change.
+    pub fn relax_size_model(&mut self) {
+        if self.vbv_size > 0 {
+            self.vbv_scale[0] *= 0.875;
+        }
+    }
+    /// Reports whether the coded frame fits into the buffer.
+    pub fn vbv_fits(&self, dsize: usize) -> bool {
+        self.vbv_size == 0 || dsize <= self.vbv_fullness
+    }
+    /// Reports whether the coded keyframe fits into the buffer or has to be used anyway.
+    pub fn vbv_key_fits(&self, dsize: usize) -> bool {
+        self.frame_no == 0 || self.vbv_fits(dsize)
+    }
+    /// Updates size prediction with the actual size of the coded frame.
+    pub fn update_size_model(&mut self, intra: bool, huffman: bool, quant: usize, dsize: usize) {
+        let est_size = estimate_frame_size(intra, huffman, quant, self.mb_w, self.mb_h) * 8;
+        let ratio = (dsize as f32) / (est_size.max(1) as f32);
+        let idx = intra as usize;
+        if self.vbv_known[idx] {
+            self.vbv_scale[idx] = (self.vbv_scale[idx] * 3.0 + ratio) / 4.0;
+        } else {
+            self.vbv_scale[idx] = ratio;
+            self.vbv_known[idx] = true;
+            // use it as a guess for the other frame type as well
+            if !self.vbv_known[idx ^ 1] {
+                self.vbv_scale[idx ^ 1] = ratio;
+            }
+        }
+    }
+    /// Prepares first pass statistics output or loads statistics for the second pass.
+    pub fn start_pass(
+        &mut self,
+        pass: RCPass,
+        stats_name: &str,
+        huffman: bool,
+    ) -> EncoderResult<()> {
+        self.pass = pass;
+        match pass {
+            RCPass::Single => {}
+            RCPass::First => {
+                let file = File::create(stats_name).map_err(|_| EncoderError::InvalidParameters)?;
+                let mut writer = BufWriter::new(file);
+                writeln!(writer, "{} {}x{}", STATS_HEADER, self.mb_w, self.mb_h)
+                    .map_err(|_| EncoderError::InvalidParameters)?;
+                self.stats_out = Some(writer);
+            }
+            RCPass::Second => {
+                let file = File::open(stats_name).map_err(|_| EncoderError::InvalidParameters)?;
+                let mut lines = BufReader::new(file).lines();
+                // statistics must be collected for the same frame size
+                let header = lines
+                    .next()
+                    .and_then(|line| line.ok())
+                    .ok_or(EncoderError::InvalidParameters)?;
+                let dims = format!("{}x{}", self.mb_w, self.mb_h);
+                if header.strip_prefix(STATS_HEADER).map(str::trim) != Some(dims.as_str()) {
+                    return Err(EncoderError::InvalidParameters);
+                }
+                for line in lines {
+                    let line = line.map_err(|_| EncoderError::InvalidParameters)?;
+                    if line.starts_with('#') || line.trim().is_empty() {
+                        continue;
+                    }
+                    let fstats = FrameStats::parse(&line).ok_or(EncoderError::InvalidParameters)?;
+                    self.stats.push(fstats);
+                }
+                if self.stats.is_empty() {
+                    return Err(EncoderError::InvalidParameters);
+                }
+                self.plan_frames(huffman);
+            }
+        }
+        Ok(())
+    }
+    pub fn is_first_pass(&self) -> bool {
+        self.pass == RCPass::First
+    }
+    /// Stores statistics for the coded frame (in the first pass only).
+    pub fn add_stats(&mut self, fstats: &FrameStats) -> EncoderResult<()> {
+        if let Some(ref mut writer) = self.stats_out {
+            fstats.write(writer).map_err(|_| EncoderError::Bug)?;
+        }
+        Ok(())
+    }
+    /// Reports an error if the second pass gets more frames than there were in the first pass.
+    pub fn check_frame(&self) -> EncoderResult<()> {
+        if self.pass == RCPass::Second && self.frame_no >= self.stats.len() {
+            return Err(EncoderError::InvalidParameters);
+        }
+        Ok(())
+    }
+    /// Writes out the first pass statistics or checks that the second pass has coded all frames.
+    pub fn flush_stats(&mut self) -> EncoderResult<()> {
+        if let Some(ref mut writer) = self.stats_out {
+            writer.flush().map_err(|_| EncoderError::Bug)?;
+        }
+        if self.pass == RCPass::Second && self.frame_no != self.stats.len() {
+            return Err(EncoderError::InvalidParameters);
+        }
+        Ok(())
+    }
+    /// Distributes the total bit budget between frames.
+    ///
+    /// The quantiser that fits all frames into the budget is found first
+    /// (with high-motion frames quantised coarser as in constant quality mode)
+    /// and then the frame sizes predicted for it are scaled to match the budget exactly.
+    fn plan_frames(&mut self, huffman: bool) {
+        self.plan.clear();
+        self.spent = 0;
+        self.plan_left = 0;
+        if self.tgt_br == 0 {
+            return;
+        }
+        let nframes = self.stats.len() as u64;
+        self.total_budget = (u64::from(self.tgt_br) * nframes * u64::from(self.ts_num)
+            / u64::from(self.ts_den)) as usize;
+
+        let (mb_w, mb_h) = (self.mb_w, self.mb_h);
+        let frame_bits = |fs: &FrameStats, q: usize| {
+            let q = q.saturating_sub(fs.motion_offset(mb_w, mb_h));
+            fs.predict_bits(fs.is_intra, huffman, q, mb_w, mb_h)
+                .unwrap_or_default()
+        };
+        let mut best_q = 0;
+        let mut best_diff = usize::MAX;
+        for q in 0..64 {
+            let total: usize = self.stats.iter().map(|fs| frame_bits(fs, q)).sum();
+            let diff = total.abs_diff(self.total_budget);
+            if diff < best_diff {
+                best_diff = diff;
+                best_q = q;
+            }
+        }
+        let sizes: Vec<usize> = self
+            .stats
+            .iter()
+            .map(|fs| frame_bits(fs, best_q).max(1))
+            .collect();
+        let total = sizes.iter().sum::<usize>() as u64;
+        for &size in sizes.iter() {
+            let tgt = (size as u64) * (self.total_budget as u64) / total;
+            self.plan.push(tgt as usize);
+        }
+        self.plan_left = self.plan.iter().sum();
+    }
+    /// Selects quantiser according to the frame size planned in the second pass.
+    fn guess_quant_2pass(&mut self, intra: bool, huffman: bool) -> Option<usize> {
+        let fstats = self.stats.get(self.frame_no)?;
+        let mut planned = *self.plan.get(self.frame_no)?;
+        if fstats.is_intra != intra {
+            // keyframe placed only in the second pass takes more bits
+            let bits = fstats.predict_bits(intra, huffman, fstats.quant, self.mb_w, self.mb_h)?;
+            planned = ((planned as u64) * (bits as u64) / (fstats.bits.max(1) as u64)) as usize;
+        }
+        if self.plan_left == 0 {
+            return None;
+        }
+        // compensate for the difference between planned and actual sizes of the previous frames
+        let budget_left = self.total_budget.saturating_sub(self.spent) as u64;
+        let tgt = (planned as u64) * budget_left / (self.plan_left as u64);
+        let mut tgt = (tgt as usize).max(planned / 2).min(planned * 2);
+        if self.vbv_size > 0 {
+            tgt = tgt.min(self.vbv_max_frame_size());
+        }
+
+        let predict = |q| {
+            fstats
+                .predict_bits(intra, huffman, q, self.mb_w, self.mb_h)
+                .unwrap_or_default()
+        };
+        let mut quant = 0;
+        for q in 0..64 {
+            if predict(q) > tgt {
+                break;
+            }
+            quant = q;
+        }
+        self.projected = predict(quant);
+        Some(quant)
     }
     pub fn guess_quant(&mut self, intra: bool, huffman: bool) -> usize {
+        let quant = match self.pass {
+            RCPass::First => return FIRST_PASS_QUANT,
+            _ if self.quality > 0 => self.guess_quant_cq(intra),
+            RCPass::Single => self.guess_quant_1pass(intra, huffman),
+            RCPass::Second => {
+                if let Some(quant) = self.guess_quant_2pass(intra, huffman) {
+                    quant
+                } else {
+                    self.guess_quant_1pass(intra, huffman)
+                }
+            }
+        };
+        self.limit_quant(intra, huffman, quant)
+    }
+    fn guess_quant_1pass(&mut self, intra: bool, huffman: bool) -> usize {
         let fsize = self.get_target_frame_size(intra);
         self.projected = fsize;
         if fsize > 0 {
@@ -63,13 +538,30 @@ impl RateControl {
     pub fn update(&mut self, dsize: usize) {
         const LAMBDA_STEP: f32 = 1.0 / 32.0;
 
+        if self.pass == RCPass::Second {
+            if let Some(&planned) = self.plan.get(self.frame_no) {
+                self.plan_left -= planned;
+            }
+            self.spent += dsize;
+        }
+        self.frame_no += 1;
         if self.tgt_br == 0 {
             return;
         }
-        if (self.projected > dsize + dsize / 10) && self.lambda > LAMBDA_STEP {
-            self.lambda -= LAMBDA_STEP;
-        } else if self.projected < dsize - dsize / 10 {
-            self.lambda += LAMBDA_STEP;
+        // lambda is kept fixed in constant quality mode and for dropped frames
+        if self.quality == 0 && dsize > 0 {
+            if (self.projected > dsize + dsize / 10) && self.lambda > LAMBDA_STEP {
+                self.lambda -= LAMBDA_STEP;
+            } else if self.projected < dsize - dsize / 10 {
+                self.lambda += LAMBDA_STEP;
+            }
+        }
+        if self.vbv_size > 0 {
+            let arrival = u64::from(self.tgt_br) * u64::from(self.ts_num) + self.vbv_frac;
+            self.vbv_frac = arrival % u64::from(self.ts_den);
+            self.vbv_fullness = self.vbv_fullness.saturating_sub(dsize)
+                + (arrival / u64::from(self.ts_den)) as usize;
+            self.vbv_fullness = self.vbv_fullness.min(self.vbv_size);
         }
         self.budget -= dsize as isize;
         self.cur_time += self.ts_num;
@@ -95,3 +587,46 @@ impl RateControl {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_2pass_plan() {
+        let mut rc = RateControl::new();
+        rc.init(8, 6, 100000, 1, 25);
+        let still = FrameStats {
+            is_intra: false,
+            quant: FIRST_PASS_QUANT,
+            bits: 4000,
+            intra_cost: 8000,
+            inter_cost: 2000,
+            motion: 0,
+        };
+        let moving = FrameStats {
+            motion: 8 * 6 * 64,
+            ..still
+        };
+        let key = FrameStats {
+            is_intra: true,
+            bits: 20000,
+            inter_cost: 0,
+            ..still
+        };
+        rc.stats = vec![key, still, moving, still];
+        rc.pass = RCPass::Second;
+        rc.plan_frames(false);
+        assert_eq!(rc.plan.len(), rc.stats.len());
+        // fast motion masks coding artefacts
+        assert!(rc.plan[2] < rc.plan[1]);
+
+        // inter frame may be coded as keyframe but not vice versa
+        assert!(rc.guess_quant_2pass(false, false).is_none());
+        rc.frame_no = 1;
+        rc.guess_quant_2pass(false, false).unwrap();
+        let inter_size = rc.projected;
+        rc.guess_quant_2pass(true, false).unwrap();
+        assert!(rc.projected > inter_size * 2);
+    }
+}
@@ -0,0 +1,386 @@
+use super::super::vp6data::*;
+use super::coder::*;
+use super::models::*;
+
+const NUM_TOKENS: usize = 11;
+const INVALID_COST: u64 = u64::MAX;
+
+// rate weight relative to the squared quantiser step (in 1/64)
+const LAMBDA_SCALE: u64 = 2;
+
+/// Coefficient coding costs (in nits) derived from the frame probabilities.
+pub struct TokenCosts {
+    huffman: bool,
+    tokens: [[[[u32; NUM_TOKENS]; 6]; 3]; 2],
+    nz_tokens: [[[u32; NUM_TOKENS]; 6]; 2],
+    eob: [[[u32; 6]; 3]; 2],
+    zero_run: [[u32; 64]; 2],
+}
+
+impl TokenCosts {
+    pub fn new(models: &VP56Models, huffman: bool) -> Self {
+        let mut costs = Self {
+            huffman,
+            tokens: [[[[0; NUM_TOKENS]; 6]; 3]; 2],
+            nz_tokens: [[[0; NUM_TOKENS]; 6]; 2],
+            eob: [[[0; 6]; 3]; 2],
+            zero_run: [[0; 64]; 2],
+        };
+        if !huffman {
+            for plane in 0..2 {
+                for mode in 0..3 {
+                    for band in 0..6 {
+                        let probs = &models.coeff_models[plane].ac_val_probs[mode][band];
+                        for (token, cost) in costs.tokens[plane][mode][band].iter_mut().enumerate()
+                        {
+                            *cost = coef_token_nits(token, true, probs);
+                        }
+                        costs.eob[plane][mode][band] = eob_nits(probs);
+                        if mode == 0 {
+                            for (token, cost) in
+                                costs.nz_tokens[plane][band].iter_mut().enumerate().skip(1)
+                            {
+                                *cost = coef_token_nits(token, false, probs);
+                            }
+                        }
+                    }
+                }
+            }
+            for (costs, probs) in costs
+                .zero_run
+                .iter_mut()
+                .zip(models.vp6models.zero_run_probs.iter())
+            {
+                for (run, cost) in costs.iter_mut().enumerate().skip(1) {
+                    *cost = zero_run_nits(run, probs);
+                }
+            }
+        } else {
+            let mut huff = VP6Huff::default();
+            for plane in 0..2 {
+                for mode in 0..3 {
+                    for band in 0..6 {
+                        // Huffman coding uses only the first four bands
+                        huff.build_codes(
+                            &models.coeff_models[plane].ac_val_probs[mode][band.min(3)],
+                        );
+                        for (cost, &bits) in costs.tokens[plane][mode][band]
+                            .iter_mut()
+                            .zip(huff.bits.iter())
+                        {
+                            *cost = u32::from(bits) * 8;
+                        }
+                        costs.eob[plane][mode][band] = u32::from(huff.bits[11]) * 8;
+                    }
+                }
+                costs.nz_tokens[plane] = costs.tokens[plane][0];
+            }
+            for (costs, probs) in costs
+                .zero_run
+                .iter_mut()
+                .zip(models.vp6models.zero_run_probs.iter())
+            {
+                huff.build_codes_zero_run(probs);
+                for (run, cost) in costs.iter_mut().enumerate().skip(1) {
+                    // Huffman mode codes the number of zeroes after the zero token
+                    let hrun = run - 1;
+                    let add_bits = if hrun >= 8 { 6 } else { 0 };
+                    *cost = (u32::from(huff.bits[hrun.min(8)]) + add_bits) * 8;
+                }
+            }
+        }
+        costs
+    }
+    fn level_nits(&self, aval: i16, tokens: &[u32; NUM_TOKENS]) -> u32 {
+        let token = match aval {
+            1..=4 => aval as usize,
+            5..=6 => 5,
+            7..=10 => 6,
+            11..=18 => 7,
+            19..=34 => 8,
+            35..=66 => 9,
+            _ => 10,
+        };
+        let mut nits = tokens[token] + u32::from(PROB_BITS[128]);
+        if token >= 5 {
+            let cat = token - 5;
+            nits += if !self.huffman {
+                large_coef_nits(aval, cat)
+            } else {
+                u32::from(VP6_COEF_ADD_BITS[cat]) * 8
+            };
+        }
+        nits
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TrellisNode {
+    cost: u64,
+    prev_idx: usize,
+    prev_ctx: usize,
+    level: i16,
+}
+
+impl Default for TrellisNode {
+    fn default() -> Self {
+        Self {
+            cost: INVALID_COST,
+            prev_idx: 0,
+            prev_ctx: 0,
+            level: 0,
+        }
+    }
+}
+
+impl TrellisNode {
+    fn update(&mut self, cost: u64, prev_idx: usize, prev_ctx: usize, level: i16) {
+        if cost < self.cost {
+            *self = Self {
+                cost,
+                prev_idx,
+                prev_ctx,
+                level,
+            };
+        }
+    }
+}
+
+fn level_ctx(level: i16) -> usize {
+    level.unsigned_abs().min(2) as usize
+}
+
+/// Selects AC coefficient levels minimising distortion plus weighted coding cost.
+///
+/// `blk` contains quantised coefficients with already predicted DC, `dct` is the source block
+/// transform and `scan` maps coefficient index to its position in the block.
+pub fn trellis_quant_block(
+    blk: &mut [i16; 64],
+    dct: &[i16; 64],
+    qstep: i16,
+    plane: usize,
+    scan: &[usize; 64],
+    costs: &TokenCosts,
+) {
+    let lambda = (qstep as u64) * (qstep as u64) * LAMBDA_SCALE / 64;
+    let qstep = i32::from(qstep);
+
+    let mut zero_dist = [0u64; 64];
+    let mut levels = [[0i16; 2]; 64];
+    let mut dists = [[0u64; 2]; 64];
+    for idx in 1..64 {
+        let coef = i32::from(dct[scan[idx]]);
+        let aval = coef.abs();
+        zero_dist[idx] = (aval * aval) as u64;
+        let hi_level = (aval + qstep / 2) / qstep;
+        for (i, level) in [hi_level, hi_level - 1].iter().enumerate() {
+            if *level > 0 {
+                let diff = aval - level * qstep;
+                levels[idx][i] = if coef > 0 { *level } else { -*level } as i16;
+                dists[idx][i] = (diff * diff) as u64;
+            }
+        }
+    }
+    // cumulative distortion of zeroed coefficients
+    let mut zero_sum = [0u64; 64];
+    for idx in 1..64 {
+        zero_sum[idx] = zero_sum[idx - 1] + zero_dist[idx];
+    }
+
+    // context is the last coded value class, zero context after the DC means zero run mode
+    let mut nodes = [[TrellisNode::default(); 3]; 64];
+    nodes[0][level_ctx(blk[0])].cost = 0;
+    let mut best_cost = INVALID_COST;
+    let mut best_end = (0, level_ctx(blk[0]));
+
+    for idx in 0..64 {
+        for ctx in 0..3 {
+            let cost = nodes[idx][ctx].cost;
+            if cost == INVALID_COST {
+                continue;
+            }
+            if idx == 0 || ctx != 0 {
+                if idx == 63 {
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best_end = (idx, ctx);
+                    }
+                    continue;
+                }
+                let next = idx + 1;
+                let band = VP6_IDX_TO_AC_BAND[next];
+                let eob_cost = cost
+                    + lambda * u64::from(costs.eob[plane][ctx][band])
+                    + (zero_sum[63] - zero_sum[idx]);
+                if eob_cost < best_cost {
+                    best_cost = eob_cost;
+                    best_end = (idx, ctx);
+                }
+                let tokens = &costs.tokens[plane][ctx][band];
+                nodes[next][0].update(
+                    cost + lambda * u64::from(tokens[0]) + zero_dist[next],
+                    idx,
+                    ctx,
+                    0,
+                );
+                for (&level, &dist) in levels[next].iter().zip(dists[next].iter()) {
+                    if level != 0 {
+                        let nits = costs.level_nits(level.abs(), tokens);
+                        nodes[next][level_ctx(level)].update(
+                            cost + lambda * u64::from(nits) + dist,
+                            idx,
+                            ctx,
+                            level,
+                        );
+                    }
+                }
+            } else {
+                let run_ctx = if idx + 1 >= 7 { 1 } else { 0 };
+                for next in idx + 1..64 {
+                    let run_cost = cost
+                        + lambda * u64::from(costs.zero_run[run_ctx][next - idx])
+                        + (zero_sum[next - 1] - zero_sum[idx]);
+                    if run_cost >= best_cost {
+                        break;
+                    }
+                    let tokens = &costs.nz_tokens[plane][VP6_IDX_TO_AC_BAND[next]];
+                    for (&level, &dist) in levels[next].iter().zip(dists[next].iter()) {
+                        if level != 0 {
+                            let nits = costs.level_nits(level.abs(), tokens);
+                            nodes[next][level_ctx(level)].update(
+                                run_cost + lambda * u64::from(nits) + dist,
+                                idx,
+                                ctx,
+                                level,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for &pos in scan[1..].iter() {
+        blk[pos] = 0;
+    }
+    let (mut idx, mut ctx) = best_end;
+    while idx > 0 {
+        let node = nodes[idx][ctx];
+        blk[scan[idx]] = node.level;
+        idx = node.prev_idx;
+        ctx = node.prev_ctx;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // distortion plus weighted cost of coding the block the way trellis models it
+    fn block_cost(
+        blk: &[i16; 64],
+        dct: &[i16; 64],
+        qstep: i16,
+        plane: usize,
+        scan: &[usize; 64],
+        costs: &TokenCosts,
+    ) -> u64 {
+        let lambda = (qstep as u64) * (qstep as u64) * LAMBDA_SCALE / 64;
+        let mut dist = 0;
+        for idx in 1..64 {
+            let diff = i32::from(dct[scan[idx]].abs())
+                - i32::from(blk[scan[idx]].abs()) * i32::from(qstep);
+            dist += (diff * diff) as u64;
+        }
+        let last = (1..64).rev().find(|&idx| blk[scan[idx]] != 0).unwrap_or(0);
+        let mut nits = 0;
+        let mut idx = 0;
+        let mut ctx = level_ctx(blk[0]);
+        while idx < last {
+            if idx == 0 || ctx != 0 {
+                let next = idx + 1;
+                let tokens = &costs.tokens[plane][ctx][VP6_IDX_TO_AC_BAND[next]];
+                let level = blk[scan[next]];
+                nits += if level == 0 {
+                    tokens[0]
+                } else {
+                    costs.level_nits(level.abs(), tokens)
+                };
+                ctx = level_ctx(level);
+                idx = next;
+            } else {
+                let next = (idx + 1..64).find(|&i| blk[scan[i]] != 0).unwrap();
+                let run_ctx = if idx + 1 >= 7 { 1 } else { 0 };
+                let tokens = &costs.nz_tokens[plane][VP6_IDX_TO_AC_BAND[next]];
+                nits += costs.zero_run[run_ctx][next - idx];
+                nits += costs.level_nits(blk[scan[next]].abs(), tokens);
+                ctx = level_ctx(blk[scan[next]]);
+                idx = next;
+            }
+        }
+        if last < 63 {
+            nits += costs.eob[plane][ctx][VP6_IDX_TO_AC_BAND[last + 1]];
+        }
+        dist + lambda * u64::from(nits)
+    }
+
+    fn quant_block(blk: &mut [i16; 64], dct: &[i16; 64], qstep: i16) {
+        for (dst, &coef) in blk.iter_mut().zip(dct.iter()).skip(1) {
+            let level = (coef.abs() + qstep / 2) / qstep;
+            *dst = if coef < 0 { -level } else { level };
+        }
+    }
+
+    #[test]
+    fn test_trellis_quant() {
+        let mut models = VP56Models::new();
+        models.reset(false);
+        let scan = models.vp6models.zigzag;
+        for &huffman in [false, true].iter() {
+            let costs = TokenCosts::new(&models, huffman);
+
+            // isolated small trailing coefficient is not worth coding
+            let qstep = 20;
+            let mut dct = [0i16; 64];
+            dct[scan[1]] = 300;
+            dct[scan[2]] = -150;
+            dct[scan[3]] = 80;
+            dct[scan[45]] = 12;
+            let mut blk = [0i16; 64];
+            blk[0] = 5;
+            quant_block(&mut blk, &dct, qstep);
+            assert_eq!(blk[scan[45]], 1);
+            let plain_cost = block_cost(&blk, &dct, qstep, 0, &scan, &costs);
+            trellis_quant_block(&mut blk, &dct, qstep, 0, &scan, &costs);
+            assert_eq!(blk[0], 5);
+            assert_eq!(blk[scan[45]], 0);
+            assert_ne!(blk[scan[3]], 0);
+            assert!(block_cost(&blk, &dct, qstep, 0, &scan, &costs) < plain_cost);
+
+            let mut seed = 0x1357_9BDFu32;
+            let mut rnd = || {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed
+            };
+            for i in 0..1024 {
+                let qstep = (rnd() % 120 + 8) as i16;
+                let plane = i & 1;
+                let mut dct = [0i16; 64];
+                // mix sparse and dense blocks with decaying magnitudes
+                let ncoeffs = (rnd() % 64) as usize;
+                for &pos in scan[1..].iter().take(ncoeffs) {
+                    let range = (rnd() % 1024 + 1) as i32;
+                    dct[pos] = ((rnd() as i32) % range) as i16;
+                }
+                let mut blk = [0i16; 64];
+                blk[0] = (rnd() % 5) as i16 - 2;
+                quant_block(&mut blk, &dct, qstep);
+                let plain_cost = block_cost(&blk, &dct, qstep, plane, &scan, &costs);
+                trellis_quant_block(&mut blk, &dct, qstep, plane, &scan, &costs);
+                assert!(block_cost(&blk, &dct, qstep, plane, &scan, &costs) <= plain_cost);
+            }
+        }
+    }
+}
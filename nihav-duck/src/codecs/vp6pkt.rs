@@ -0,0 +1,400 @@
+//! Packetiser for raw VP6 streams.
+//!
+//! Two kinds of streams are supported: length-prefixed ones where every frame is
+//! preceded by its size as 32-bit little-endian number and headerless ones.
+//! Frame boundaries in the latter cannot be found from the data so every chunk
+//! passed to `add_data()` must contain exactly one frame. Chunks that do not
+//! start with a valid frame header are rejected as invalid data.
+use super::vp6dec::{parse_frame_header, VP6FrameHeader, VP6HeaderParser};
+use super::vpcommon::VP_YUVA420_FORMAT;
+use nihav_core::codecs::*;
+use nihav_core::io::byteio::read_u32le;
+
+const MAX_FRAME_SIZE: usize = 1 << 22;
+const MAX_BUF_SIZE: usize = 1 << 24;
+
+// raw streams carry no timing information
+const DEFAULT_TB_NUM: u32 = 1;
+const DEFAULT_TB_DEN: u32 = 25;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Framing {
+    Unknown,
+    LengthPrefixed,
+    Headerless,
+}
+
+struct VP6Packetiser {
+    buf: Vec<u8>,
+    chunks: Vec<usize>,
+    framing: Framing,
+    flipped: bool,
+    has_alpha: bool,
+    frameno: u64,
+    parser: VP6HeaderParser,
+}
+
+impl VP6Packetiser {
+    fn new(flipped: bool, has_alpha: bool) -> Self {
+        Self {
+            buf: Vec::new(),
+            chunks: Vec::new(),
+            framing: Framing::Unknown,
+            flipped,
+            has_alpha,
+            frameno: 0,
+            parser: VP6HeaderParser::new(has_alpha),
+        }
+    }
+    fn get_name(&self) -> &'static str {
+        match (self.has_alpha, self.flipped) {
+            (true, _) => "vp6a",
+            (false, true) => "vp6",
+            (false, false) => "vp6f",
+        }
+    }
+    /// Parses keyframe header of the stream kind handled by this packetiser.
+    fn check_keyframe(&self, src: &[u8]) -> Option<VP6FrameHeader> {
+        let is_keyframe = |has_alpha| {
+            parse_frame_header(src, has_alpha)
+                .ok()
+                .filter(|hdr| hdr.main.is_intra)
+        };
+        let hdr = is_keyframe(self.has_alpha)?;
+        if !self.has_alpha && is_keyframe(true).is_some() {
+            return None;
+        }
+        Some(hdr)
+    }
+    /// Quickly checks whether the data may start with a keyframe.
+    fn may_be_keyframe(&self, src: &[u8]) -> bool {
+        let src = if self.has_alpha {
+            src.get(3..).unwrap_or(&[])
+        } else {
+            src
+        };
+        if src.len() < 2 || (src[0] & 0x80) != 0 {
+            return false;
+        }
+        let version = src[1] >> 3;
+        let profile = (src[1] >> 1) & 3;
+        (6..=8).contains(&version) && (profile == 0 || profile == 3)
+    }
+    fn get_frame_size(&self, pos: usize) -> Option<usize> {
+        let size = read_u32le(self.buf.get(pos..)?).ok()? as usize;
+        if size <= MAX_FRAME_SIZE {
+            Some(size)
+        } else {
+            None
+        }
+    }
+    /// Discards data from the buffer along with the chunks it belonged to.
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+        let mut left = len;
+        while left > 0 && !self.chunks.is_empty() {
+            if self.chunks[0] <= left {
+                left -= self.chunks.remove(0);
+            } else {
+                self.chunks[0] -= left;
+                left = 0;
+            }
+        }
+    }
+    fn detect_framing(&mut self) -> DecoderResult<VP6FrameHeader> {
+        if self.buf.len() < 4 {
+            return Err(DecoderError::ShortData);
+        }
+        let size = self.get_frame_size(0);
+        if let Some(size) = size {
+            if size > 0 && self.buf.len() >= size + 4 {
+                if let Some(hdr) = self.check_keyframe(&self.buf[4..][..size]) {
+                    self.framing = Framing::LengthPrefixed;
+                    self.chunks.clear();
+                    return Ok(hdr);
+                }
+            }
+        }
+        let first_len = self.chunks.first().copied().unwrap_or(self.buf.len());
+        if let Some(hdr) = self.check_keyframe(&self.buf[..first_len]) {
+            self.framing = Framing::Headerless;
+            return Ok(hdr);
+        }
+        match size {
+            Some(size) if size > 0 && self.may_be_keyframe(&self.buf[4..]) => {
+                Err(DecoderError::ShortData)
+            }
+            _ => Err(DecoderError::InvalidData),
+        }
+    }
+}
+
+impl NAPacketiser for VP6Packetiser {
+    fn add_data(&mut self, src: &[u8]) -> bool {
+        if self.framing != Framing::LengthPrefixed && !src.is_empty() {
+            self.chunks.push(src.len());
+        }
+        self.buf.extend_from_slice(src);
+        self.buf.len() < MAX_BUF_SIZE
+    }
+    fn parse_stream(&mut self, id: u32) -> DecoderResult<NAStreamRef> {
+        // framing is detected on keyframes only
+        let hdr = self.detect_framing()?.main.kf_params.unwrap();
+        let width = usize::from(hdr.disp_w) * 16;
+        let height = usize::from(hdr.disp_h) * 16;
+        let fmt = if self.has_alpha {
+            VP_YUVA420_FORMAT
+        } else {
+            YUV420_FORMAT
+        };
+        let vinfo = NAVideoInfo::new(width, height, self.flipped, fmt);
+        let info = NACodecInfo::new(self.get_name(), NACodecTypeInfo::Video(vinfo), None);
+        Ok(NAStream::new(
+            StreamType::Video,
+            id,
+            info,
+            DEFAULT_TB_NUM,
+            DEFAULT_TB_DEN,
+            0,
+        )
+        .into_ref())
+    }
+    fn skip_junk(&mut self) -> DecoderResult<usize> {
+        if self.framing != Framing::LengthPrefixed {
+            let mut skipped = 0;
+            for &len in self.chunks.iter() {
+                if self.check_keyframe(&self.buf[skipped..][..len]).is_some() {
+                    self.consume(skipped);
+                    return Ok(skipped);
+                }
+                skipped += len;
+            }
+            if self.framing == Framing::Headerless {
+                self.consume(skipped);
+                return Err(DecoderError::ShortData);
+            }
+        }
+        let mut pos = 0;
+        while pos + 4 <= self.buf.len() {
+            if let Some(size) = self.get_frame_size(pos) {
+                if size > 0 && self.may_be_keyframe(&self.buf[pos + 4..]) {
+                    if pos + 4 + size > self.buf.len() {
+                        self.consume(pos);
+                        return Err(DecoderError::ShortData);
+                    }
+                    if self.check_keyframe(&self.buf[pos + 4..][..size]).is_some() {
+                        self.consume(pos);
+                        return Ok(pos);
+                    }
+                }
+            }
+            pos += 1;
+        }
+        // keep the possible start of the frame size only if the framing is known
+        if self.framing == Framing::Unknown {
+            pos = self.buf.len();
+        }
+        self.consume(pos);
+        Err(DecoderError::ShortData)
+    }
+    fn get_packet(&mut self, stream: NAStreamRef) -> DecoderResult<Option<NAPacket>> {
+        if self.framing == Framing::Unknown {
+            self.detect_framing()?;
+        }
+        let data = loop {
+            let data = match self.framing {
+                Framing::LengthPrefixed => {
+                    if self.buf.len() < 4 {
+                        return Err(DecoderError::ShortData);
+                    }
+                    let size = self.get_frame_size(0).ok_or(DecoderError::InvalidData)?;
+                    if self.buf.len() < size + 4 {
+                        return Err(DecoderError::ShortData);
+                    }
+                    let data = self.buf[4..][..size].to_vec();
+                    self.consume(size + 4);
+                    data
+                }
+                _ => {
+                    if self.chunks.is_empty() {
+                        return Err(DecoderError::ShortData);
+                    }
+                    let len = self.chunks.remove(0);
+                    let data: Vec<u8> = self.buf.drain(..len).collect();
+                    // the chunk has to be a whole frame
+                    if !data.is_empty() && self.parser.parse_frame_header(&data).is_err() {
+                        return Err(DecoderError::InvalidData);
+                    }
+                    data
+                }
+            };
+            self.frameno += 1;
+            // empty frames are dropped ones
+            if !data.is_empty() {
+                break data;
+            }
+        };
+        let frameno = self.frameno - 1;
+        let keyframe = if self.has_alpha {
+            data.len() > 3 && (data[3] & 0x80) == 0
+        } else {
+            (data[0] & 0x80) == 0
+        };
+        let (tb_num, tb_den) = stream.get_timebase();
+        let ts = NATimeInfo::new(Some(frameno), None, Some(1), tb_num, tb_den);
+        Ok(Some(NAPacket::new(stream, ts, keyframe, data)))
+    }
+    fn reset(&mut self) {
+        self.buf.clear();
+        self.chunks.clear();
+        self.framing = Framing::Unknown;
+        self.frameno = 0;
+        self.parser = VP6HeaderParser::new(self.has_alpha);
+    }
+}
+
+pub fn get_packetiser_vp6() -> Box<dyn NAPacketiser + Send> {
+    Box::new(VP6Packetiser::new(true, false))
+}
+
+pub fn get_packetiser_vp6f() -> Box<dyn NAPacketiser + Send> {
+    Box::new(VP6Packetiser::new(false, false))
+}
+
+pub fn get_packetiser_vp6_alpha() -> Box<dyn NAPacketiser + Send> {
+    Box::new(VP6Packetiser::new(false, true))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nihav_commonfmt::generic_register_all_demuxers;
+    use nihav_core::demuxers::*;
+
+    fn read_frames(name: &str, count: usize) -> Vec<Vec<u8>> {
+        let mut dmx_reg = RegisteredDemuxers::new();
+        generic_register_all_demuxers(&mut dmx_reg);
+        let mut file = std::fs::File::open(name).unwrap();
+        let mut fr = FileReader::new_read(&mut file);
+        let mut br = ByteReader::new(&mut fr);
+        let mut dmx = create_demuxer(dmx_reg.find_demuxer("avi").unwrap(), &mut br).unwrap();
+        let mut frames = Vec::with_capacity(count);
+        while frames.len() < count {
+            let pkt = dmx.get_frame().unwrap();
+            if pkt.get_stream().get_media_type() == StreamType::Video {
+                frames.push(pkt.get_buffer().to_vec());
+            }
+        }
+        frames
+    }
+    fn add_length_prefixed(dst: &mut Vec<u8>, frames: &[Vec<u8>]) {
+        for frame in frames.iter() {
+            dst.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            dst.extend_from_slice(frame);
+        }
+    }
+    fn get_packets(pkt: &mut dyn NAPacketiser, stream: NAStreamRef, src: &[u8]) -> Vec<NAPacket> {
+        let mut pkts = Vec::new();
+        for chunk in src.chunks(1000) {
+            pkt.add_data(chunk);
+            while let Ok(Some(packet)) = pkt.get_packet(stream.clone()) {
+                pkts.push(packet);
+            }
+        }
+        pkts
+    }
+    #[test]
+    fn test_vp6_packetiser() {
+        let frames = read_frames("assets/selection_720x576_300kBit_vp60i.avi", 8);
+        let mut src = Vec::new();
+        add_length_prefixed(&mut src, &frames);
+
+        let mut pkt = get_packetiser_vp6_alpha();
+        pkt.add_data(&src);
+        assert!(pkt.parse_stream(0).is_err());
+
+        let mut pkt = get_packetiser_vp6();
+        pkt.add_data(&src[..100]);
+        assert_eq!(pkt.parse_stream(0).err(), Some(DecoderError::ShortData));
+        pkt.add_data(&src[100..]);
+        let stream = pkt.parse_stream(0).unwrap();
+        assert_eq!(stream.get_info().get_name(), "vp6");
+        let vinfo = stream.get_info().get_properties().get_video_info().unwrap();
+        assert_eq!((vinfo.get_width(), vinfo.get_height()), (720, 576));
+        assert!(vinfo.is_flipped());
+        pkt.reset();
+
+        let pkts = get_packets(pkt.as_mut(), stream.clone(), &src);
+        assert_eq!(pkts.len(), frames.len());
+        for (i, (packet, frame)) in pkts.iter().zip(frames.iter()).enumerate() {
+            assert_eq!(packet.get_buffer().as_slice(), frame.as_slice());
+            assert_eq!(packet.is_keyframe(), i == 0 || i == 5);
+            assert_eq!(packet.get_pts(), Some(i as u64));
+        }
+
+        // resynchronise on the next keyframe after garbage
+        let mut src = vec![0x55; 300];
+        add_length_prefixed(&mut src, &frames[3..]);
+        let mut pkt = get_packetiser_vp6f();
+        pkt.add_data(&src);
+        assert!(pkt.parse_stream(0).is_err());
+        let skipped = pkt.skip_junk().unwrap();
+        assert_eq!(skipped, 300 + 8 + frames[3].len() + frames[4].len());
+        let stream = pkt.parse_stream(0).unwrap();
+        assert_eq!(stream.get_info().get_name(), "vp6f");
+        let packet = pkt.get_packet(stream).unwrap().unwrap();
+        assert_eq!(packet.get_buffer().as_slice(), frames[5].as_slice());
+    }
+    #[test]
+    fn test_vp6_packetiser_headerless() {
+        let frames = read_frames("assets/vp6a.avi", 8);
+
+        let mut pkt = get_packetiser_vp6f();
+        pkt.add_data(&frames[0]);
+        assert!(pkt.parse_stream(0).is_err());
+
+        let mut pkt = get_packetiser_vp6_alpha();
+        for frame in frames[1..].iter() {
+            pkt.add_data(frame);
+        }
+        assert_eq!(pkt.skip_junk().err(), Some(DecoderError::ShortData));
+        for frame in frames.iter() {
+            pkt.add_data(frame);
+        }
+        let stream = pkt.parse_stream(0).unwrap();
+        assert_eq!(stream.get_info().get_name(), "vp6a");
+        let vinfo = stream.get_info().get_properties().get_video_info().unwrap();
+        assert!(vinfo.get_format() == VP_YUVA420_FORMAT);
+        for frame in frames.iter() {
+            let packet = pkt.get_packet(stream.clone()).unwrap().unwrap();
+            assert_eq!(packet.get_buffer().as_slice(), frame.as_slice());
+        }
+        assert_eq!(
+            pkt.get_packet(stream.clone()).err(),
+            Some(DecoderError::ShortData)
+        );
+
+        // framing is detected anew after reset
+        pkt.reset();
+        let mut src = Vec::new();
+        add_length_prefixed(&mut src, &frames);
+        let pkts = get_packets(pkt.as_mut(), stream.clone(), &src);
+        assert_eq!(pkts.len(), frames.len());
+        for (i, (packet, frame)) in pkts.iter().zip(frames.iter()).enumerate() {
+            assert_eq!(packet.get_buffer().as_slice(), frame.as_slice());
+            assert_eq!(packet.get_pts(), Some(i as u64));
+        }
+
+        // chunks not containing whole frames are rejected
+        pkt.reset();
+        pkt.add_data(&frames[0]);
+        let (head, tail) = frames[1].split_at(frames[1].len() / 2);
+        pkt.add_data(head);
+        pkt.add_data(tail);
+        assert!(pkt.get_packet(stream.clone()).unwrap().is_some());
+        assert_eq!(
+            pkt.get_packet(stream).err(),
+            Some(DecoderError::InvalidData)
+        );
+    }
+}
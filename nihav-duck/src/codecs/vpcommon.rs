@@ -158,7 +158,7 @@ pub struct BoolCoder<'a> {
 #[allow(dead_code)]
 impl<'a> BoolCoder<'a> {
     pub fn new(src: &'a [u8]) -> DecoderResult<Self> {
-        if src.len() < 3 {
+        if src.len() < 4 {
             return Err(DecoderError::ShortData);
         }
         let value = (u32::from(src[0]) << 24)
@@ -240,6 +240,10 @@ impl<'a> BoolCoder<'a> {
             }
         }*/
     }
+    /// Reports whether the decoder has read well past the end of its data.
+    pub fn is_exhausted(&self) -> bool {
+        self.pos >= self.src.len() && self.bits < -32
+    }
     pub fn skip_bytes(&mut self, nbytes: usize) {
         for _ in 0..nbytes {
             self.value <<= 8;
@@ -320,6 +324,17 @@ macro_rules! idct_step {
 }
 
 pub fn vp_idct(coeffs: &mut [i16; 64]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::vpsimd::cpu_features().avx2 {
+            unsafe { super::vpsimd::vp_idct_avx2(coeffs) };
+            return;
+        }
+    }
+    vp_idct_scalar(coeffs);
+}
+
+pub fn vp_idct_scalar(coeffs: &mut [i16; 64]) {
     let mut tmp = [0i32; 64];
     for (src, dst) in coeffs.chunks(8).zip(tmp.chunks_exact_mut(8)) {
         idct_step!(
@@ -367,6 +382,44 @@ pub fn vp_idct_dc(coeffs: &mut [i16; 64]) {
     }
 }*/
 
+fn put_pixels(coeffs: &[i16; 64], dst: &mut [u8], stride: usize) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::vpsimd::cpu_features().sse2 {
+            unsafe { super::vpsimd::put_pixels_sse2(coeffs, dst, stride) };
+            return;
+        }
+    }
+    put_pixels_scalar(coeffs, dst, stride);
+}
+
+pub fn put_pixels_scalar(coeffs: &[i16; 64], dst: &mut [u8], stride: usize) {
+    for (src, line) in coeffs.chunks_exact(8).zip(dst.chunks_mut(stride)) {
+        for (dst, &coef) in line[..8].iter_mut().zip(src.iter()) {
+            *dst = (coef + 128).min(255).max(0) as u8;
+        }
+    }
+}
+
+fn add_pixels(coeffs: &[i16; 64], dst: &mut [u8], stride: usize) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::vpsimd::cpu_features().sse2 {
+            unsafe { super::vpsimd::add_pixels_sse2(coeffs, dst, stride) };
+            return;
+        }
+    }
+    add_pixels_scalar(coeffs, dst, stride);
+}
+
+pub fn add_pixels_scalar(coeffs: &[i16; 64], dst: &mut [u8], stride: usize) {
+    for (src, line) in coeffs.chunks_exact(8).zip(dst.chunks_mut(stride)) {
+        for (dst, &coef) in line[..8].iter_mut().zip(src.iter()) {
+            *dst = (coef + i16::from(*dst)).min(255).max(0) as u8;
+        }
+    }
+}
+
 pub fn vp_put_block(
     coeffs: &mut [i16; 64],
     bx: usize,
@@ -375,13 +428,9 @@ pub fn vp_put_block(
     frm: &mut NASimpleVideoFrame<u8>,
 ) {
     vp_idct(coeffs);
-    let mut off = frm.offset[plane] + bx * 8 + by * 8 * frm.stride[plane];
-    for y in 0..8 {
-        for x in 0..8 {
-            frm.data[off + x] = (coeffs[x + y * 8] + 128).min(255).max(0) as u8;
-        }
-        off += frm.stride[plane];
-    }
+    let stride = frm.stride[plane];
+    let off = frm.offset[plane] + bx * 8 + by * 8 * stride;
+    put_pixels(coeffs, &mut frm.data[off..], stride);
 }
 
 pub fn vp_put_block_ilace(
@@ -392,13 +441,9 @@ pub fn vp_put_block_ilace(
     frm: &mut NASimpleVideoFrame<u8>,
 ) {
     vp_idct(coeffs);
-    let mut off = frm.offset[plane] + bx * 8 + ((by & !1) * 8 + (by & 1)) * frm.stride[plane];
-    for y in 0..8 {
-        for x in 0..8 {
-            frm.data[off + x] = (coeffs[x + y * 8] + 128).min(255).max(0) as u8;
-        }
-        off += frm.stride[plane] * 2;
-    }
+    let stride = frm.stride[plane];
+    let off = frm.offset[plane] + bx * 8 + ((by & !1) * 8 + (by & 1)) * stride;
+    put_pixels(coeffs, &mut frm.data[off..], stride * 2);
 }
 
 pub fn vp_put_block_dc(
@@ -428,15 +473,8 @@ pub fn vp_add_block(
 ) {
     vp_idct(coeffs);
     let stride = frm.stride[plane];
-    let mut off = frm.offset[plane] + bx * 8 + by * 8 * stride;
-
-    for y in 0..8 {
-        let frm_data = &mut frm.data[off..(off + 8)];
-        for x in 0..8 {
-            frm_data[x] = (coeffs[x + y * 8] + i16::from(frm_data[x])).min(255).max(0) as u8;
-        }
-        off += stride;
-    }
+    let off = frm.offset[plane] + bx * 8 + by * 8 * stride;
+    add_pixels(coeffs, &mut frm.data[off..], stride);
 }
 
 pub fn vp_add_block_ilace(
@@ -447,15 +485,9 @@ pub fn vp_add_block_ilace(
     frm: &mut NASimpleVideoFrame<u8>,
 ) {
     vp_idct(coeffs);
-    let mut off = frm.offset[plane] + bx * 8 + ((by & !1) * 8 + (by & 1)) * frm.stride[plane];
-    for y in 0..8 {
-        for x in 0..8 {
-            frm.data[off + x] = (coeffs[x + y * 8] + i16::from(frm.data[off + x]))
-                .min(255)
-                .max(0) as u8;
-        }
-        off += frm.stride[plane] * 2;
-    }
+    let stride = frm.stride[plane];
+    let off = frm.offset[plane] + bx * 8 + ((by & !1) * 8 + (by & 1)) * stride;
+    add_pixels(coeffs, &mut frm.data[off..], stride * 2);
 }
 
 pub fn vp_add_block_dc(
@@ -493,7 +525,7 @@ pub fn vp31_build_lf_tab(tab: &mut [i8; 256], loop_str: i16) {
 }
 
 #[inline(always)]
-fn vp31_loop_filter(
+pub fn vp31_loop_filter(
     data: &mut [u8],
     mut off: usize,
     step: usize,
@@ -506,7 +538,7 @@ fn vp31_loop_filter(
         let b = i16::from(data[off - step]);
         let c = i16::from(data[off]);
         let d = i16::from(data[off + step]);
-        let diff = ((a - d) + 3 * (c - b) + 4) >> 3;
+        let diff = (((a - d) + 3 * (c - b) + 4) >> 3).clamp(-128, 127);
         let diff = i16::from(loop_tab[(diff + 128) as usize]);
         if diff != 0 {
             data[off - step] = (b + diff).max(0).min(255) as u8;
@@ -523,6 +555,13 @@ pub fn vp31_loop_filter_step1_stride16(
     len: usize,
     loop_tab: &[i8; 256],
 ) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::vpsimd::cpu_features().sse2 && len <= 16 {
+            unsafe { super::vpsimd::vp31_loop_filter_sse2(data, off, 1, 16, len, loop_tab) };
+            return;
+        }
+    }
     vp31_loop_filter(data, off, 1, 16, len, loop_tab);
 }
 
@@ -532,5 +571,28 @@ pub fn vp31_loop_filter_step16_stride1(
     len: usize,
     loop_tab: &[i8; 256],
 ) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if super::vpsimd::cpu_features().sse2 && len <= 16 {
+            unsafe { super::vpsimd::vp31_loop_filter_sse2(data, off, 16, 1, len, loop_tab) };
+            return;
+        }
+    }
     vp31_loop_filter(data, off, 16, 1, len, loop_tab);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vp31_loop_filter_range() {
+        let mut loop_tab = [0; 256];
+        vp31_build_lf_tab(&mut loop_tab, 40);
+        // the largest possible edge difference
+        let mut data = [255, 0, 255, 0];
+        vp31_loop_filter(&mut data, 2, 1, 4, 1, &loop_tab);
+        let mut data = [0, 255, 0, 255];
+        vp31_loop_filter(&mut data, 2, 1, 4, 1, &loop_tab);
+    }
+}
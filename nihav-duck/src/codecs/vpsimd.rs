@@ -0,0 +1,754 @@
+//! x86_64 SIMD versions of VP5/VP6 DSP functions.
+//!
+//! All functions here produce the same output as their scalar counterparts.
+//! Callers must check that the CPU supports the required instruction set extension
+//! (see `cpu_features()`).
+//! Reference block fetching is left scalar since it is a plain copy of 12-byte lines.
+
+use std::arch::x86_64::*;
+use std::sync::OnceLock;
+
+/// Instruction set extensions used by the SIMD functions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CPUFeatures {
+    pub sse2: bool,
+    pub ssse3: bool,
+    pub avx2: bool,
+}
+
+/// Returns the instruction set extensions supported by the CPU (detected once).
+pub fn cpu_features() -> CPUFeatures {
+    static FEATURES: OnceLock<CPUFeatures> = OnceLock::new();
+    *FEATURES.get_or_init(|| CPUFeatures {
+        sse2: is_x86_feature_detected!("sse2"),
+        ssse3: is_x86_feature_detected!("ssse3"),
+        avx2: is_x86_feature_detected!("avx2"),
+    })
+}
+
+const C1S7: i32 = 64277;
+const C2S6: i32 = 60547;
+const C3S5: i32 = 54491;
+const C4S4: i32 = 46341;
+const C5S3: i32 = 36410;
+const C6S2: i32 = 25080;
+const C7S1: i32 = 12785;
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn transpose8x8_epi16(r: &mut [__m128i; 8]) {
+    let a0 = _mm_unpacklo_epi16(r[0], r[1]);
+    let a1 = _mm_unpackhi_epi16(r[0], r[1]);
+    let a2 = _mm_unpacklo_epi16(r[2], r[3]);
+    let a3 = _mm_unpackhi_epi16(r[2], r[3]);
+    let a4 = _mm_unpacklo_epi16(r[4], r[5]);
+    let a5 = _mm_unpackhi_epi16(r[4], r[5]);
+    let a6 = _mm_unpacklo_epi16(r[6], r[7]);
+    let a7 = _mm_unpackhi_epi16(r[6], r[7]);
+    let b0 = _mm_unpacklo_epi32(a0, a2);
+    let b1 = _mm_unpackhi_epi32(a0, a2);
+    let b2 = _mm_unpacklo_epi32(a1, a3);
+    let b3 = _mm_unpackhi_epi32(a1, a3);
+    let b4 = _mm_unpacklo_epi32(a4, a6);
+    let b5 = _mm_unpackhi_epi32(a4, a6);
+    let b6 = _mm_unpacklo_epi32(a5, a7);
+    let b7 = _mm_unpackhi_epi32(a5, a7);
+    r[0] = _mm_unpacklo_epi64(b0, b4);
+    r[1] = _mm_unpackhi_epi64(b0, b4);
+    r[2] = _mm_unpacklo_epi64(b1, b5);
+    r[3] = _mm_unpackhi_epi64(b1, b5);
+    r[4] = _mm_unpacklo_epi64(b2, b6);
+    r[5] = _mm_unpackhi_epi64(b2, b6);
+    r[6] = _mm_unpacklo_epi64(b3, b7);
+    r[7] = _mm_unpackhi_epi64(b3, b7);
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn transpose8x8_epi32(r: &mut [__m256i; 8]) {
+    let a0 = _mm256_unpacklo_epi32(r[0], r[1]);
+    let a1 = _mm256_unpackhi_epi32(r[0], r[1]);
+    let a2 = _mm256_unpacklo_epi32(r[2], r[3]);
+    let a3 = _mm256_unpackhi_epi32(r[2], r[3]);
+    let a4 = _mm256_unpacklo_epi32(r[4], r[5]);
+    let a5 = _mm256_unpackhi_epi32(r[4], r[5]);
+    let a6 = _mm256_unpacklo_epi32(r[6], r[7]);
+    let a7 = _mm256_unpackhi_epi32(r[6], r[7]);
+    let b0 = _mm256_unpacklo_epi64(a0, a2);
+    let b1 = _mm256_unpackhi_epi64(a0, a2);
+    let b2 = _mm256_unpacklo_epi64(a1, a3);
+    let b3 = _mm256_unpackhi_epi64(a1, a3);
+    let b4 = _mm256_unpacklo_epi64(a4, a6);
+    let b5 = _mm256_unpackhi_epi64(a4, a6);
+    let b6 = _mm256_unpacklo_epi64(a5, a7);
+    let b7 = _mm256_unpackhi_epi64(a5, a7);
+    r[0] = _mm256_permute2x128_si256(b0, b4, 0x20);
+    r[1] = _mm256_permute2x128_si256(b1, b5, 0x20);
+    r[2] = _mm256_permute2x128_si256(b2, b6, 0x20);
+    r[3] = _mm256_permute2x128_si256(b3, b7, 0x20);
+    r[4] = _mm256_permute2x128_si256(b0, b4, 0x31);
+    r[5] = _mm256_permute2x128_si256(b1, b5, 0x31);
+    r[6] = _mm256_permute2x128_si256(b2, b6, 0x31);
+    r[7] = _mm256_permute2x128_si256(b3, b7, 0x31);
+}
+
+/// Loads 8x8 block rows and returns its columns widened to 32 bits.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn load_columns(blk: &[i16; 64]) -> [__m128i; 8] {
+    let mut rows = [_mm_setzero_si128(); 8];
+    for (row, src) in rows.iter_mut().zip(blk.chunks_exact(8)) {
+        *row = _mm_loadu_si128(src.as_ptr() as *const __m128i);
+    }
+    transpose8x8_epi16(&mut rows);
+    rows
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn idct_mul16(c: i32, a: __m256i) -> __m256i {
+    _mm256_srai_epi32(_mm256_mullo_epi32(_mm256_set1_epi32(c), a), 16)
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn idct_pass(
+    s: &[__m256i; 8],
+    sum04: __m256i,
+    diff04: __m256i,
+    bias: __m256i,
+) -> [__m256i; 8] {
+    let t_a = _mm256_add_epi32(idct_mul16(C1S7, s[1]), idct_mul16(C7S1, s[7]));
+    let t_b = _mm256_sub_epi32(idct_mul16(C7S1, s[1]), idct_mul16(C1S7, s[7]));
+    let t_c = _mm256_add_epi32(idct_mul16(C3S5, s[3]), idct_mul16(C5S3, s[5]));
+    let t_d = _mm256_sub_epi32(idct_mul16(C3S5, s[5]), idct_mul16(C5S3, s[3]));
+    let t_a1 = idct_mul16(C4S4, _mm256_sub_epi32(t_a, t_c));
+    let t_b1 = idct_mul16(C4S4, _mm256_sub_epi32(t_b, t_d));
+    let t_c = _mm256_add_epi32(t_a, t_c);
+    let t_d = _mm256_add_epi32(t_b, t_d);
+    let t_e = _mm256_add_epi32(idct_mul16(C4S4, sum04), bias);
+    let t_f = _mm256_add_epi32(idct_mul16(C4S4, diff04), bias);
+    let t_g = _mm256_add_epi32(idct_mul16(C2S6, s[2]), idct_mul16(C6S2, s[6]));
+    let t_h = _mm256_sub_epi32(idct_mul16(C6S2, s[2]), idct_mul16(C2S6, s[6]));
+    let t_e1 = _mm256_sub_epi32(t_e, t_g);
+    let t_g = _mm256_add_epi32(t_e, t_g);
+    let t_a = _mm256_add_epi32(t_f, t_a1);
+    let t_f = _mm256_sub_epi32(t_f, t_a1);
+    let t_b = _mm256_sub_epi32(t_b1, t_h);
+    let t_h = _mm256_add_epi32(t_b1, t_h);
+    [
+        _mm256_add_epi32(t_g, t_c),
+        _mm256_add_epi32(t_a, t_h),
+        _mm256_sub_epi32(t_a, t_h),
+        _mm256_add_epi32(t_e1, t_d),
+        _mm256_sub_epi32(t_e1, t_d),
+        _mm256_add_epi32(t_f, t_b),
+        _mm256_sub_epi32(t_f, t_b),
+        _mm256_sub_epi32(t_g, t_c),
+    ]
+}
+
+/// AVX2 version of `vp_idct`.
+#[target_feature(enable = "avx2")]
+pub unsafe fn vp_idct_avx2(coeffs: &mut [i16; 64]) {
+    let cols = load_columns(coeffs);
+    // the first pass sums DC terms in 16 bits
+    let sum04 = _mm256_cvtepi16_epi32(_mm_add_epi16(cols[0], cols[4]));
+    let diff04 = _mm256_cvtepi16_epi32(_mm_sub_epi16(cols[0], cols[4]));
+    let mut src = [_mm256_setzero_si256(); 8];
+    for (dst, &col) in src.iter_mut().zip(cols.iter()) {
+        *dst = _mm256_cvtepi16_epi32(col);
+    }
+    let mut tmp = idct_pass(&src, sum04, diff04, _mm256_setzero_si256());
+    transpose8x8_epi32(&mut tmp);
+
+    let sum04 = _mm256_add_epi32(tmp[0], tmp[4]);
+    let diff04 = _mm256_sub_epi32(tmp[0], tmp[4]);
+    let res = idct_pass(&tmp, sum04, diff04, _mm256_set1_epi32(8));
+    for (dst, &row) in coeffs.chunks_exact_mut(8).zip(res.iter()) {
+        let row = _mm256_srai_epi32(row, 4);
+        // truncate to 16 bits like the scalar version does
+        let row = _mm256_srai_epi32(_mm256_slli_epi32(row, 16), 16);
+        let row = _mm_packs_epi32(
+            _mm256_castsi256_si128(row),
+            _mm256_extracti128_si256(row, 1),
+        );
+        _mm_storeu_si128(dst.as_mut_ptr() as *mut __m128i, row);
+    }
+}
+
+#[cfg(feature = "encoder_vp6")]
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn fdct_mul16(c: i32, a: __m256i) -> __m256i {
+    let res = _mm256_mullo_epi32(_mm256_set1_epi32(c), a);
+    let round = _mm256_and_si256(_mm256_srai_epi32(res, 31), _mm256_set1_epi32(0xFFFF));
+    _mm256_srai_epi32(_mm256_add_epi32(res, round), 16)
+}
+
+#[cfg(feature = "encoder_vp6")]
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn fdct_pass(s: &[__m256i; 8]) -> [__m256i; 8] {
+    let t_g = _mm256_add_epi32(s[0], s[7]);
+    let t_c = _mm256_sub_epi32(s[0], s[7]);
+    let t_a = _mm256_add_epi32(s[1], s[2]);
+    let t_h = _mm256_sub_epi32(s[1], s[2]);
+    let t_e1 = _mm256_add_epi32(s[3], s[4]);
+    let t_d = _mm256_sub_epi32(s[3], s[4]);
+    let t_f = _mm256_add_epi32(s[5], s[6]);
+    let t_b = _mm256_sub_epi32(s[5], s[6]);
+
+    let t_b1 = _mm256_add_epi32(t_h, t_b);
+    let t_h = _mm256_sub_epi32(t_h, t_b);
+    let t_a1 = _mm256_sub_epi32(t_a, t_f);
+    let t_f = _mm256_add_epi32(t_a, t_f);
+    let t_e = _mm256_add_epi32(t_g, t_e1);
+    let t_g = _mm256_sub_epi32(t_g, t_e1);
+
+    let d2 = _mm256_add_epi32(fdct_mul16(C2S6, t_g), fdct_mul16(C6S2, t_h));
+    let d6 = _mm256_sub_epi32(fdct_mul16(C6S2, t_g), fdct_mul16(C2S6, t_h));
+    let d0 = fdct_mul16(C4S4, _mm256_add_epi32(t_e, t_f));
+    let d4 = fdct_mul16(C4S4, _mm256_sub_epi32(t_e, t_f));
+    let t_a = _mm256_add_epi32(t_c, fdct_mul16(C4S4, t_a1));
+    let t_c = _mm256_sub_epi32(t_c, fdct_mul16(C4S4, t_a1));
+    let t_b = _mm256_add_epi32(t_d, fdct_mul16(C4S4, t_b1));
+    let t_d = _mm256_sub_epi32(t_d, fdct_mul16(C4S4, t_b1));
+    let d3 = _mm256_sub_epi32(fdct_mul16(C3S5, t_c), fdct_mul16(C5S3, t_d));
+    let d5 = _mm256_add_epi32(fdct_mul16(C5S3, t_c), fdct_mul16(C3S5, t_d));
+    let d1 = _mm256_add_epi32(fdct_mul16(C1S7, t_a), fdct_mul16(C7S1, t_b));
+    let d7 = _mm256_sub_epi32(fdct_mul16(C7S1, t_a), fdct_mul16(C1S7, t_b));
+
+    let min = _mm256_set1_epi32(-32768);
+    let max = _mm256_set1_epi32(32767);
+    let mut dst = [d0, d1, d2, d3, d4, d5, d6, d7];
+    for el in dst.iter_mut() {
+        *el = _mm256_min_epi32(_mm256_max_epi32(*el, min), max);
+    }
+    dst
+}
+
+/// AVX2 version of `vp_fdct`.
+#[cfg(feature = "encoder_vp6")]
+#[target_feature(enable = "avx2")]
+pub unsafe fn vp_fdct_avx2(blk: &mut [i16; 64]) {
+    let cols = load_columns(blk);
+    let mut src = [_mm256_setzero_si256(); 8];
+    for (dst, &col) in src.iter_mut().zip(cols.iter()) {
+        *dst = _mm256_cvtepi16_epi32(col);
+    }
+    let mut tmp = fdct_pass(&src);
+    transpose8x8_epi32(&mut tmp);
+    let res = fdct_pass(&tmp);
+    for (dst, &row) in blk.chunks_exact_mut(8).zip(res.iter()) {
+        let row = _mm_packs_epi32(
+            _mm256_castsi256_si128(row),
+            _mm256_extracti128_si256(row, 1),
+        );
+        _mm_storeu_si128(dst.as_mut_ptr() as *mut __m128i, row);
+    }
+}
+
+/// SSE2 version of storing IDCT output biased by 128.
+#[target_feature(enable = "sse2")]
+pub unsafe fn put_pixels_sse2(coeffs: &[i16; 64], dst: &mut [u8], stride: usize) {
+    let bias = _mm_set1_epi16(128);
+    for (src, line) in coeffs.chunks_exact(8).zip(dst.chunks_mut(stride)) {
+        let line = &mut line[..8];
+        let val = _mm_add_epi16(_mm_loadu_si128(src.as_ptr() as *const __m128i), bias);
+        _mm_storel_epi64(
+            line.as_mut_ptr() as *mut __m128i,
+            _mm_packus_epi16(val, val),
+        );
+    }
+}
+
+/// SSE2 version of adding IDCT output to the prediction.
+#[target_feature(enable = "sse2")]
+pub unsafe fn add_pixels_sse2(coeffs: &[i16; 64], dst: &mut [u8], stride: usize) {
+    let zero = _mm_setzero_si128();
+    for (src, line) in coeffs.chunks_exact(8).zip(dst.chunks_mut(stride)) {
+        let line = &mut line[..8];
+        let pix = _mm_loadl_epi64(line.as_ptr() as *const __m128i);
+        let val = _mm_add_epi16(
+            _mm_loadu_si128(src.as_ptr() as *const __m128i),
+            _mm_unpacklo_epi8(pix, zero),
+        );
+        _mm_storel_epi64(
+            line.as_mut_ptr() as *mut __m128i,
+            _mm_packus_epi16(val, val),
+        );
+    }
+}
+
+#[inline]
+#[target_feature(enable = "ssse3")]
+unsafe fn bilinear_ssse3(src: &[u8], step: usize, weights: __m128i) -> __m128i {
+    let src = &src[..step + 8];
+    let a = _mm_loadl_epi64(src.as_ptr() as *const __m128i);
+    let b = _mm_loadl_epi64(src[step..].as_ptr() as *const __m128i);
+    bilinear_blend(a, b, weights)
+}
+
+#[inline]
+#[target_feature(enable = "ssse3")]
+unsafe fn bilinear_blend(a: __m128i, b: __m128i, weights: __m128i) -> __m128i {
+    let sum = _mm_maddubs_epi16(_mm_unpacklo_epi8(a, b), weights);
+    let res = _mm_srli_epi16(_mm_add_epi16(sum, _mm_set1_epi16(4)), 3);
+    _mm_packus_epi16(res, res)
+}
+
+#[inline]
+#[target_feature(enable = "ssse3")]
+unsafe fn bilinear_weights(frac: u16) -> __m128i {
+    _mm_set1_epi16(((frac << 8) | (8 - frac)) as i16)
+}
+
+/// SSSE3 version of `mc_bilinear16`.
+#[target_feature(enable = "ssse3")]
+pub unsafe fn mc_bilinear16_ssse3(dst: &mut [u8], dstride: usize, src: &[u8], mx: u16, my: u16) {
+    const SSTRIDE: usize = 16;
+    let mut soff = 16 * 2 + 2;
+    assert!(dstride >= 8);
+
+    let wx = bilinear_weights(mx);
+    let wy = bilinear_weights(my);
+    if my == 0 {
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let res = bilinear_ssse3(&src[soff..], 1, wx);
+            _mm_storel_epi64(dline[..8].as_mut_ptr() as *mut __m128i, res);
+            soff += SSTRIDE;
+        }
+    } else if mx == 0 {
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let res = bilinear_ssse3(&src[soff..], SSTRIDE, wy);
+            _mm_storel_epi64(dline[..8].as_mut_ptr() as *mut __m128i, res);
+            soff += SSTRIDE;
+        }
+    } else {
+        let mut tmp = bilinear_ssse3(&src[soff..], 1, wx);
+        soff += SSTRIDE;
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let cur = bilinear_ssse3(&src[soff..], 1, wx);
+            let res = bilinear_blend(tmp, cur, wy);
+            _mm_storel_epi64(dline[..8].as_mut_ptr() as *mut __m128i, res);
+            tmp = cur;
+            soff += SSTRIDE;
+        }
+    }
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn bilinear_sse2(a: __m128i, b: __m128i, frac: u16) -> __m128i {
+    let zero = _mm_setzero_si128();
+    let a = _mm_mullo_epi16(
+        _mm_unpacklo_epi8(a, zero),
+        _mm_set1_epi16((8 - frac) as i16),
+    );
+    let b = _mm_mullo_epi16(_mm_unpacklo_epi8(b, zero), _mm_set1_epi16(frac as i16));
+    let res = _mm_srli_epi16(_mm_add_epi16(_mm_add_epi16(a, b), _mm_set1_epi16(4)), 3);
+    _mm_packus_epi16(res, res)
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn load_pair(src: &[u8], step: usize) -> (__m128i, __m128i) {
+    let src = &src[..step + 8];
+    (
+        _mm_loadl_epi64(src.as_ptr() as *const __m128i),
+        _mm_loadl_epi64(src[step..].as_ptr() as *const __m128i),
+    )
+}
+
+/// SSE2 version of `mc_bilinear16` for CPUs without SSSE3.
+#[target_feature(enable = "sse2")]
+pub unsafe fn mc_bilinear16_sse2(dst: &mut [u8], dstride: usize, src: &[u8], mx: u16, my: u16) {
+    const SSTRIDE: usize = 16;
+    let mut soff = 16 * 2 + 2;
+    assert!(dstride >= 8);
+
+    if my == 0 {
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let (a, b) = load_pair(&src[soff..], 1);
+            _mm_storel_epi64(
+                dline[..8].as_mut_ptr() as *mut __m128i,
+                bilinear_sse2(a, b, mx),
+            );
+            soff += SSTRIDE;
+        }
+    } else if mx == 0 {
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let (a, b) = load_pair(&src[soff..], SSTRIDE);
+            _mm_storel_epi64(
+                dline[..8].as_mut_ptr() as *mut __m128i,
+                bilinear_sse2(a, b, my),
+            );
+            soff += SSTRIDE;
+        }
+    } else {
+        let (a, b) = load_pair(&src[soff..], 1);
+        let mut tmp = bilinear_sse2(a, b, mx);
+        soff += SSTRIDE;
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let (a, b) = load_pair(&src[soff..], 1);
+            let cur = bilinear_sse2(a, b, mx);
+            let res = bilinear_sse2(tmp, cur, my);
+            _mm_storel_epi64(dline[..8].as_mut_ptr() as *mut __m128i, res);
+            tmp = cur;
+            soff += SSTRIDE;
+        }
+    }
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn bicubic_sse2(src: &[u8], off: usize, step: usize, coeffs: &[i16; 4]) -> __m128i {
+    let src = &src[off - step..][..step * 3 + 8];
+    let zero = _mm_setzero_si128();
+    let mut pix = [zero; 4];
+    for (i, el) in pix.iter_mut().enumerate() {
+        let line = _mm_loadl_epi64(src[step * i..].as_ptr() as *const __m128i);
+        *el = _mm_unpacklo_epi8(line, zero);
+    }
+    let c01 = _mm_set1_epi32(((i32::from(coeffs[1])) << 16) | (i32::from(coeffs[0]) & 0xFFFF));
+    let c23 = _mm_set1_epi32(((i32::from(coeffs[3])) << 16) | (i32::from(coeffs[2]) & 0xFFFF));
+    let rnd = _mm_set1_epi32(64);
+    let lo = _mm_add_epi32(
+        _mm_madd_epi16(_mm_unpacklo_epi16(pix[0], pix[1]), c01),
+        _mm_madd_epi16(_mm_unpacklo_epi16(pix[2], pix[3]), c23),
+    );
+    let hi = _mm_add_epi32(
+        _mm_madd_epi16(_mm_unpackhi_epi16(pix[0], pix[1]), c01),
+        _mm_madd_epi16(_mm_unpackhi_epi16(pix[2], pix[3]), c23),
+    );
+    let lo = _mm_srai_epi32(_mm_add_epi32(lo, rnd), 7);
+    let hi = _mm_srai_epi32(_mm_add_epi32(hi, rnd), 7);
+    let res = _mm_packs_epi32(lo, hi);
+    _mm_packus_epi16(res, res)
+}
+
+/// SSE2 version of `mc_bicubic`.
+#[target_feature(enable = "sse2")]
+pub unsafe fn mc_bicubic_sse2(
+    dst: &mut [u8],
+    dstride: usize,
+    src: &[u8],
+    mut soff: usize,
+    sstride: usize,
+    coeffs_w: &[i16; 4],
+    coeffs_h: &[i16; 4],
+) {
+    if coeffs_h[1] == 128 {
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let res = bicubic_sse2(src, soff, 1, coeffs_w);
+            _mm_storel_epi64(dline[..8].as_mut_ptr() as *mut __m128i, res);
+            soff += sstride;
+        }
+    } else if coeffs_w[1] == 128 {
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let res = bicubic_sse2(src, soff, sstride, coeffs_h);
+            _mm_storel_epi64(dline[..8].as_mut_ptr() as *mut __m128i, res);
+            soff += sstride;
+        }
+    } else {
+        let mut buf = [0u8; 16 * 11];
+        soff -= sstride;
+        for dline in buf.chunks_mut(16) {
+            let res = bicubic_sse2(src, soff, 1, coeffs_w);
+            _mm_storel_epi64(dline.as_mut_ptr() as *mut __m128i, res);
+            soff += sstride;
+        }
+        let mut soff = 16;
+        for dline in dst.chunks_mut(dstride).take(8) {
+            let res = bicubic_sse2(&buf, soff, 16, coeffs_h);
+            _mm_storel_epi64(dline[..8].as_mut_ptr() as *mut __m128i, res);
+            soff += 16;
+        }
+    }
+}
+
+/// SSE2 version of `vp31_loop_filter` for up to 16 pixels along the edge.
+///
+/// Edge differences and filtered pixels are calculated in parallel
+/// while the filter table is still looked up for each pixel.
+#[target_feature(enable = "sse2")]
+pub unsafe fn vp31_loop_filter_sse2(
+    data: &mut [u8],
+    off: usize,
+    step: usize,
+    stride: usize,
+    len: usize,
+    loop_tab: &[i8; 256],
+) {
+    assert!(len <= 16);
+    let start = off - step * 2;
+    // pixels two and one before the edge and the two after it
+    let mut pix = [[0u8; 16]; 4];
+    if stride == 1 {
+        for (i, line) in pix.iter_mut().enumerate() {
+            line[..len].copy_from_slice(&data[start + i * step..][..len]);
+        }
+    } else {
+        for k in 0..len {
+            let pos = start + k * stride;
+            for (i, line) in pix.iter_mut().enumerate() {
+                line[k] = data[pos + i * step];
+            }
+        }
+    }
+    let zero = _mm_setzero_si128();
+    let [a, b, c, d] = pix.map(|line| _mm_loadu_si128(line.as_ptr() as *const __m128i));
+    let mut filt = [0i16; 16];
+    for (half, out) in filt.chunks_exact_mut(8).enumerate() {
+        let unpack = |x| {
+            if half == 0 {
+                _mm_unpacklo_epi8(x, zero)
+            } else {
+                _mm_unpackhi_epi8(x, zero)
+            }
+        };
+        let (a, b, c, d) = (unpack(a), unpack(b), unpack(c), unpack(d));
+        let cb = _mm_sub_epi16(c, b);
+        let diff = _mm_add_epi16(
+            _mm_sub_epi16(a, d),
+            _mm_add_epi16(cb, _mm_add_epi16(cb, cb)),
+        );
+        let diff = _mm_srai_epi16(_mm_add_epi16(diff, _mm_set1_epi16(4)), 3);
+        let diff = _mm_min_epi16(
+            _mm_max_epi16(diff, _mm_set1_epi16(-128)),
+            _mm_set1_epi16(127),
+        );
+        _mm_storeu_si128(
+            out.as_mut_ptr() as *mut __m128i,
+            _mm_add_epi16(diff, _mm_set1_epi16(128)),
+        );
+    }
+    for el in filt[..len].iter_mut() {
+        *el = i16::from(loop_tab[*el as usize]);
+    }
+    let filt_lo = _mm_loadu_si128(filt.as_ptr() as *const __m128i);
+    let filt_hi = _mm_loadu_si128(filt[8..].as_ptr() as *const __m128i);
+    let new_b = _mm_packus_epi16(
+        _mm_add_epi16(_mm_unpacklo_epi8(b, zero), filt_lo),
+        _mm_add_epi16(_mm_unpackhi_epi8(b, zero), filt_hi),
+    );
+    let new_c = _mm_packus_epi16(
+        _mm_sub_epi16(_mm_unpacklo_epi8(c, zero), filt_lo),
+        _mm_sub_epi16(_mm_unpackhi_epi8(c, zero), filt_hi),
+    );
+    _mm_storeu_si128(pix[1].as_mut_ptr() as *mut __m128i, new_b);
+    _mm_storeu_si128(pix[2].as_mut_ptr() as *mut __m128i, new_c);
+    if stride == 1 {
+        for i in 1..3 {
+            data[start + i * step..][..len].copy_from_slice(&pix[i][..len]);
+        }
+    } else {
+        for (k, (&b, &c)) in pix[1].iter().zip(pix[2].iter()).take(len).enumerate() {
+            let pos = start + k * stride;
+            data[pos + step] = b;
+            data[pos + step * 2] = c;
+        }
+    }
+}
+
+/// SSE2 version of variance calculation for every second pixel in 8x8 block.
+#[target_feature(enable = "sse2")]
+pub unsafe fn calc_variance_sse2(src: &[u8], stride: usize) -> u16 {
+    let mask = _mm_set1_epi16(0xFF);
+    let ones = _mm_set1_epi16(1);
+    let mut sum = _mm_setzero_si128();
+    let mut ssum = _mm_setzero_si128();
+    for off in [0, stride * 4].iter() {
+        let line0 = &src[*off..][..8];
+        let line1 = &src[off + stride * 2..][..8];
+        let pix = _mm_unpacklo_epi64(
+            _mm_loadl_epi64(line0.as_ptr() as *const __m128i),
+            _mm_loadl_epi64(line1.as_ptr() as *const __m128i),
+        );
+        // even pixels end up in the low bytes of 16-bit words
+        let pix = _mm_and_si128(pix, mask);
+        sum = _mm_add_epi32(sum, _mm_madd_epi16(pix, ones));
+        ssum = _mm_add_epi32(ssum, _mm_madd_epi16(pix, pix));
+    }
+    let sum = _mm_add_epi32(sum, _mm_unpackhi_epi64(sum, sum));
+    let sum = _mm_add_epi32(sum, _mm_shuffle_epi32(sum, 1));
+    let ssum = _mm_add_epi32(ssum, _mm_unpackhi_epi64(ssum, ssum));
+    let ssum = _mm_add_epi32(ssum, _mm_shuffle_epi32(ssum, 1));
+    let sum = _mm_cvtsi128_si32(sum) as u32;
+    let ssum = _mm_cvtsi128_si32(ssum) as u32;
+    ((ssum * 16 - sum * sum) >> 8) as u16
+}
+
+/// SSE2 version of 8x8 block SAD.
+#[cfg(feature = "encoder_vp6")]
+#[target_feature(enable = "sse2")]
+pub unsafe fn sad_sse2(src1: &[u8; 64], src2: &[u8; 64]) -> u32 {
+    let mut sum = _mm_setzero_si128();
+    for (s1, s2) in src1.chunks_exact(16).zip(src2.chunks_exact(16)) {
+        let a = _mm_loadu_si128(s1.as_ptr() as *const __m128i);
+        let b = _mm_loadu_si128(s2.as_ptr() as *const __m128i);
+        sum = _mm_add_epi64(sum, _mm_sad_epu8(a, b));
+    }
+    let sum = _mm_add_epi64(sum, _mm_unpackhi_epi64(sum, sum));
+    _mm_cvtsi128_si32(sum) as u32
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::codecs::vp6data::VP6_BICUBIC_COEFFS;
+    use crate::codecs::vp6dsp::{calc_variance_scalar, mc_bicubic_scalar, mc_bilinear};
+    use crate::codecs::vpcommon::vp31_loop_filter;
+    use crate::codecs::vpcommon::*;
+
+    /// Xorshift generator for test input.
+    pub struct Rng(pub u32);
+
+    impl Rng {
+        pub fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+        pub fn range(&mut self, min: i32, max: i32) -> i16 {
+            (min + (self.next() % ((max - min + 1) as u32)) as i32) as i16
+        }
+        pub fn fill_pixels(&mut self, dst: &mut [u8]) {
+            for el in dst.iter_mut() {
+                *el = self.next() as u8;
+            }
+        }
+    }
+
+    #[test]
+    fn test_simd_idct() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let mut rng = Rng(0x1234_5678);
+        for i in 0..4096 {
+            let mut blk = [0i16; 64];
+            // mix sparse and dense blocks
+            let ncoeffs = if (i & 1) == 0 { 64 } else { (i % 63) + 1 };
+            for el in blk.iter_mut().take(ncoeffs) {
+                *el = rng.range(-1024, 1023);
+            }
+            let mut ref_blk = blk;
+            vp_idct_scalar(&mut ref_blk);
+            unsafe { vp_idct_avx2(&mut blk) };
+            assert_eq!(blk, ref_blk);
+        }
+    }
+
+    #[test]
+    fn test_simd_put_add() {
+        if !is_x86_feature_detected!("sse2") {
+            return;
+        }
+        let mut rng = Rng(0xDEAD_BEEF);
+        for _ in 0..1024 {
+            let mut blk = [0i16; 64];
+            for el in blk.iter_mut() {
+                *el = rng.range(-400, 400);
+            }
+            let mut dst = [0u8; 24 * 8];
+            rng.fill_pixels(&mut dst);
+            let mut ref_dst = dst;
+            put_pixels_scalar(&blk, &mut ref_dst[3..], 24);
+            unsafe { put_pixels_sse2(&blk, &mut dst[3..], 24) };
+            assert_eq!(dst, ref_dst);
+
+            rng.fill_pixels(&mut dst);
+            let mut ref_dst = dst;
+            add_pixels_scalar(&blk, &mut ref_dst[5..], 24);
+            unsafe { add_pixels_sse2(&blk, &mut dst[5..], 24) };
+            assert_eq!(dst, ref_dst);
+        }
+    }
+
+    #[test]
+    fn test_simd_mc() {
+        if !is_x86_feature_detected!("ssse3") {
+            return;
+        }
+        let mut rng = Rng(0xC0FF_EE00);
+        let mut src = [0u8; 16 * 16];
+        for _ in 0..16 {
+            rng.fill_pixels(&mut src);
+            for my in 0..8 {
+                for mx in 0..8 {
+                    let mut dst = [0u8; 8 * 8];
+                    let mut ref_dst = [0u8; 8 * 8];
+                    mc_bilinear::<{ 16 * 2 + 2 }, 16>(&mut ref_dst, 8, &src, mx, my);
+                    unsafe { mc_bilinear16_ssse3(&mut dst, 8, &src, mx, my) };
+                    assert_eq!(dst, ref_dst);
+                    let mut dst = [0u8; 8 * 8];
+                    unsafe { mc_bilinear16_sse2(&mut dst, 8, &src, mx, my) };
+                    assert_eq!(dst, ref_dst);
+                }
+            }
+            for coeffs in VP6_BICUBIC_COEFFS.iter() {
+                for coeffs_h in coeffs.iter() {
+                    for coeffs_w in coeffs.iter() {
+                        let mut dst = [0u8; 8 * 8];
+                        let mut ref_dst = [0u8; 8 * 8];
+                        mc_bicubic_scalar(&mut ref_dst, 8, &src, 34, 16, coeffs_w, coeffs_h);
+                        unsafe { mc_bicubic_sse2(&mut dst, 8, &src, 34, 16, coeffs_w, coeffs_h) };
+                        assert_eq!(dst, ref_dst);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_simd_variance() {
+        if !is_x86_feature_detected!("sse2") {
+            return;
+        }
+        let mut rng = Rng(0x0BAD_F00D);
+        let mut src = [0u8; 16 * 12];
+        for i in 0..1024 {
+            rng.fill_pixels(&mut src);
+            if (i & 1) != 0 {
+                // flat blocks with small variations
+                for el in src.iter_mut() {
+                    *el = 0x80 | (*el & 3);
+                }
+            }
+            let off = 16 * 2 + 2 + (i & 1) + (i & 16);
+            let ref_var = calc_variance_scalar(&src[off..], 16);
+            assert_eq!(unsafe { calc_variance_sse2(&src[off..], 16) }, ref_var);
+        }
+    }
+
+    #[test]
+    fn test_simd_loop_filter() {
+        if !is_x86_feature_detected!("sse2") {
+            return;
+        }
+        let mut rng = Rng(0x5EED_1234);
+        let mut loop_tab = [0i8; 256];
+        let mut src = [0u8; 16 * 20];
+        for i in 0..1024 {
+            vp31_build_lf_tab(&mut loop_tab, rng.range(0, 64));
+            rng.fill_pixels(&mut src);
+            if (i & 1) != 0 {
+                // small differences that are actually filtered
+                for el in src.iter_mut() {
+                    *el = 0x40 + (*el & 0x1F);
+                }
+            }
+            let len = if i < 512 { 12 } else { (i % 16) + 1 };
+            for &(off, step, stride) in [(2 + (i & 7), 1, 16), (16 * 2 + (i & 3), 16, 1)].iter() {
+                let mut data = src;
+                let mut ref_data = src;
+                vp31_loop_filter(&mut ref_data, off, step, stride, len, &loop_tab);
+                unsafe { vp31_loop_filter_sse2(&mut data, off, step, stride, len, &loop_tab) };
+                assert_eq!(data, ref_data);
+            }
+        }
+    }
+}
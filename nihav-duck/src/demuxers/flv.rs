@@ -0,0 +1,681 @@
+use nihav_core::demuxers::*;
+
+const FLV_TAG_AUDIO: u8 = 8;
+const FLV_TAG_VIDEO: u8 = 9;
+const FLV_TAG_SCRIPT: u8 = 18;
+
+const AUDIO_ID: u32 = 1;
+const VIDEO_ID: u32 = 0;
+
+// maximum number of tags to look at when searching for stream parameters
+const PROBE_TAGS: usize = 32;
+// maximum nesting depth for AMF values
+const MAX_AMF_DEPTH: u8 = 8;
+
+enum AMFValue {
+    Number(f64),
+    String(String),
+    Object(Vec<(String, AMFValue)>),
+    Array(Vec<AMFValue>),
+    Null,
+}
+
+impl AMFValue {
+    fn find(&self, name: &str) -> Option<&AMFValue> {
+        if let AMFValue::Object(ref entries) = self {
+            for (key, val) in entries.iter() {
+                if key == name {
+                    return Some(val);
+                }
+            }
+        }
+        None
+    }
+    fn as_number(&self) -> Option<f64> {
+        if let AMFValue::Number(val) = *self {
+            Some(val)
+        } else {
+            None
+        }
+    }
+    fn as_numbers(&self) -> Vec<f64> {
+        let mut ret = Vec::new();
+        if let AMFValue::Array(ref vals) = self {
+            for val in vals.iter() {
+                ret.push(val.as_number().unwrap_or(0.0));
+            }
+        }
+        ret
+    }
+}
+
+fn read_amf_string(src: &mut ByteReader, len: usize) -> DemuxerResult<String> {
+    validate!((len as i64) <= src.left());
+    let mut buf = vec![0; len];
+    src.read_buf(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn read_amf_object(src: &mut ByteReader, depth: u8) -> DemuxerResult<Vec<(String, AMFValue)>> {
+    let mut entries = Vec::new();
+    loop {
+        let klen = src.read_u16be()? as usize;
+        if klen == 0 {
+            let end = src.read_byte()?;
+            validate!(end == 9);
+            break;
+        }
+        let key = read_amf_string(src, klen)?;
+        let val = read_amf_value(src, depth + 1)?;
+        entries.push((key, val));
+    }
+    Ok(entries)
+}
+
+fn read_amf_value(src: &mut ByteReader, depth: u8) -> DemuxerResult<AMFValue> {
+    validate!(depth < MAX_AMF_DEPTH);
+    let vtype = src.read_byte()?;
+    match vtype {
+        0 => Ok(AMFValue::Number(src.read_f64be()?)),
+        // booleans are treated as numbers since nothing needs to tell them apart
+        1 => Ok(AMFValue::Number(f64::from(src.read_byte()?))),
+        2 => {
+            let len = src.read_u16be()? as usize;
+            Ok(AMFValue::String(read_amf_string(src, len)?))
+        }
+        3 => Ok(AMFValue::Object(read_amf_object(src, depth)?)),
+        5 | 6 => Ok(AMFValue::Null),
+        7 => {
+            src.read_skip(2)?;
+            Ok(AMFValue::Null)
+        }
+        8 => {
+            src.read_skip(4)?;
+            Ok(AMFValue::Object(read_amf_object(src, depth)?))
+        }
+        10 => {
+            let count = src.read_u32be()? as usize;
+            let mut vals = Vec::new();
+            for _ in 0..count {
+                vals.push(read_amf_value(src, depth + 1)?);
+            }
+            Ok(AMFValue::Array(vals))
+        }
+        11 => {
+            src.read_skip(10)?;
+            Ok(AMFValue::Null)
+        }
+        12 => {
+            let len = src.read_u32be()? as usize;
+            Ok(AMFValue::String(read_amf_string(src, len)?))
+        }
+        _ => Err(DemuxerError::NotImplemented),
+    }
+}
+
+// Reads coded frame dimensions from the bool-coded part of VP6 intra frame header.
+fn vp6_coded_size(src: &[u8]) -> Option<(usize, usize)> {
+    if src.len() < 2 || (src[0] & 0x80) != 0 {
+        return None;
+    }
+    let multistream = (src[0] & 1) != 0;
+    let profile = (src[1] >> 1) & 3;
+    let start = if multistream || profile == 0 { 4 } else { 2 };
+    if src.len() < start + 4 {
+        return None;
+    }
+    let src = &src[start..];
+
+    let mut value = read_u32be(src).unwrap_or(0);
+    let mut range = 255u32;
+    let mut bits = 8i32;
+    let mut pos = 4;
+    let mut dims = [0usize; 2];
+    for dim in dims.iter_mut() {
+        for _ in 0..8 {
+            let shift = range.leading_zeros() & 7;
+            range <<= shift;
+            value <<= shift;
+            bits -= shift as i32;
+            if bits <= 0 && pos < src.len() {
+                value |= u32::from(src[pos]) << (-bits as u8);
+                pos += 1;
+                bits += 8;
+            }
+            let split = 1 + (((range - 1) * 128) >> 8);
+            let bit = if value < (split << 24) {
+                range = split;
+                0
+            } else {
+                range -= split;
+                value -= split << 24;
+                1
+            };
+            *dim = (*dim << 1) | bit;
+        }
+    }
+    let (mb_h, mb_w) = (dims[0], dims[1]);
+    if mb_w == 0 || mb_h == 0 {
+        return None;
+    }
+    Some((mb_w * 16, mb_h * 16))
+}
+
+fn audio_codec_info(flags: u8) -> Option<(&'static str, NAAudioInfo)> {
+    let fmt = flags >> 4;
+    let mut srate = [5512, 11025, 22050, 44100][usize::from((flags >> 2) & 3)];
+    let bits = if (flags & 2) != 0 { 16 } else { 8 };
+    let channels = if (flags & 1) != 0 { 2 } else { 1 };
+    let name = match fmt {
+        0 | 3 => "pcm",
+        1 => "flv-adpcm",
+        2 | 14 => "mp3",
+        4 => {
+            srate = 16000;
+            "asao"
+        }
+        5 => {
+            srate = 8000;
+            "asao"
+        }
+        6 => "asao",
+        10 => "aac",
+        11 => {
+            srate = 16000;
+            "speex"
+        }
+        _ => return None,
+    };
+    if fmt == 14 {
+        srate = 8000;
+    }
+    let soniton = match (name, bits) {
+        ("pcm", 8) => SND_U8_FORMAT,
+        _ => SND_S16_FORMAT,
+    };
+    let block_len = if name == "pcm" { 1 } else { 0 };
+    Some((name, NAAudioInfo::new(srate, channels, soniton, block_len)))
+}
+
+fn video_codec_name(codec: u8) -> &'static str {
+    match codec {
+        2 => "flv263",
+        3 => "flashsv",
+        4 => "vp6f",
+        5 => "vp6a",
+        6 => "flashsv2",
+        7 => "h264",
+        _ => "unknown",
+    }
+}
+
+struct FLVDemuxer<'a> {
+    src: &'a mut ByteReader<'a>,
+    data_start: u64,
+    duration: u64,
+    width: usize,
+    height: usize,
+    vcodec: Option<u8>,
+    vp6_adj: u8,
+    aflags: Option<u8>,
+}
+
+impl<'a> FLVDemuxer<'a> {
+    fn new(src: &'a mut ByteReader<'a>) -> Self {
+        FLVDemuxer {
+            src,
+            data_start: 0,
+            duration: 0,
+            width: 0,
+            height: 0,
+            vcodec: None,
+            vp6_adj: 0,
+            aflags: None,
+        }
+    }
+    fn read_tag_header(&mut self) -> DemuxerResult<(u8, usize, u64)> {
+        let ttype = self.src.read_byte()? & 0x1F;
+        let size = self.src.read_u24be()? as usize;
+        let ts_lo = self.src.read_u24be()?;
+        let ts_hi = self.src.read_byte()?;
+        let _stream_id = self.src.read_u24be()?;
+        let ts = u64::from(ts_lo) | (u64::from(ts_hi) << 24);
+        Ok((ttype, size, ts))
+    }
+    fn parse_metadata(&mut self, size: usize, seek_index: &mut SeekIndex) -> DemuxerResult<()> {
+        let mut buf = vec![0; size];
+        self.src.read_buf(&mut buf)?;
+        // metadata is optional so malformed or unsupported values are ignored
+        let mut mr = MemoryReader::new_read(&buf);
+        let mut br = ByteReader::new(&mut mr);
+        if let Ok(AMFValue::String(ref name)) = read_amf_value(&mut br, 0) {
+            if name == "onMetaData" {
+                if let Ok(meta) = read_amf_value(&mut br, 0) {
+                    self.apply_metadata(&meta, seek_index);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn apply_metadata(&mut self, meta: &AMFValue, seek_index: &mut SeekIndex) {
+        if let Some(duration) = meta.find("duration").and_then(AMFValue::as_number) {
+            if duration > 0.0 {
+                self.duration = (duration * 1000.0) as u64;
+            }
+        }
+        if self.vcodec.is_none() {
+            if let Some(width) = meta.find("width").and_then(AMFValue::as_number) {
+                self.width = width as usize;
+            }
+            if let Some(height) = meta.find("height").and_then(AMFValue::as_number) {
+                self.height = height as usize;
+            }
+        }
+        if let Some(kf) = meta.find("keyframes") {
+            let positions = kf
+                .find("filepositions")
+                .map(AMFValue::as_numbers)
+                .unwrap_or_default();
+            let times = kf
+                .find("times")
+                .map(AMFValue::as_numbers)
+                .unwrap_or_default();
+            if !positions.is_empty() && positions.len() == times.len() {
+                for (&pos, &time) in positions.iter().zip(times.iter()) {
+                    let time = (time * 1000.0) as u64;
+                    seek_index.add_entry(
+                        VIDEO_ID,
+                        SeekEntry {
+                            time,
+                            pts: time,
+                            pos: pos as u64,
+                        },
+                    );
+                }
+                seek_index.mode = SeekIndexMode::Present;
+            }
+        }
+    }
+    fn probe_video(&mut self, size: usize) -> DemuxerResult<()> {
+        validate!(size > 0);
+        let codec = self.src.read_byte()? & 0xF;
+        if (codec == 4 || codec == 5) && size > 2 {
+            let adj = self.src.read_byte()?;
+            let hdr_start = if codec == 5 { 5 } else { 2 };
+            if size > hdr_start {
+                if codec == 5 {
+                    self.src.read_skip(3)?;
+                }
+                let mut hdr = [0; 16];
+                let hdr_size = (size - hdr_start).min(hdr.len());
+                self.src.peek_buf(&mut hdr[..hdr_size])?;
+                if let Some((w, h)) = vp6_coded_size(&hdr[..hdr_size]) {
+                    self.width = w;
+                    self.height = h;
+                } else if self.width > 0 && self.height > 0 {
+                    // metadata contains display size, restore the coded one
+                    self.width += usize::from(adj >> 4);
+                    self.height += usize::from(adj & 0xF);
+                }
+            }
+            self.vp6_adj = adj;
+        }
+        self.vcodec = Some(codec);
+        Ok(())
+    }
+}
+
+impl<'a> DemuxCore<'a> for FLVDemuxer<'a> {
+    fn open(
+        &mut self,
+        strmgr: &mut StreamManager,
+        seek_index: &mut SeekIndex,
+    ) -> DemuxerResult<()> {
+        let tag = self.src.read_tag()?;
+        validate!(&tag[..3] == b"FLV");
+        validate!(tag[3] == 1);
+        let flags = self.src.read_byte()?;
+        let has_audio = (flags & 4) != 0;
+        let has_video = (flags & 1) != 0;
+        let hdr_size = self.src.read_u32be()?;
+        validate!(hdr_size >= 9);
+        self.data_start = u64::from(hdr_size) + 4;
+        self.src.seek(SeekFrom::Start(self.data_start))?;
+
+        for _ in 0..PROBE_TAGS {
+            if (!has_video || self.vcodec.is_some()) && (!has_audio || self.aflags.is_some()) {
+                break;
+            }
+            let ret = self.read_tag_header();
+            if let Err(DemuxerError::IOError) | Err(DemuxerError::EOF) = ret {
+                break;
+            }
+            let (ttype, size, _ts) = ret?;
+            let end = self.src.tell() + (size as u64) + 4;
+            match ttype {
+                FLV_TAG_AUDIO if self.aflags.is_none() && size > 0 => {
+                    self.aflags = Some(self.src.read_byte()?);
+                }
+                FLV_TAG_VIDEO if self.vcodec.is_none() => {
+                    self.probe_video(size)?;
+                }
+                FLV_TAG_SCRIPT => {
+                    self.parse_metadata(size, seek_index)?;
+                }
+                _ => {}
+            };
+            self.src.seek(SeekFrom::Start(end))?;
+        }
+        validate!(self.vcodec.is_some() || self.aflags.is_some());
+
+        if let Some(codec) = self.vcodec {
+            let edata = if codec == 4 || codec == 5 {
+                Some(vec![self.vp6_adj])
+            } else {
+                None
+            };
+            let vci = NACodecTypeInfo::Video(NAVideoInfo::new(
+                self.width,
+                self.height,
+                false,
+                YUV420_FORMAT,
+            ));
+            let vinfo = NACodecInfo::new(video_codec_name(codec), vci, edata);
+            if strmgr
+                .add_stream(NAStream::new(
+                    StreamType::Video,
+                    VIDEO_ID,
+                    vinfo,
+                    1,
+                    1000,
+                    self.duration,
+                ))
+                .is_none()
+            {
+                return Err(DemuxerError::MemoryError);
+            }
+        }
+        if let Some(aflags) = self.aflags {
+            if let Some((name, ainfo)) = audio_codec_info(aflags) {
+                let ainfo = NACodecInfo::new(name, NACodecTypeInfo::Audio(ainfo), None);
+                if strmgr
+                    .add_stream(NAStream::new(
+                        StreamType::Audio,
+                        AUDIO_ID,
+                        ainfo,
+                        1,
+                        1000,
+                        self.duration,
+                    ))
+                    .is_none()
+                {
+                    return Err(DemuxerError::MemoryError);
+                }
+            } else {
+                self.aflags = None;
+            }
+        }
+
+        self.src.seek(SeekFrom::Start(self.data_start))?;
+        Ok(())
+    }
+
+    fn get_frame(&mut self, strmgr: &mut StreamManager) -> DemuxerResult<NAPacket> {
+        loop {
+            let ret = self.read_tag_header();
+            if let Err(DemuxerError::IOError) = ret {
+                return Err(DemuxerError::EOF);
+            }
+            let (ttype, size, ts) = ret?;
+            let end = self.src.tell() + (size as u64) + 4;
+            let (stream, keyframe, hdr_size) = match ttype {
+                FLV_TAG_AUDIO if size > 0 && self.aflags.is_some() => {
+                    let aflags = self.src.read_byte()?;
+                    if (aflags >> 4) == 10 {
+                        validate!(size > 1);
+                        let pkt_type = self.src.read_byte()?;
+                        if pkt_type == 0 {
+                            self.src.seek(SeekFrom::Start(end))?;
+                            continue;
+                        }
+                    }
+                    let hdr_size = if (aflags >> 4) == 10 { 2 } else { 1 };
+                    (strmgr.get_stream_by_id(AUDIO_ID), true, hdr_size)
+                }
+                FLV_TAG_VIDEO if size > 0 && self.vcodec.is_some() => {
+                    let vflags = self.src.read_byte()?;
+                    let frame_type = vflags >> 4;
+                    let hdr_size = match vflags & 0xF {
+                        4 | 5 => 2,
+                        7 => 5,
+                        _ => 1,
+                    };
+                    if frame_type == 5 || size <= hdr_size {
+                        self.src.seek(SeekFrom::Start(end))?;
+                        continue;
+                    }
+                    self.src.read_skip(hdr_size - 1)?;
+                    (strmgr.get_stream_by_id(VIDEO_ID), frame_type == 1, hdr_size)
+                }
+                _ => {
+                    self.src.seek(SeekFrom::Start(end))?;
+                    continue;
+                }
+            };
+            if let Some(stream) = stream {
+                let ts = NATimeInfo::new(Some(ts), None, None, 1, 1000);
+                let pkt = self
+                    .src
+                    .read_packet(stream, ts, keyframe, size - hdr_size)?;
+                self.src.read_skip(4)?;
+                return Ok(pkt);
+            }
+            self.src.seek(SeekFrom::Start(end))?;
+        }
+    }
+
+    fn seek(&mut self, time: NATimePoint, seek_index: &SeekIndex) -> DemuxerResult<()> {
+        if let Some(seek_info) = seek_index.find_pos(time) {
+            self.src.seek(SeekFrom::Start(seek_info.pos))?;
+            Ok(())
+        } else if time == NATimePoint::Milliseconds(0) || time == NATimePoint::PTS(0) {
+            self.src.seek(SeekFrom::Start(self.data_start))?;
+            Ok(())
+        } else {
+            Err(DemuxerError::SeekError)
+        }
+    }
+    fn get_duration(&self) -> u64 {
+        self.duration
+    }
+}
+
+impl<'a> NAOptionHandler for FLVDemuxer<'a> {
+    fn get_supported_options(&self) -> &[NAOptionDefinition] {
+        &[]
+    }
+    fn set_options(&mut self, _options: &[NAOption]) {}
+    fn query_option_value(&self, _name: &str) -> Option<NAValue> {
+        None
+    }
+}
+
+pub struct FLVDemuxerCreator {}
+
+impl DemuxerCreator for FLVDemuxerCreator {
+    fn new_demuxer<'a>(&self, br: &'a mut ByteReader<'a>) -> Box<dyn DemuxCore<'a> + 'a> {
+        Box::new(FLVDemuxer::new(br))
+    }
+    fn get_name(&self) -> &'static str {
+        "flv"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nihav_commonfmt::generic_register_all_demuxers;
+    use std::fs::File;
+
+    fn write_tag(bw: &mut ByteWriter, ttype: u8, ts: u32, payload: &[u8]) {
+        bw.write_byte(ttype).unwrap();
+        bw.write_u24be(payload.len() as u32).unwrap();
+        bw.write_u24be(ts & 0xFFFFFF).unwrap();
+        bw.write_byte((ts >> 24) as u8).unwrap();
+        bw.write_u24be(0).unwrap();
+        bw.write_buf(payload).unwrap();
+        bw.write_u32be(payload.len() as u32 + 11).unwrap();
+    }
+    fn write_amf_key(bw: &mut ByteWriter, key: &str) {
+        bw.write_u16be(key.len() as u16).unwrap();
+        bw.write_buf(key.as_bytes()).unwrap();
+    }
+
+    // returns the first frames of an AVI file and FLV video tag size adjustment
+    fn read_avi_frames(name: &str, count: usize) -> (NAVideoInfo, u8, Vec<(bool, Vec<u8>)>) {
+        let mut dmx_reg = RegisteredDemuxers::new();
+        generic_register_all_demuxers(&mut dmx_reg);
+        let mut file = File::open(name).unwrap();
+        let mut fr = FileReader::new_read(&mut file);
+        let mut br = ByteReader::new(&mut fr);
+        let mut dmx = create_demuxer(dmx_reg.find_demuxer("avi").unwrap(), &mut br).unwrap();
+        let vinfo = dmx
+            .get_stream(0)
+            .unwrap()
+            .get_info()
+            .get_properties()
+            .get_video_info()
+            .unwrap();
+        let coded_w = (vinfo.width + 15) & !15;
+        let coded_h = (vinfo.height + 15) & !15;
+        let adj = (((coded_w - vinfo.width) << 4) | (coded_h - vinfo.height)) as u8;
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let pkt = dmx.get_frame().unwrap();
+            frames.push((pkt.keyframe, pkt.get_buffer().to_vec()));
+        }
+        (vinfo, adj, frames)
+    }
+
+    #[test]
+    fn test_flv_bad_metadata() {
+        let (_vinfo, adj, frames) = read_avi_frames("assets/vp6a.avi", 1);
+
+        let mut data = Vec::new();
+        let mut gw = GrowableMemoryWriter::new_write(&mut data);
+        let mut bw = ByteWriter::new(&mut gw);
+        bw.write_buf(b"FLV\x01\x01").unwrap();
+        bw.write_u32be(9).unwrap();
+        bw.write_u32be(0).unwrap();
+        // long string with huge length, unsupported value type and valid metadata
+        for &(vtype, fill) in [(12, 0xFFFF_FFF0u32), (4, 0), (0, 0)].iter() {
+            let mut meta = Vec::new();
+            {
+                let mut gw = GrowableMemoryWriter::new_write(&mut meta);
+                let mut mw = ByteWriter::new(&mut gw);
+                mw.write_byte(2).unwrap();
+                write_amf_key(&mut mw, "onMetaData");
+                mw.write_byte(3).unwrap();
+                write_amf_key(&mut mw, "duration");
+                mw.write_byte(vtype).unwrap();
+                if vtype == 0 {
+                    mw.write_f64be(2.0).unwrap();
+                } else {
+                    mw.write_u32be(fill).unwrap();
+                }
+                mw.write_buf(&[0, 0, 9]).unwrap();
+            }
+            write_tag(&mut bw, FLV_TAG_SCRIPT, 0, &meta);
+        }
+        let mut payload = vec![0x15, adj];
+        payload.extend_from_slice(&frames[0].1);
+        write_tag(&mut bw, FLV_TAG_VIDEO, 0, &payload);
+
+        let mut mr = MemoryReader::new_read(&data);
+        let mut br = ByteReader::new(&mut mr);
+        let mut dmx = create_demuxer(&FLVDemuxerCreator {}, &mut br).unwrap();
+        assert_eq!(dmx.get_num_streams(), 1);
+        assert_eq!(dmx.get_duration(), 2000);
+        let pkt = dmx.get_frame().unwrap();
+        assert_eq!(pkt.get_buffer().as_slice(), frames[0].1.as_slice());
+    }
+    #[test]
+    fn test_flv_demux() {
+        // wrap the first frames of an AVI file into FLV
+        let (vinfo, adj, frames) = read_avi_frames("assets/vp6a.avi", 2);
+        let coded_w = (vinfo.width + 15) & !15;
+        let coded_h = (vinfo.height + 15) & !15;
+
+        let mut data = Vec::new();
+        let mut gw = GrowableMemoryWriter::new_write(&mut data);
+        let mut bw = ByteWriter::new(&mut gw);
+        bw.write_buf(b"FLV\x01\x01").unwrap();
+        bw.write_u32be(9).unwrap();
+        bw.write_u32be(0).unwrap();
+
+        let mut meta = Vec::new();
+        {
+            let mut gw = GrowableMemoryWriter::new_write(&mut meta);
+            let mut mw = ByteWriter::new(&mut gw);
+            mw.write_byte(2).unwrap();
+            write_amf_key(&mut mw, "onMetaData");
+            mw.write_byte(8).unwrap();
+            mw.write_u32be(2).unwrap();
+            write_amf_key(&mut mw, "duration");
+            mw.write_byte(0).unwrap();
+            mw.write_f64be(1.5).unwrap();
+            write_amf_key(&mut mw, "keyframes");
+            mw.write_byte(3).unwrap();
+            write_amf_key(&mut mw, "filepositions");
+            mw.write_byte(10).unwrap();
+            mw.write_u32be(1).unwrap();
+            mw.write_byte(0).unwrap();
+            mw.write_f64be(0.0).unwrap(); // patched below
+            write_amf_key(&mut mw, "times");
+            mw.write_byte(10).unwrap();
+            mw.write_u32be(1).unwrap();
+            mw.write_byte(0).unwrap();
+            mw.write_f64be(0.0).unwrap();
+            mw.write_buf(&[0, 0, 9, 0, 0, 9]).unwrap();
+        }
+        let kf_pos = 13 + 11 + meta.len() as u64 + 4;
+        let fpos_offset = meta.len() - 6 - 8 - 1 - 4 - 1 - 2 - 5 - 8;
+        write_f64be(&mut meta[fpos_offset..], kf_pos as f64).unwrap();
+        write_tag(&mut bw, FLV_TAG_SCRIPT, 0, &meta);
+        for (i, (key, frame)) in frames.iter().enumerate() {
+            let mut payload = vec![if *key { 0x15 } else { 0x25 }, adj];
+            payload.extend_from_slice(frame);
+            write_tag(&mut bw, FLV_TAG_VIDEO, (i as u32) * 40, &payload);
+        }
+
+        let mut mr = MemoryReader::new_read(&data);
+        let mut br = ByteReader::new(&mut mr);
+        let mut dmx = create_demuxer(&FLVDemuxerCreator {}, &mut br).unwrap();
+        assert_eq!(dmx.get_num_streams(), 1);
+        assert_eq!(dmx.get_duration(), 1500);
+        let stream = dmx.get_stream(0).unwrap();
+        let info = stream.get_info();
+        assert_eq!(info.get_name(), "vp6a");
+        assert_eq!(info.get_extradata().unwrap().as_slice(), &[adj]);
+        let fvinfo = info.get_properties().get_video_info().unwrap();
+        assert_eq!((fvinfo.width, fvinfo.height), (coded_w, coded_h));
+        let seek_info = dmx
+            .get_seek_index()
+            .find_pos(NATimePoint::Milliseconds(100))
+            .unwrap();
+        assert_eq!(seek_info.pos, kf_pos);
+
+        for (i, (key, frame)) in frames.iter().enumerate() {
+            let pkt = dmx.get_frame().unwrap();
+            assert_eq!(pkt.keyframe, *key);
+            assert_eq!(pkt.get_pts(), Some((i as u64) * 40));
+            assert_eq!(pkt.get_buffer().as_slice(), frame.as_slice());
+        }
+        assert_eq!(dmx.get_frame().err(), Some(DemuxerError::EOF));
+
+        dmx.seek(NATimePoint::Milliseconds(100)).unwrap();
+        let pkt = dmx.get_frame().unwrap();
+        assert!(pkt.keyframe);
+        assert_eq!(pkt.get_pts(), Some(0));
+    }
+}
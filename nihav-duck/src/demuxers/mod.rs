@@ -10,10 +10,14 @@ macro_rules! validate {
     };
 }
 
+#[cfg(feature = "demuxer_flv")]
+mod flv;
 #[cfg(feature = "demuxer_ivf")]
 mod ivf;
 
 const DEMUXERS: &[&dyn DemuxerCreator] = &[
+    #[cfg(feature = "demuxer_flv")]
+    &flv::FLVDemuxerCreator {},
     #[cfg(feature = "demuxer_ivf")]
     &ivf::IVFDemuxerCreator {},
 ];
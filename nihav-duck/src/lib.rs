@@ -11,10 +11,23 @@ extern crate nihav_core;
 mod codecs;
 
 mod demuxers;
+mod muxers;
 
 pub use crate::codecs::duck_register_all_decoders;
 pub use crate::codecs::duck_register_all_encoders;
+pub use crate::codecs::duck_register_all_packetisers;
+#[cfg(feature = "decoder_vp6")]
+pub use crate::codecs::{MBParams, VP56FrameInfo, VPMBType, VP_REF_GOLDEN, VP_REF_INTER};
+
+/// VP6 bitstream utilities.
+#[cfg(feature = "decoder_vp6")]
+pub mod vp6 {
+    pub use crate::codecs::{
+        parse_frame_header, VP6FrameHeader, VP6HeaderParser, VP6KeyframeParams, VP6StreamHeader,
+    };
+}
 pub use crate::demuxers::duck_register_all_demuxers;
+pub use crate::muxers::duck_register_all_muxers;
 
 #[cfg(test)]
 extern crate nihav_commonfmt;
@@ -0,0 +1,485 @@
+use nihav_core::muxers::*;
+
+const FLV_TAG_AUDIO: u8 = 8;
+const FLV_TAG_VIDEO: u8 = 9;
+const FLV_TAG_SCRIPT: u8 = 18;
+
+// maximum number of entries in the keyframe index
+const KF_INDEX_SLOTS: usize = 256;
+const KF_POS_NAME: &str = "filepositions";
+const KF_TIME_NAME: &str = "times";
+const KF_PAD_NAME: &str = "padding";
+
+fn write_amf_string(bw: &mut ByteWriter, name: &str) -> MuxerResult<()> {
+    bw.write_u16be(name.len() as u16)?;
+    bw.write_buf(name.as_bytes())?;
+    Ok(())
+}
+
+// writes named number entry and returns the position of the value for patching
+fn write_amf_number(bw: &mut ByteWriter, name: &str, val: f64) -> MuxerResult<u64> {
+    write_amf_string(bw, name)?;
+    bw.write_byte(0)?;
+    let pos = bw.tell();
+    bw.write_f64be(val)?;
+    Ok(pos)
+}
+
+fn write_amf_bool(bw: &mut ByteWriter, name: &str, val: bool) -> MuxerResult<()> {
+    write_amf_string(bw, name)?;
+    bw.write_byte(1)?;
+    bw.write_byte(val as u8)?;
+    Ok(())
+}
+
+fn write_amf_array(bw: &mut ByteWriter, name: &str, vals: &[f64]) -> MuxerResult<()> {
+    write_amf_string(bw, name)?;
+    bw.write_byte(10)?;
+    bw.write_u32be(vals.len() as u32)?;
+    for &val in vals.iter() {
+        bw.write_byte(0)?;
+        bw.write_f64be(val)?;
+    }
+    Ok(())
+}
+
+fn get_kf_index_size(nentries: usize) -> usize {
+    (2 + KF_POS_NAME.len() + 5) + (2 + KF_TIME_NAME.len() + 5) + nentries * 2 * 9
+}
+
+// writes keyframe index padded to the size reserved for the maximum number of entries
+fn write_kf_index(bw: &mut ByteWriter, keyframes: &[KeyframeEntry]) -> MuxerResult<()> {
+    let positions: Vec<f64> = keyframes.iter().map(|kf| kf.pos as f64).collect();
+    let times: Vec<f64> = keyframes
+        .iter()
+        .map(|kf| (kf.time as f64) / 1000.0)
+        .collect();
+    write_amf_array(bw, KF_POS_NAME, &positions)?;
+    write_amf_array(bw, KF_TIME_NAME, &times)?;
+    let pad_size = get_kf_index_size(KF_INDEX_SLOTS) - get_kf_index_size(keyframes.len());
+    if pad_size > 0 {
+        // unused space is taken by a string entry
+        let str_len = pad_size - (2 + KF_PAD_NAME.len() + 3);
+        write_amf_string(bw, KF_PAD_NAME)?;
+        bw.write_byte(2)?;
+        bw.write_u16be(str_len as u16)?;
+        for _ in 0..str_len {
+            bw.write_byte(0x20)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_amf_end(bw: &mut ByteWriter) -> MuxerResult<()> {
+    bw.write_u16be(0)?;
+    bw.write_byte(9)?;
+    Ok(())
+}
+
+fn patch_f64(bw: &mut ByteWriter, pos: u64, val: f64) -> MuxerResult<()> {
+    bw.seek(SeekFrom::Start(pos))?;
+    bw.write_f64be(val)?;
+    Ok(())
+}
+
+fn get_video_codec_id(name: &str) -> Option<u8> {
+    match name {
+        "flv263" => Some(2),
+        "flashsv" => Some(3),
+        "vp6f" => Some(4),
+        "vp6a" => Some(5),
+        "flashsv2" => Some(6),
+        _ => None,
+    }
+}
+
+fn get_audio_flags(name: &str, ainfo: &NAAudioInfo) -> Option<u8> {
+    let rate_id = match ainfo.sample_rate {
+        5512 => 0,
+        11025 => 1,
+        22050 => 2,
+        44100 => 3,
+        _ => return None,
+    };
+    let stereo = match ainfo.channels {
+        1 => 0,
+        2 => 1,
+        _ => return None,
+    };
+    let (fmt, size) = match name {
+        "pcm" => {
+            let fmt = ainfo.format;
+            match (fmt.bits, fmt.signed) {
+                (8, false) => (3, 0),
+                (16, true) if !fmt.be && !fmt.planar && !fmt.float => (3, 1),
+                _ => return None,
+            }
+        }
+        "flv-adpcm" => (1, 1),
+        "mp3" => (2, 1),
+        _ => return None,
+    };
+    Some((fmt << 4) | (rate_id << 2) | (size << 1) | stereo)
+}
+
+#[derive(Clone, Copy)]
+struct KeyframeEntry {
+    pos: u64,
+    time: u64,
+}
+
+struct FLVMuxer<'a> {
+    bw: &'a mut ByteWriter<'a>,
+    vid_id: Option<u32>,
+    aud_id: Option<u32>,
+    vflags: u8,
+    vp6_adj: u8,
+    aflags: u8,
+    duration: u64,
+    duration_pos: u64,
+    filesize_pos: u64,
+    kf_index_pos: u64,
+    keyframes: Vec<KeyframeEntry>,
+}
+
+impl<'a> FLVMuxer<'a> {
+    fn new(bw: &'a mut ByteWriter<'a>) -> Self {
+        Self {
+            bw,
+            vid_id: None,
+            aud_id: None,
+            vflags: 0,
+            vp6_adj: 0,
+            aflags: 0,
+            duration: 0,
+            duration_pos: 0,
+            filesize_pos: 0,
+            kf_index_pos: 0,
+            keyframes: Vec::new(),
+        }
+    }
+    fn write_tag_header(&mut self, ttype: u8, size: usize, ts: u64) -> MuxerResult<()> {
+        if size >= (1 << 24) {
+            return Err(MuxerError::InvalidData);
+        }
+        self.bw.write_byte(ttype)?;
+        self.bw.write_u24be(size as u32)?;
+        self.bw.write_u24be((ts & 0xFFFFFF) as u32)?;
+        self.bw.write_byte((ts >> 24) as u8)?;
+        self.bw.write_u24be(0)?;
+        Ok(())
+    }
+    fn write_metadata(&mut self, strmgr: &StreamManager) -> MuxerResult<()> {
+        let tag_start = self.bw.tell();
+        self.write_tag_header(FLV_TAG_SCRIPT, 0, 0)?;
+        let data_start = self.bw.tell();
+
+        self.bw.write_byte(2)?;
+        write_amf_string(self.bw, "onMetaData")?;
+        self.bw.write_byte(8)?;
+        let mut nentries = 2;
+        if self.vid_id.is_some() {
+            nentries += 5;
+        }
+        if self.aud_id.is_some() {
+            nentries += 4;
+        }
+        self.bw.write_u32be(nentries)?;
+        self.duration_pos = write_amf_number(self.bw, "duration", 0.0)?;
+        self.filesize_pos = write_amf_number(self.bw, "filesize", 0.0)?;
+        if let Some(id) = self.vid_id {
+            let stream = strmgr.get_stream_by_id(id).unwrap();
+            let vinfo = stream.get_info().get_properties().get_video_info().unwrap();
+            let width = vinfo.width - usize::from(self.vp6_adj >> 4);
+            let height = vinfo.height - usize::from(self.vp6_adj & 0xF);
+            let (tb_num, tb_den) = stream.get_timebase();
+            let framerate = if tb_num > 0 {
+                f64::from(tb_den) / f64::from(tb_num)
+            } else {
+                0.0
+            };
+            write_amf_number(self.bw, "width", width as f64)?;
+            write_amf_number(self.bw, "height", height as f64)?;
+            write_amf_number(self.bw, "framerate", framerate)?;
+            write_amf_number(self.bw, "videocodecid", f64::from(self.vflags))?;
+
+            write_amf_string(self.bw, "keyframes")?;
+            self.bw.write_byte(3)?;
+            self.kf_index_pos = self.bw.tell();
+            write_kf_index(self.bw, &[])?;
+            write_amf_end(self.bw)?;
+        }
+        if let Some(id) = self.aud_id {
+            let stream = strmgr.get_stream_by_id(id).unwrap();
+            let ainfo = stream.get_info().get_properties().get_audio_info().unwrap();
+            write_amf_number(self.bw, "audiocodecid", f64::from(self.aflags >> 4))?;
+            write_amf_number(self.bw, "audiosamplerate", f64::from(ainfo.sample_rate))?;
+            write_amf_number(
+                self.bw,
+                "audiosamplesize",
+                if (self.aflags & 2) != 0 { 16.0 } else { 8.0 },
+            )?;
+            write_amf_bool(self.bw, "stereo", (self.aflags & 1) != 0)?;
+        }
+        write_amf_end(self.bw)?;
+
+        let size = self.bw.tell() - data_start;
+        self.bw.seek(SeekFrom::Start(tag_start + 1))?;
+        self.bw.write_u24be(size as u32)?;
+        self.bw.seek(SeekFrom::End(0))?;
+        self.bw.write_u32be((size + 11) as u32)?;
+        Ok(())
+    }
+    fn patch_keyframes(&mut self) -> MuxerResult<()> {
+        let nkf = self.keyframes.len();
+        // decimate index if it does not fit
+        let keyframes: Vec<KeyframeEntry> = if nkf > KF_INDEX_SLOTS {
+            (0..KF_INDEX_SLOTS)
+                .map(|slot| self.keyframes[slot * nkf / KF_INDEX_SLOTS])
+                .collect()
+        } else {
+            self.keyframes.clone()
+        };
+        self.bw.seek(SeekFrom::Start(self.kf_index_pos))?;
+        write_kf_index(self.bw, &keyframes)
+    }
+}
+
+impl<'a> MuxCore<'a> for FLVMuxer<'a> {
+    fn create(&mut self, strmgr: &StreamManager) -> MuxerResult<()> {
+        if strmgr.get_num_streams() == 0 || strmgr.get_num_streams() > 2 {
+            return Err(MuxerError::InvalidArgument);
+        }
+        for stream in strmgr.iter() {
+            let info = stream.get_info();
+            match stream.get_media_type() {
+                StreamType::Video => {
+                    if self.vid_id.is_some() {
+                        return Err(MuxerError::UnsupportedFormat);
+                    }
+                    let vinfo = info.get_properties().get_video_info().unwrap();
+                    if vinfo.width >= (1 << 12) || vinfo.height >= (1 << 12) {
+                        return Err(MuxerError::UnsupportedFormat);
+                    }
+                    self.vflags =
+                        get_video_codec_id(info.get_name()).ok_or(MuxerError::UnsupportedFormat)?;
+                    if self.vflags == 4 || self.vflags == 5 {
+                        let coded_w = (vinfo.width + 15) & !15;
+                        let coded_h = (vinfo.height + 15) & !15;
+                        self.vp6_adj = match info.get_extradata() {
+                            Some(ref edata)
+                                if !edata.is_empty()
+                                    && coded_w == vinfo.width
+                                    && coded_h == vinfo.height =>
+                            {
+                                edata[0]
+                            }
+                            _ => (((coded_w - vinfo.width) << 4) | (coded_h - vinfo.height)) as u8,
+                        };
+                    }
+                    self.vid_id = Some(stream.id);
+                }
+                StreamType::Audio => {
+                    if self.aud_id.is_some() {
+                        return Err(MuxerError::UnsupportedFormat);
+                    }
+                    let ainfo = info.get_properties().get_audio_info().unwrap();
+                    self.aflags = get_audio_flags(info.get_name(), &ainfo)
+                        .ok_or(MuxerError::UnsupportedFormat)?;
+                    self.aud_id = Some(stream.id);
+                }
+                _ => return Err(MuxerError::UnsupportedFormat),
+            };
+        }
+
+        self.bw.write_buf(b"FLV\x01")?;
+        let flags =
+            if self.vid_id.is_some() { 1 } else { 0 } | if self.aud_id.is_some() { 4 } else { 0 };
+        self.bw.write_byte(flags)?;
+        self.bw.write_u32be(9)?;
+        self.bw.write_u32be(0)?;
+
+        self.write_metadata(strmgr)?;
+        Ok(())
+    }
+    fn mux_frame(&mut self, _strmgr: &StreamManager, pkt: NAPacket) -> MuxerResult<()> {
+        if self.duration_pos == 0 {
+            return Err(MuxerError::NotCreated);
+        }
+        let stream = pkt.get_stream();
+        let (tb_num, tb_den) = stream.get_timebase();
+        let pts = pkt
+            .get_pts()
+            .or_else(|| pkt.get_dts())
+            .ok_or(MuxerError::InvalidData)?;
+        let ms = NATimeInfo::ts_to_time(pts, 1000, tb_num, tb_den);
+        let end_ms = if let Some(dur) = pkt.get_duration() {
+            NATimeInfo::ts_to_time(pts + dur, 1000, tb_num, tb_den)
+        } else if stream.get_media_type() == StreamType::Video {
+            NATimeInfo::ts_to_time(pts + 1, 1000, tb_num, tb_den)
+        } else {
+            ms
+        };
+        self.duration = self.duration.max(end_ms);
+
+        let src = pkt.get_buffer();
+        let tag_pos = self.bw.tell();
+        if Some(stream.id) == self.vid_id {
+            let is_vp6 = self.vflags == 4 || self.vflags == 5;
+            let hdr_size = if is_vp6 { 2 } else { 1 };
+            self.write_tag_header(FLV_TAG_VIDEO, src.len() + hdr_size, ms)?;
+            let frame_type = if pkt.keyframe { 0x10 } else { 0x20 };
+            self.bw.write_byte(frame_type | self.vflags)?;
+            if is_vp6 {
+                self.bw.write_byte(self.vp6_adj)?;
+            }
+            self.bw.write_buf(&src)?;
+            self.bw.write_u32be((src.len() + hdr_size + 11) as u32)?;
+            if pkt.keyframe {
+                self.keyframes.push(KeyframeEntry {
+                    pos: tag_pos,
+                    time: ms,
+                });
+            }
+        } else if Some(stream.id) == self.aud_id {
+            self.write_tag_header(FLV_TAG_AUDIO, src.len() + 1, ms)?;
+            self.bw.write_byte(self.aflags)?;
+            self.bw.write_buf(&src)?;
+            self.bw.write_u32be((src.len() + 1 + 11) as u32)?;
+        } else {
+            return Err(MuxerError::InvalidData);
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> MuxerResult<()> {
+        Ok(())
+    }
+    fn end(&mut self) -> MuxerResult<()> {
+        let filesize = self.bw.tell();
+        patch_f64(self.bw, self.duration_pos, (self.duration as f64) / 1000.0)?;
+        patch_f64(self.bw, self.filesize_pos, filesize as f64)?;
+        if self.vid_id.is_some() {
+            self.patch_keyframes()?;
+        }
+        self.bw.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl<'a> NAOptionHandler for FLVMuxer<'a> {
+    fn get_supported_options(&self) -> &[NAOptionDefinition] {
+        &[]
+    }
+    fn set_options(&mut self, _options: &[NAOption]) {}
+    fn query_option_value(&self, _name: &str) -> Option<NAValue> {
+        None
+    }
+}
+
+pub struct FLVMuxerCreator {}
+
+impl MuxerCreator for FLVMuxerCreator {
+    fn new_muxer<'a>(&self, bw: &'a mut ByteWriter<'a>) -> Box<dyn MuxCore<'a> + 'a> {
+        Box::new(FLVMuxer::new(bw))
+    }
+    fn get_name(&self) -> &'static str {
+        "flv"
+    }
+    fn get_capabilities(&self) -> MuxerCapabilities {
+        MuxerCapabilities::SingleVideoAndAudio("any", "any")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use nihav_codec_support::test::enc_video::*;
+    use nihav_commonfmt::*;
+    use nihav_core::codecs::*;
+    use nihav_core::demuxers::*;
+    use nihav_core::muxers::*;
+
+    #[test]
+    fn test_flv_muxer() {
+        let mut dmx_reg = RegisteredDemuxers::new();
+        generic_register_all_demuxers(&mut dmx_reg);
+        let dec_config = DecoderTestParams {
+            demuxer: "avi",
+            in_name: "assets/vp6a.avi",
+            limit: None,
+            stream_type: StreamType::None,
+            dmx_reg,
+            dec_reg: RegisteredDecoders::new(),
+        };
+        let mut mux_reg = RegisteredMuxers::new();
+        duck_register_all_muxers(&mut mux_reg);
+        test_remuxing_md5(
+            &dec_config,
+            "flv",
+            &mux_reg,
+            [0x6459e50c, 0x6101698b, 0xec050450, 0xfd9905a8],
+        );
+    }
+
+    #[test]
+    fn test_flv_roundtrip() {
+        let mut dmx_reg = RegisteredDemuxers::new();
+        generic_register_all_demuxers(&mut dmx_reg);
+        duck_register_all_demuxers(&mut dmx_reg);
+        let mut mux_reg = RegisteredMuxers::new();
+        duck_register_all_muxers(&mut mux_reg);
+
+        let mut file = std::fs::File::open("assets/vp6a.avi").unwrap();
+        let mut fr = FileReader::new_read(&mut file);
+        let mut br = ByteReader::new(&mut fr);
+        let mut dmx = create_demuxer(dmx_reg.find_demuxer("avi").unwrap(), &mut br).unwrap();
+        let in_stream = dmx.get_stream(0).unwrap();
+        let mut pkts = Vec::new();
+        for _ in 0..10 {
+            pkts.push(dmx.get_frame().unwrap());
+        }
+
+        let mut dst = Vec::new();
+        let mut gw = GrowableMemoryWriter::new_write(&mut dst);
+        let mut bw = ByteWriter::new(&mut gw);
+        let mut out_sm = StreamManager::new();
+        out_sm.add_stream(NAStream::clone(&in_stream)).unwrap();
+        let mut mux = create_muxer(mux_reg.find_muxer("flv").unwrap(), out_sm, &mut bw).unwrap();
+        for pkt in pkts.iter() {
+            let data = pkt.get_buffer().to_vec();
+            let opkt = NAPacket::new(
+                pkt.get_stream(),
+                pkt.get_time_information(),
+                pkt.keyframe,
+                data,
+            );
+            mux.mux_frame(opkt).unwrap();
+        }
+        mux.end().unwrap();
+
+        let mut mr = MemoryReader::new_read(&dst);
+        let mut br = ByteReader::new(&mut mr);
+        let mut dmx = create_demuxer(dmx_reg.find_demuxer("flv").unwrap(), &mut br).unwrap();
+        let stream = dmx.get_stream(0).unwrap();
+        let info = stream.get_info();
+        assert_eq!(info.get_name(), "vp6a");
+        let vinfo = info.get_properties().get_video_info().unwrap();
+        assert_eq!((vinfo.width, vinfo.height), (768, 208));
+        assert_eq!(info.get_extradata().unwrap().as_slice(), &[0xC5]);
+        let last_pts = pkts.last().unwrap().get_pts().unwrap();
+        assert_eq!(dmx.get_duration(), last_pts + 1);
+        let seek_index = dmx.get_seek_index();
+        assert!(seek_index
+            .find_pos(NATimePoint::Milliseconds(last_pts))
+            .is_some());
+        let num_kf = pkts.iter().filter(|pkt| pkt.keyframe).count();
+        assert_eq!(seek_index.seek_info[0].entries.len(), num_kf);
+        for pkt in pkts.iter() {
+            let fpkt = dmx.get_frame().unwrap();
+            assert_eq!(fpkt.get_pts(), pkt.get_pts());
+            assert_eq!(fpkt.keyframe, pkt.keyframe);
+            assert_eq!(fpkt.get_buffer().as_slice(), pkt.get_buffer().as_slice());
+        }
+        assert_eq!(dmx.get_frame().err(), Some(DemuxerError::EOF));
+    }
+}
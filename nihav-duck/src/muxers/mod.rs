@@ -0,0 +1,16 @@
+use nihav_core::muxers::*;
+
+#[cfg(feature = "muxer_flv")]
+mod flv;
+
+const MUXERS: &[&dyn MuxerCreator] = &[
+    #[cfg(feature = "muxer_flv")]
+    &flv::FLVMuxerCreator {},
+];
+
+/// Registers all available muxers provided by this crate.
+pub fn duck_register_all_muxers(rm: &mut RegisteredMuxers) {
+    for muxer in MUXERS.iter() {
+        rm.add_muxer(*muxer);
+    }
+}